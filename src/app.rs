@@ -1,27 +1,56 @@
+pub mod cli;
 mod commands;
 mod event_handler;
+mod logging;
 mod models;
 mod services;
 
-use std::{fmt, fs, io, path::Path, sync::Arc};
+use std::{
+    collections::HashSet,
+    fmt,
+    fs::{self, File},
+    io,
+    path::Path,
+    sync::Arc,
+};
 
-use crate::AppConfig;
+use crate::{config::StorageBackend, AppConfig};
 
 use poise::FrameworkError;
-use serenity::prelude::GatewayIntents;
-use services::{CkeyService, ConfigService, VerificationService, WhitelistService};
+use serenity::{model::prelude::GuildId, prelude::GatewayIntents};
+use services::{Actor, AuditService, ConfigService, Guild, GuildService};
+
+/// Held in the data directory for as long as its data is in use, so the bot
+/// and command line don't overwrite each other's changes
+const LOCK_FILE: &str = "lock";
 
 pub struct Data {
-    ckey: Arc<CkeyService>,
+    audit: Arc<AuditService>,
     config: Arc<ConfigService>,
-    verification: Arc<VerificationService>,
-    whitelist: Arc<WhitelistService>,
+    guilds: Arc<GuildService>,
+    /// Released when the services are dropped
+    _lock: File,
+}
+
+impl Data {
+    async fn guild(&self, id: Option<GuildId>) -> Result<Arc<Guild>, Error> {
+        let id = id.ok_or(Error::GuildNotConfigured(None))?;
+
+        self.guilds
+            .get(id)
+            .await
+            .ok_or(Error::GuildNotConfigured(Some(id)))
+    }
 }
 
 #[derive(Debug)]
 pub enum Error {
     DataDir(io::Error),
+    DataDirLocked,
     Whietlist(io::Error),
+    GuildNotConfigured(Option<GuildId>),
+    GuildRequired,
+    File(io::Error),
     Discord(serenity::Error),
     Service(services::Error),
     JoinError(tokio::task::JoinError),
@@ -49,7 +78,11 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match *self {
             Error::DataDir(ref e) => fmt::Display::fmt(e, f),
+            Error::DataDirLocked => write!(f, "the data directory is in use"),
             Error::Whietlist(ref e) => fmt::Display::fmt(e, f),
+            Error::GuildNotConfigured(_) => write!(f, "this server is not configured"),
+            Error::GuildRequired => write!(f, "several servers are configured, pick one"),
+            Error::File(ref e) => fmt::Display::fmt(e, f),
             Error::Discord(ref e) => fmt::Display::fmt(e, f),
             Error::Service(ref e) => fmt::Display::fmt(e, f),
             Error::JoinError(ref e) => fmt::Display::fmt(e, f),
@@ -57,7 +90,36 @@ impl fmt::Display for Error {
     }
 }
 
+/// Takes the lock on the data directory, `None` if another instance holds it
+fn lock(data_path: &Path) -> io::Result<Option<File>> {
+    let file = File::create(data_path.join(LOCK_FILE))?;
+
+    match file.try_lock() {
+        Ok(_) => Ok(Some(file)),
+        Err(fs::TryLockError::WouldBlock) => Ok(None),
+        Err(fs::TryLockError::Error(e)) => Err(e),
+    }
+}
+
+/// Waits for Ctrl+C, or for SIGTERM as sent by service managers and `kill`
+async fn shutdown_signal() -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
 type Context<'a> = poise::Context<'a, Data, Error>;
+type ApplicationContext<'a> = poise::ApplicationContext<'a, Data, Error>;
 
 pub struct AppPaths<'a> {
     pub config: &'a Path,
@@ -97,37 +159,105 @@ impl<'a> App<'a> {
             Err(e) => return Err(Error::DataDir(e)),
         }
 
-        let config = Arc::new(ConfigService::new(self.config.clone(), self.paths.config));
+        let lock = lock(self.paths.data)
+            .map_err(Error::DataDir)?
+            .ok_or(Error::DataDirLocked)?;
 
-        let whitelist = Arc::new(WhitelistService::new(&self.config.whitelist_path));
-        whitelist.load().await?;
-        let whitelist_path =
-            fs::canonicalize(&self.config.whitelist_path).map_err(Error::DataDir)?;
-        log::info!("Whitelist loaded from {}", whitelist_path.display());
+        let audit = Arc::new(AuditService::new(self.paths.data));
 
-        let ckey = Arc::new(CkeyService::new(self.paths.data));
-        ckey.load().await?;
-        log::info!("Ckey mapping loaded");
+        let config = Arc::new(ConfigService::new(
+            self.config.clone(),
+            self.paths.config,
+            audit.clone(),
+        ));
 
-        let verification = Arc::new(VerificationService::new(
-            Arc::downgrade(&ckey),
+        let guilds = Arc::new(GuildService::new(
+            audit.clone(),
             Arc::downgrade(&config),
-            Arc::downgrade(&whitelist),
             self.paths.data,
         ));
-        verification.load().await?;
-        log::info!("Verifier loaded");
+        guilds.load().await?;
+        log::info!("Guild services loaded");
 
         Ok(Data {
-            ckey,
+            audit,
             config,
-            verification,
-            whitelist,
+            guilds,
+            _lock: lock,
         })
     }
 
+    /// Runs an administration command against the data directory without connecting to Discord
+    pub async fn run_command(
+        self,
+        guild_id: Option<u64>,
+        command: cli::Command,
+    ) -> Result<(), Error> {
+        let services = self.init_services().await?;
+
+        if let cli::Command::Migrate = command {
+            return self.migrate(&services).await;
+        }
+
+        let guild_id = match guild_id {
+            Some(id) => GuildId(id),
+            None => {
+                let ids: Vec<_> = self.config.guilds.keys().copied().collect();
+                match ids[..] {
+                    [id] => id,
+                    [] => return Err(Error::GuildNotConfigured(None)),
+                    _ => return Err(Error::GuildRequired),
+                }
+            }
+        };
+
+        let guild = services.guild(Some(guild_id)).await?;
+
+        cli::run(&guild, command).await?;
+        services.guilds.flush().await?;
+
+        Ok(())
+    }
+
+    /// Moves every guild's data into SQLite, switching the config over once
+    /// all of it is copied so a failure leaves the RON files in use
+    async fn migrate(&self, services: &Data) -> Result<(), Error> {
+        if self.config.storage == StorageBackend::Sqlite {
+            println!("Already using SQLite");
+            return Ok(());
+        }
+
+        let data_paths: Vec<_> = self
+            .config
+            .guilds
+            .keys()
+            .map(|id| (*id, self.paths.data.join(id.to_string())))
+            .collect();
+
+        for (id, data_path) in data_paths.clone() {
+            let members =
+                tokio::task::spawn_blocking(move || services::migrate_to_sqlite(&data_path))
+                    .await??;
+            println!("{id}: copied {members} members");
+        }
+
+        services
+            .config
+            .set_storage(StorageBackend::Sqlite, Actor::Console)
+            .await?;
+
+        for (_, data_path) in data_paths {
+            tokio::task::spawn_blocking(move || services::archive_ron_files(&data_path)).await??;
+        }
+
+        println!("Now using SQLite, the old files were renamed to *.ron.migrated");
+
+        Ok(())
+    }
+
     pub async fn run(self) -> Result<(), Error> {
         let services = self.init_services().await?;
+        let guilds = services.guilds.clone();
 
         log::info!("Initializing Discord client");
 
@@ -154,8 +284,27 @@ impl<'a> App<'a> {
                                 event: _,
                                 framework: _,
                             } => log::error!("command: {error}"),
-                            FrameworkError::Command { error, ctx: _ } => {
-                                log::error!("command: {error}")
+                            FrameworkError::Command { error, ctx } => {
+                                log::error!("command: {error}");
+
+                                if let Err(e) = ctx
+                                    .send(|b| b.ephemeral(true).content(format!("Error: {error}")))
+                                    .await
+                                {
+                                    log::error!("command: {e}")
+                                }
+                            }
+                            FrameworkError::ArgumentParse { error, input, ctx } => {
+                                let response = match input {
+                                    Some(input) => format!("Invalid input `{input}`: {error}"),
+                                    None => format!("Invalid input: {error}"),
+                                };
+
+                                if let Err(e) =
+                                    ctx.send(|b| b.ephemeral(true).content(response)).await
+                                {
+                                    log::error!("command: {e}")
+                                }
                             }
                             FrameworkError::CommandCheckFailed {
                                 error: Some(error),
@@ -173,6 +322,7 @@ impl<'a> App<'a> {
                     })
                 },
                 event_handler: event_handler::event_handler,
+                owners: HashSet::from([self.config.owner_id]),
                 ..Default::default()
             })
             .token(&self.config.token)
@@ -191,10 +341,23 @@ impl<'a> App<'a> {
             .build()
             .await?;
 
+        let shard_manager = framework.shard_manager().clone();
+        tokio::spawn(async move {
+            if shutdown_signal().await.is_ok() {
+                log::info!("Shutting down");
+                shard_manager.lock().await.shutdown_all().await;
+            }
+        });
+
         log::info!("Starting bot");
 
-        framework.start().await?;
+        let result = framework.start().await;
 
-        Ok(())
+        // Changes are written a moment after they happen, anything still
+        // pending has to make it to disk before exiting
+        guilds.flush().await?;
+        log::info!("Data written");
+
+        Ok(result?)
     }
 }
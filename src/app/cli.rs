@@ -0,0 +1,343 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use clap::Subcommand;
+use poise::serenity_prelude as serenity;
+
+use super::{
+    models::{Age, Ckey},
+    services::{
+        Actor, EntrySource, Format, Guild, ImportMode, ImportPlan, MemberRecord, WhitelistEntry,
+    },
+    Error,
+};
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Modify and inspect ckey mappings
+    #[command(subcommand)]
+    Ckey(CkeyCommand),
+    /// Modify and inspect whitelist
+    #[command(subcommand)]
+    Whitelist(WhitelistCommand),
+    /// Modify and inspect verification data
+    #[command(subcommand)]
+    Verification(VerificationCommand),
+    /// Move ckeys and verification statuses of every server from RON files to SQLite
+    Migrate,
+    /// Export ckeys and verification statuses of every member
+    Export {
+        /// Output file, standard output if not given
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// `json` or `csv`, guessed from the output file name if not given
+        #[arg(short, long)]
+        format: Option<Format>,
+    },
+    /// Import ckeys and verification statuses of members
+    Import {
+        /// File made by export or another tool in the same format
+        input: PathBuf,
+        /// `json` or `csv`, guessed from the input file name if not given
+        #[arg(short, long)]
+        format: Option<Format>,
+        /// `merge` keeps current data on conflicts, `replace` makes data match the file
+        #[arg(short, long, default_value = "merge")]
+        mode: ImportMode,
+        /// Only report what would change
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CkeyCommand {
+    /// List user's mapped ckeys
+    Get { user_id: u64 },
+    /// Find Discord user for ckey
+    Find { ckey: String },
+    /// Set user's ckey, replacing any others they have
+    Set { user_id: u64, ckey: String },
+    /// Add another ckey to user's mapping
+    Add {
+        user_id: u64,
+        ckey: String,
+        /// Make it the user's primary ckey
+        #[arg(short, long)]
+        primary: bool,
+    },
+    /// Remove a single ckey from user's mapping
+    Remove { user_id: u64, ckey: String },
+    /// Unset all of user's ckeys
+    Unset { user_id: u64 },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum WhitelistCommand {
+    /// Show the entire whitelist
+    List,
+    /// Add ckey to the whitelist
+    Add {
+        ckey: String,
+        /// Why the ckey is whitelisted
+        #[arg(short, long)]
+        reason: Option<String>,
+        /// Remove it again after this long, like 7d, never if not given
+        #[arg(short, long)]
+        duration: Option<Age>,
+    },
+    /// Remove ckey from the whitelist
+    Remove { ckey: String },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum VerificationCommand {
+    /// Get user verification status and history
+    Status { user_id: u64 },
+    /// Clear user verification
+    Clear { user_id: u64 },
+}
+
+/// Runs a command against a guild's data, printing the result
+pub async fn run(guild: &Guild, command: Command) -> Result<(), Error> {
+    match command {
+        Command::Migrate => unreachable!("Migration isn't bound to a guild"),
+        Command::Ckey(command) => ckey(guild, command).await,
+        Command::Whitelist(command) => whitelist(guild, command).await,
+        Command::Verification(command) => verification(guild, command).await,
+        Command::Export { output, format } => export(guild, output, format).await,
+        Command::Import {
+            input,
+            format,
+            mode,
+            dry_run,
+        } => import(guild, input, format, mode, dry_run).await,
+    }
+}
+
+fn guess_format(format: Option<Format>, path: Option<&PathBuf>) -> Format {
+    format
+        .or_else(|| {
+            path.and_then(|path| path.to_str())
+                .and_then(Format::from_file_name)
+        })
+        .unwrap_or(Format::Json)
+}
+
+async fn export(
+    guild: &Guild,
+    output: Option<PathBuf>,
+    format: Option<Format>,
+) -> Result<(), Error> {
+    let format = guess_format(format, output.as_ref());
+    let data = format.serialize(&MemberRecord::export(guild).await)?;
+
+    match output {
+        Some(path) => fs::write(path, data).map_err(Error::File)?,
+        None => io::stdout().write_all(&data).map_err(Error::File)?,
+    }
+
+    Ok(())
+}
+
+async fn import(
+    guild: &Guild,
+    input: PathBuf,
+    format: Option<Format>,
+    mode: ImportMode,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let format = guess_format(format, Some(&input));
+    let data = fs::read(input).map_err(Error::File)?;
+    let records = format.deserialize(&data)?;
+
+    let plan = ImportPlan::new(guild, records, mode).await;
+    println!("{}", plan.report());
+
+    if dry_run {
+        println!("Dry run, nothing was changed");
+    } else {
+        plan.apply(guild, Actor::Console).await?;
+        println!("Import finished");
+    }
+
+    Ok(())
+}
+
+async fn ckey(guild: &Guild, command: CkeyCommand) -> Result<(), Error> {
+    match command {
+        CkeyCommand::Get { user_id } => {
+            let ckeys = guild.members.get_ckeys(&serenity::UserId(user_id)).await;
+            if ckeys.is_empty() {
+                println!("{user_id} has no ckey mapping");
+            }
+            for mapped in ckeys {
+                if mapped.primary {
+                    println!("{} (primary)", mapped.ckey);
+                } else {
+                    println!("{}", mapped.ckey);
+                }
+            }
+        }
+        CkeyCommand::Find { ckey } => {
+            let ckey = Ckey::from(&ckey);
+            match guild.members.get_user(&ckey).await {
+                Some(user_id) => println!("{ckey} belongs to {user_id}"),
+                None => println!("{ckey} is not mapped to any user"),
+            }
+        }
+        CkeyCommand::Set { user_id, ckey } => {
+            let ckey = Ckey::from(&ckey);
+            if guild
+                .verification
+                .set_ckey(serenity::UserId(user_id), &ckey, Actor::Console)
+                .await?
+            {
+                println!("{user_id} mapped to {ckey}");
+            } else {
+                println!("{user_id} is already mapped to only {ckey}");
+            }
+        }
+        CkeyCommand::Add {
+            user_id,
+            ckey,
+            primary,
+        } => {
+            let ckey = Ckey::from(&ckey);
+            if guild
+                .verification
+                .add_ckey(serenity::UserId(user_id), &ckey, primary, Actor::Console)
+                .await?
+            {
+                println!("{ckey} added to {user_id}");
+            } else {
+                println!("{user_id} already has {ckey}");
+            }
+        }
+        CkeyCommand::Remove { user_id, ckey } => {
+            let ckey = Ckey::from(&ckey);
+            if guild
+                .verification
+                .remove_ckey(serenity::UserId(user_id), &ckey, Actor::Console)
+                .await?
+            {
+                println!("{ckey} removed from {user_id}");
+            } else {
+                println!("{user_id} doesn't have {ckey}");
+            }
+        }
+        CkeyCommand::Unset { user_id } => {
+            if guild
+                .verification
+                .remove_ckeys(serenity::UserId(user_id), Actor::Console)
+                .await?
+            {
+                println!("{user_id} ckey mapping removed");
+            } else {
+                println!("{user_id} has no ckey mapping");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn whitelist(guild: &Guild, command: WhitelistCommand) -> Result<(), Error> {
+    match command {
+        WhitelistCommand::List => {
+            for (ckey, entry) in guild.whitelist.list_entries().await {
+                let Some(entry) = entry else {
+                    println!("{ckey}");
+                    continue;
+                };
+
+                let mut line = format!(
+                    "{ckey}\t{}\t{}\t{}",
+                    entry.source.name(),
+                    entry.added_by,
+                    entry.added_at
+                );
+                if let Some(expires_at) = entry.expires_at {
+                    line.push_str(&format!("\texpires {expires_at}"));
+                }
+                if let Some(reason) = entry.reason {
+                    line.push_str(&format!("\t{reason}"));
+                }
+                println!("{line}");
+            }
+        }
+        WhitelistCommand::Add {
+            ckey,
+            reason,
+            duration,
+        } => {
+            let ckey = Ckey::from(&ckey);
+
+            let mut entry = WhitelistEntry::new(Actor::Console, EntrySource::Manual);
+            entry.reason = reason;
+            entry.expires_at = duration.map(|duration| duration.later());
+
+            if guild.whitelist.add(&ckey, entry).await? {
+                println!("{ckey} added to the whitelist");
+            } else {
+                println!("{ckey} is already in the whitelist");
+            }
+        }
+        WhitelistCommand::Remove { ckey } => {
+            let ckey = Ckey::from(&ckey);
+
+            let claimants = guild.whitelist.claimants(&ckey).await;
+            if !claimants.is_empty() {
+                let users: Vec<_> = claimants.iter().map(|id| id.to_string()).collect();
+                println!(
+                    "{ckey} belongs to {}, flag them never whitelisted or take it away instead",
+                    users.join(", ")
+                );
+            } else if guild.whitelist.remove(&ckey, Actor::Console).await? {
+                println!("{ckey} removed from the whitelist");
+            } else {
+                println!("{ckey} is not in the whitelist");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn verification(guild: &Guild, command: VerificationCommand) -> Result<(), Error> {
+    match command {
+        VerificationCommand::Status { user_id } => {
+            let user_id = serenity::UserId(user_id);
+
+            match guild.verification.get_status(&user_id).await {
+                Some(status) => println!("Status: {}", status.name()),
+                None => println!("Status: No data"),
+            }
+
+            for entry in guild.verification.get_history(&user_id).await {
+                match entry.actor {
+                    Actor::User(id) if id != user_id => {
+                        println!("{} {} by {id}", entry.timestamp, entry.event)
+                    }
+                    _ => println!("{} {}", entry.timestamp, entry.event),
+                }
+            }
+        }
+        VerificationCommand::Clear { user_id } => {
+            if guild
+                .verification
+                .remove(&serenity::UserId(user_id), Actor::Console)
+                .await?
+            {
+                println!("Cleared verification data for {user_id}");
+            } else {
+                println!("{user_id} has no verification data");
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,32 +1,79 @@
+mod audit;
+mod bulk;
 mod ckey;
 mod config;
 mod help;
+mod members;
 mod verification;
 mod whitelist;
 
-pub use ckey::{ckey, ckey_get_ctx, ckey_unset_ctx, ckey_unwhitelist_ctx, ckey_whitelist_ctx};
+pub use audit::audit;
+pub use bulk::bulk;
+pub use ckey::{ckey, ckey_get_ctx};
 pub use config::{
     config, config_greeting_message_ctx, config_rejected_message_ctx, config_verified_message_ctx,
 };
 pub use help::{help, license, source, version};
+pub use members::members;
 pub use verification::{
-    verification, verification_clear_ctx, verification_greet_ctx, verification_status_ctx,
+    verification, verification_approve_ctx, verification_reject_ctx, verification_status_ctx,
+    verification_transcript_ctx,
 };
 pub use whitelist::whitelist;
 
+// Discord only allows five user context menus, these have slash command
+// equivalents and aren't registered
+#[allow(unused_imports)]
+pub use ckey::{ckey_unset_ctx, ckey_unwhitelist_ctx, ckey_whitelist_ctx};
+#[allow(unused_imports)]
+pub use verification::{verification_clear_ctx, verification_greet_ctx};
+
 pub fn commands() -> Vec<poise::Command<crate::app::Data, crate::app::Error>> {
     vec![
+        audit(),
+        bulk(),
         ckey(),
         config(),
         help(),
         license(),
         source(),
         version(),
+        members(),
         verification(),
         whitelist(),
         ckey_get_ctx(),
+        verification_status_ctx(),
+        verification_transcript_ctx(),
+        verification_approve_ctx(),
+        verification_reject_ctx(),
         config_greeting_message_ctx(),
         config_rejected_message_ctx(),
         config_verified_message_ctx(),
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use poise::ContextMenuCommandAction;
+
+    use super::*;
+
+    /// Discord refuses to register more than five of each kind
+    #[test]
+    fn context_menus_within_limit() {
+        let commands = commands();
+        let count = |user: bool| {
+            commands
+                .iter()
+                .filter(|c| match c.context_menu_action {
+                    Some(ContextMenuCommandAction::User(_)) => user,
+                    Some(ContextMenuCommandAction::Message(_)) => !user,
+                    _ => false,
+                })
+                .count()
+        };
+
+        assert!(count(true) <= 5, "{} user context menus", count(true));
+        assert!(count(false) <= 5, "{} message context menus", count(false));
+    }
+}
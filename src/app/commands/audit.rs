@@ -0,0 +1,71 @@
+use poise::serenity_prelude as serenity;
+
+use crate::app::{
+    models::{Age, Ckey},
+    services::{Actor, AuditFilter},
+    Context, Error,
+};
+
+/// Search the audit log
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn audit(
+    ctx: Context<'_>,
+    #[description = "Only changes affecting this user"] user: Option<serenity::User>,
+    #[description = "Only changes affecting this ckey"] ckey: Option<String>,
+    #[description = "Only changes made by this user"] actor: Option<serenity::User>,
+    #[description = "Only changes made automatically by the bot"] system: Option<bool>,
+    #[description = "Only changes newer than this, like 7d"] newer_than: Option<Age>,
+    #[description = "Only changes older than this, like 12h"] older_than: Option<Age>,
+) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+
+    let actor = match (actor, system) {
+        (Some(actor), _) => Some(Actor::User(actor.id)),
+        (None, Some(true)) => Some(Actor::System),
+        (None, _) => None,
+    };
+
+    let entries = ctx
+        .data()
+        .audit
+        .query(AuditFilter {
+            guild_id: Some(guild.id),
+            whitelist: Some(guild.whitelist.path().into()),
+            // Changes to the bot as a whole concern every server, only its
+            // owners get to see them
+            global: ctx.framework().options().owners.contains(&ctx.author().id),
+            user_id: user.map(|u| u.id),
+            ckey: ckey.map(|c| Ckey::from(&c).as_str().to_string()),
+            actor,
+            after: newer_than.map(|age| age.ago()),
+            before: older_than.map(|age| age.ago()),
+        })
+        .await?;
+
+    if entries.is_empty() {
+        ctx.send(|b| b.ephemeral(true).content("No matching audit entries"))
+            .await?;
+
+        return Ok(());
+    }
+
+    let pages: Vec<_> = entries
+        .chunks(10)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .map(|entry| entry.to_string())
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+        .collect();
+
+    poise::samples::paginate(ctx, &pages.iter().map(|s| &s[..]).collect::<Vec<_>>()).await?;
+
+    Ok(())
+}
@@ -0,0 +1,347 @@
+use std::time::Duration;
+
+use poise::{serenity_prelude as serenity, ReplyHandle};
+
+use crate::app::{
+    models::{Age, Ckey},
+    services::{Actor, VerificationStatus},
+    Context, Error,
+};
+
+/// Pause between members so big batches stay clear of Discord rate limits
+const PACE: Duration = Duration::from_secs(1);
+/// Targets listed in the preview before the rest are only counted
+const PREVIEW_LIMIT: usize = 20;
+/// How long the confirmation buttons wait for an answer
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(120);
+/// Progress is reported every this many targets
+const PROGRESS_INTERVAL: usize = 10;
+
+/// Shows what an operation would affect and, unless it's a dry run, asks to go
+/// ahead. Returns the reply to report progress in once confirmed
+async fn confirm<'a>(
+    ctx: Context<'a>,
+    action: &str,
+    targets: &[String],
+    dry_run: bool,
+) -> Result<Option<ReplyHandle<'a>>, Error> {
+    if targets.is_empty() {
+        ctx.send(|b| b.ephemeral(true).content(format!("Nothing to {action}")))
+            .await?;
+
+        return Ok(None);
+    }
+
+    let mut preview = format!("This will {action} {} targets:\n", targets.len());
+    for target in targets.iter().take(PREVIEW_LIMIT) {
+        preview.push_str(&format!("\n{target}"));
+    }
+    if targets.len() > PREVIEW_LIMIT {
+        preview.push_str(&format!("\n... and {} more", targets.len() - PREVIEW_LIMIT));
+    }
+
+    if dry_run {
+        ctx.send(|b| {
+            b.ephemeral(true)
+                .content(format!("{preview}\n\nDry run, nothing was changed"))
+        })
+        .await?;
+
+        return Ok(None);
+    }
+
+    ask(ctx, &preview).await
+}
+
+/// Shows a preview with buttons to go ahead or cancel. Returns the reply,
+/// saying work has started, once confirmed
+pub(super) async fn ask<'a>(
+    ctx: Context<'a>,
+    preview: &str,
+) -> Result<Option<ReplyHandle<'a>>, Error> {
+    let confirm_id = format!("{}confirm", ctx.id());
+    let cancel_id = format!("{}cancel", ctx.id());
+
+    let handle = ctx
+        .send(|b| {
+            b.ephemeral(true).content(preview).components(|b| {
+                b.create_action_row(|b| {
+                    b.create_button(|b| {
+                        b.custom_id(&confirm_id)
+                            .label("Confirm")
+                            .style(serenity::ButtonStyle::Danger)
+                    })
+                    .create_button(|b| {
+                        b.custom_id(&cancel_id)
+                            .label("Cancel")
+                            .style(serenity::ButtonStyle::Secondary)
+                    })
+                })
+            })
+        })
+        .await?;
+
+    let ctx_id = ctx.id().to_string();
+    let press = serenity::CollectComponentInteraction::new(ctx)
+        .author_id(ctx.author().id)
+        .filter(move |press| press.data.custom_id.starts_with(&ctx_id))
+        .timeout(CONFIRM_TIMEOUT)
+        .await;
+
+    let confirmed = match press {
+        Some(press) => {
+            press
+                .create_interaction_response(ctx, |b| {
+                    b.kind(serenity::InteractionResponseType::DeferredUpdateMessage)
+                })
+                .await?;
+
+            press.data.custom_id == confirm_id
+        }
+        None => false,
+    };
+
+    let status = if confirmed {
+        "Working..."
+    } else {
+        "Cancelled, nothing was changed"
+    };
+    handle
+        .edit(ctx, |b| {
+            b.content(format!("{preview}\n\n{status}"))
+                .components(|b| b)
+        })
+        .await?;
+
+    Ok(confirmed.then_some(handle))
+}
+
+/// Counts of how a bulk operation went
+#[derive(Default)]
+struct Progress {
+    done: usize,
+    skipped: usize,
+    failed: usize,
+}
+
+impl Progress {
+    fn handled(&self) -> usize {
+        self.done + self.skipped + self.failed
+    }
+
+    /// Edits the confirmation reply every few targets and once finished. The
+    /// reply can only be edited for 15 minutes, after that the work carries on
+    /// and the final counts are sent by DM instead
+    async fn report(&self, ctx: Context<'_>, handle: &ReplyHandle<'_>, action: &str, total: usize) {
+        let handled = self.handled();
+        if !handled.is_multiple_of(PROGRESS_INTERVAL) && handled != total {
+            return;
+        }
+
+        let state = if handled == total {
+            "Finished"
+        } else {
+            "Working"
+        };
+        let mut content = format!("{state}: {action} {handled}/{total}, {} done", self.done);
+        if self.skipped > 0 {
+            content.push_str(&format!(", {} skipped", self.skipped));
+        }
+        if self.failed > 0 {
+            content.push_str(&format!(", {} failed, see the bot log", self.failed));
+        }
+
+        let Err(e) = handle.edit(ctx, |b| b.content(&content)).await else {
+            return;
+        };
+        log::warn!("bulk {action} progress: {e}");
+
+        if handled == total {
+            if let Err(e) = ctx.author().dm(ctx, |b| b.content(&content)).await {
+                log::error!("bulk {action} result: {e}");
+            }
+        }
+    }
+}
+
+/// Act on many members at once
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR",
+    subcommands("regreet", "clear_rejected", "unwhitelist_unmapped")
+)]
+pub async fn bulk(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.send(|b| b.ephemeral(true).content("Use one of the subcommands"))
+        .await?;
+    Ok(())
+}
+
+/// Greet again everyone who hasn't started verification in a while
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn regreet(
+    ctx: Context<'_>,
+    #[description = "Only members greeted longer ago than this, like 7d"] older_than: Age,
+    #[description = "Only show who would be affected"] dry_run: Option<bool>,
+) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let cutoff = older_than.ago();
+
+    let targets: Vec<_> = guild
+        .verification
+        .list()
+        .await?
+        .into_iter()
+        .filter(|m| matches!(m.status, VerificationStatus::Greeted { .. }))
+        .filter(|m| m.since.is_some_and(|since| since < cutoff))
+        .map(|m| m.user_id)
+        .collect();
+
+    let preview: Vec<_> = targets.iter().map(|id| format!("<@{id}>")).collect();
+    let Some(handle) = confirm(ctx, "re-greet", &preview, dry_run.unwrap_or(false)).await? else {
+        return Ok(());
+    };
+
+    let actor = Actor::User(ctx.author().id);
+    let mut progress = Progress::default();
+
+    for user_id in &targets {
+        match guild.id.member(ctx, user_id).await {
+            // Whoever left gets greeted again if they come back
+            Err(_) => progress.skipped += 1,
+            Ok(member) => {
+                match guild
+                    .verification
+                    .regreet(ctx.serenity_context(), &member.user, actor)
+                    .await
+                {
+                    Ok(_) => progress.done += 1,
+                    Err(e) => {
+                        log::error!("bulk regreet {user_id}: {e}");
+                        progress.failed += 1;
+                    }
+                }
+
+                tokio::time::sleep(PACE).await;
+            }
+        }
+
+        progress
+            .report(ctx, &handle, "re-greet", targets.len())
+            .await;
+    }
+
+    Ok(())
+}
+
+/// Clear the verification of everyone rejected a while ago, letting them try again
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn clear_rejected(
+    ctx: Context<'_>,
+    #[description = "Only members rejected longer ago than this, like 30d"] older_than: Age,
+    #[description = "Only show who would be affected"] dry_run: Option<bool>,
+) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let cutoff = older_than.ago();
+
+    let targets: Vec<_> = guild
+        .verification
+        .list()
+        .await?
+        .into_iter()
+        .filter(|m| m.status == VerificationStatus::Rejected)
+        .filter(|m| m.since.is_some_and(|since| since < cutoff))
+        .map(|m| m.user_id)
+        .collect();
+
+    let preview: Vec<_> = targets.iter().map(|id| format!("<@{id}>")).collect();
+    let Some(handle) = confirm(ctx, "clear", &preview, dry_run.unwrap_or(false)).await? else {
+        return Ok(());
+    };
+
+    let actor = Actor::User(ctx.author().id);
+    let mut progress = Progress::default();
+
+    for user_id in &targets {
+        match guild.verification.remove(user_id, actor).await {
+            Ok(true) => progress.done += 1,
+            Ok(false) => progress.skipped += 1,
+            Err(e) => {
+                log::error!("bulk clear {user_id}: {e}");
+                progress.failed += 1;
+            }
+        }
+
+        progress.report(ctx, &handle, "clear", targets.len()).await;
+    }
+
+    Ok(())
+}
+
+/// Remove every ckey the bot whitelisted that no member is mapped to anymore
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn unwhitelist_unmapped(
+    ctx: Context<'_>,
+    #[description = "Only show which ckeys would be removed"] dry_run: Option<bool>,
+) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+
+    // A ckey mapped in any server writing to the same whitelist file is still in use
+    let mut mapped = std::collections::HashSet::new();
+    for other in ctx.data().guilds.sharing_whitelist(&guild).await {
+        mapped.extend(other.members.mapped_ckeys().await);
+    }
+
+    // Lines written by hand, entries added by staff and temporary ones aren't
+    // meant to belong to a member
+    let mut targets: Vec<_> = guild
+        .whitelist
+        .list_managed()
+        .await
+        .into_iter()
+        .filter(|ckey| !mapped.contains(ckey))
+        .collect();
+    targets.sort();
+
+    let preview: Vec<_> = targets.iter().map(|ckey| format!("`{ckey}`")).collect();
+    let Some(handle) = confirm(ctx, "unwhitelist", &preview, dry_run.unwrap_or(false)).await?
+    else {
+        return Ok(());
+    };
+
+    let actor = Actor::User(ctx.author().id);
+    let mut progress = Progress::default();
+
+    for ckey in &targets {
+        match guild.whitelist.remove(&Ckey::from(ckey), actor).await {
+            Ok(true) => progress.done += 1,
+            Ok(false) => progress.skipped += 1,
+            Err(e) => {
+                log::error!("bulk unwhitelist {ckey}: {e}");
+                progress.failed += 1;
+            }
+        }
+
+        progress
+            .report(ctx, &handle, "unwhitelist", targets.len())
+            .await;
+    }
+
+    Ok(())
+}
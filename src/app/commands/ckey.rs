@@ -1,6 +1,10 @@
 use poise::serenity_prelude as serenity;
 
-use crate::app::{models::Ckey, Context, Error};
+use crate::app::{
+    models::Ckey,
+    services::{Actor, MappedCkey, MemberFlag, WriteBehind},
+    Context, Error,
+};
 
 /// Modify and inspect ckey mappings
 #[poise::command(
@@ -8,7 +12,7 @@ use crate::app::{models::Ckey, Context, Error};
     guild_only,
     required_permissions = "ADMINISTRATOR",
     default_member_permissions = "ADMINISTRATOR",
-    subcommands("reload", "get", "find", "set", "unset")
+    subcommands("reload", "get", "list", "find", "set", "add", "remove", "unset")
 )]
 pub async fn ckey(ctx: Context<'_>) -> Result<(), Error> {
     ctx.send(|b| b.ephemeral(true).content("Use one of the subcommands"))
@@ -16,7 +20,7 @@ pub async fn ckey(ctx: Context<'_>) -> Result<(), Error> {
     Ok(())
 }
 
-/// Reload the ckey mapping from disk
+/// Reload member records from disk
 #[poise::command(
     slash_command,
     guild_only,
@@ -24,15 +28,21 @@ pub async fn ckey(ctx: Context<'_>) -> Result<(), Error> {
     default_member_permissions = "ADMINISTRATOR"
 )]
 pub async fn reload(ctx: Context<'_>) -> Result<(), Error> {
-    ctx.data().ckey.load().await?;
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    // Otherwise changes not written yet would be lost
+    guild.members.flush().await?;
+    guild.members.load().await?;
 
-    ctx.send(|b| b.ephemeral(true).content("Ckey mapping reloaded from disk"))
-        .await?;
+    ctx.send(|b| {
+        b.ephemeral(true)
+            .content("Member records reloaded from disk")
+    })
+    .await?;
 
     Ok(())
 }
 
-/// Get user's mapped ckey
+/// Get user's primary ckey
 #[poise::command(
     slash_command,
     guild_only,
@@ -43,7 +53,8 @@ pub async fn get(
     ctx: Context<'_>,
     #[description = "Target user"] member: serenity::Member,
 ) -> Result<(), Error> {
-    let result = ctx.data().ckey.get_ckey(&member.user.id).await;
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let result = guild.members.get_ckey(&member.user.id).await;
 
     ctx.send(|b| {
         b.ephemeral(true).content(match result {
@@ -56,7 +67,47 @@ pub async fn get(
     Ok(())
 }
 
-/// Get user's mapped ckey
+/// Lists every ckey of a user, primary first
+fn describe_ckeys(user: impl std::fmt::Display, ckeys: &[MappedCkey]) -> String {
+    if ckeys.is_empty() {
+        return format!("{user} has no ckey mapping");
+    }
+
+    let ckeys: Vec<_> = ckeys
+        .iter()
+        .map(|mapped| {
+            if mapped.primary {
+                format!("`{}` (primary)", mapped.ckey)
+            } else {
+                format!("`{}`", mapped.ckey)
+            }
+        })
+        .collect();
+
+    format!("{user} is mapped to {}", ckeys.join(", "))
+}
+
+/// List all of user's ckeys
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn list(
+    ctx: Context<'_>,
+    #[description = "Target user"] member: serenity::Member,
+) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let ckeys = guild.members.get_ckeys(&member.user.id).await;
+
+    ctx.send(|b| b.ephemeral(true).content(describe_ckeys(&member, &ckeys)))
+        .await?;
+
+    Ok(())
+}
+
+/// List all of user's ckeys
 #[poise::command(
     context_menu_command = "Get ckey",
     guild_only,
@@ -64,15 +115,11 @@ pub async fn get(
     default_member_permissions = "ADMINISTRATOR"
 )]
 pub async fn ckey_get_ctx(ctx: Context<'_>, user: serenity::User) -> Result<(), Error> {
-    let result = ctx.data().ckey.get_ckey(&user.id).await;
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let ckeys = guild.members.get_ckeys(&user.id).await;
 
-    ctx.send(|b| {
-        b.ephemeral(true).content(match result {
-            Some(ckey) => format!("{user} is mapped to `{ckey}`"),
-            None => format!("{user} has no ckey mapping"),
-        })
-    })
-    .await?;
+    ctx.send(|b| b.ephemeral(true).content(describe_ckeys(&user, &ckeys)))
+        .await?;
 
     Ok(())
 }
@@ -88,8 +135,9 @@ pub async fn find(
     ctx: Context<'_>,
     #[description = "Corresponding ckey"] ckey: String,
 ) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
     let ckey = Ckey::from(&ckey);
-    let result = ctx.data().ckey.get_user(&ckey).await;
+    let result = guild.members.get_user(&ckey).await;
     let response = match result {
         Some(user_id) => match user_id.to_user(ctx).await {
             Ok(user) => format!("`{ckey}` belongs to {user}"),
@@ -103,7 +151,7 @@ pub async fn find(
     Ok(())
 }
 
-/// Set user's ckey mapping
+/// Set user's ckey, replacing any others they have
 #[poise::command(
     slash_command,
     guild_only,
@@ -115,14 +163,55 @@ pub async fn set(
     #[description = "Target user"] member: serenity::Member,
     #[description = "Corresponding ckey"] ckey: String,
 ) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
     let ckey = Ckey::from(&ckey);
-    let result = ctx.data().ckey.insert(member.user.id, &ckey).await?;
+    let result = guild
+        .verification
+        .set_ckey(member.user.id, &ckey, Actor::User(ctx.author().id))
+        .await?;
 
     ctx.send(|b| {
         b.ephemeral(true).content(if result {
             format!("{member} mapped to `{ckey}`")
         } else {
-            format!("{member} is already mapped to `{ckey}`")
+            format!("{member} is already mapped to only `{ckey}`")
+        })
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// Add another ckey to user's mapping
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn add(
+    ctx: Context<'_>,
+    #[description = "Target user"] member: serenity::Member,
+    #[description = "Additional ckey"] ckey: String,
+    #[description = "Make it the user's primary ckey"] primary: Option<bool>,
+) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let ckey = Ckey::from(&ckey);
+    let result = guild
+        .verification
+        .add_ckey(
+            member.user.id,
+            &ckey,
+            primary.unwrap_or(false),
+            Actor::User(ctx.author().id),
+        )
+        .await?;
+
+    ctx.send(|b| {
+        b.ephemeral(true).content(if result {
+            format!("`{ckey}` added to {member}")
+        } else {
+            format!("{member} already has `{ckey}`")
         })
     })
     .await?;
@@ -130,7 +219,44 @@ pub async fn set(
     Ok(())
 }
 
-/// Unset user's ckey mapping
+/// Remove a single ckey from user's mapping
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn remove(
+    ctx: Context<'_>,
+    #[description = "Target user"] member: serenity::Member,
+    #[description = "Ckey to remove"] ckey: String,
+) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let ckey = Ckey::from(&ckey);
+    let result = guild
+        .verification
+        .remove_ckey(member.user.id, &ckey, Actor::User(ctx.author().id))
+        .await?;
+
+    // Entries added by hand or claimed by another server sharing the file stay
+    let whitelisted = guild.whitelist.list().await.contains(&ckey.to_string());
+
+    ctx.send(|b| {
+        b.ephemeral(true).content(match (result, whitelisted) {
+            (true, true) => format!(
+                "`{ckey}` removed from {member}, it's still whitelisted until removed with \
+                `/whitelist remove`"
+            ),
+            (true, false) => format!("`{ckey}` removed from {member}"),
+            (false, _) => format!("{member} doesn't have `{ckey}`"),
+        })
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// Unset all of user's ckeys
 #[poise::command(
     slash_command,
     guild_only,
@@ -141,7 +267,11 @@ pub async fn unset(
     ctx: Context<'_>,
     #[description = "Target user"] member: serenity::Member,
 ) -> Result<(), Error> {
-    let result = ctx.data().ckey.remove(&member.user.id).await?;
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let result = guild
+        .verification
+        .remove_ckeys(member.user.id, Actor::User(ctx.author().id))
+        .await?;
 
     ctx.send(|b| {
         b.ephemeral(true).content(if result {
@@ -155,7 +285,7 @@ pub async fn unset(
     Ok(())
 }
 
-/// Unset user's ckey mapping
+/// Unset all of user's ckeys
 #[poise::command(
     context_menu_command = "Unset ckey",
     guild_only,
@@ -163,7 +293,11 @@ pub async fn unset(
     default_member_permissions = "ADMINISTRATOR"
 )]
 pub async fn ckey_unset_ctx(ctx: Context<'_>, user: serenity::User) -> Result<(), Error> {
-    let result = ctx.data().ckey.remove(&user.id).await?;
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let result = guild
+        .verification
+        .remove_ckeys(user.id, Actor::User(ctx.author().id))
+        .await?;
 
     ctx.send(|b| {
         b.ephemeral(true).content(if result {
@@ -177,7 +311,7 @@ pub async fn ckey_unset_ctx(ctx: Context<'_>, user: serenity::User) -> Result<()
     Ok(())
 }
 
-/// Add user ckey to the whitelist
+/// Always whitelist user's ckeys, verified or not
 #[poise::command(
     context_menu_command = "Whitelist",
     guild_only,
@@ -185,34 +319,28 @@ pub async fn ckey_unset_ctx(ctx: Context<'_>, user: serenity::User) -> Result<()
     default_member_permissions = "ADMINISTRATOR"
 )]
 pub async fn ckey_whitelist_ctx(ctx: Context<'_>, user: serenity::User) -> Result<(), Error> {
-    let ckey = ctx.data().ckey.get_ckey(&user.id).await;
-
-    match ckey {
-        Some(ckey) => {
-            let result = ctx.data().whitelist.insert(&ckey).await?;
-
-            ctx.send(|b| {
-                b.ephemeral(true).content(if result {
-                    format!("{user} as `{ckey}` added to the whitelist")
-                } else {
-                    format!("{user} is already in the whitelist as `{ckey}`")
-                })
-            })
-            .await?;
-        }
-        None => {
-            ctx.send(|b| {
-                b.ephemeral(true)
-                    .content(format!("{user} has no ckey mapping"))
-            })
-            .await?;
-        }
-    }
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let result = guild
+        .members
+        .update(user.id, Actor::User(ctx.author().id), |member| {
+            let unblocked = member.flags.remove(&MemberFlag::NeverWhitelisted);
+            member.flags.insert(MemberFlag::AlwaysWhitelisted) || unblocked
+        })
+        .await?;
+
+    ctx.send(|b| {
+        b.ephemeral(true).content(if result {
+            format!("{user} is now always whitelisted")
+        } else {
+            format!("{user} is already always whitelisted")
+        })
+    })
+    .await?;
 
     Ok(())
 }
 
-/// Remove user ckey from the whitelist
+/// Keep user's ckeys off the whitelist, verified or not
 #[poise::command(
     context_menu_command = "Unwhitelist",
     guild_only,
@@ -220,29 +348,27 @@ pub async fn ckey_whitelist_ctx(ctx: Context<'_>, user: serenity::User) -> Resul
     default_member_permissions = "ADMINISTRATOR"
 )]
 pub async fn ckey_unwhitelist_ctx(ctx: Context<'_>, user: serenity::User) -> Result<(), Error> {
-    let ckey = ctx.data().ckey.get_ckey(&user.id).await;
-
-    match ckey {
-        Some(ckey) => {
-            let result = ctx.data().whitelist.remove(&ckey).await?;
-
-            ctx.send(|b| {
-                b.ephemeral(true).content(if result {
-                    format!("{user} as `{ckey}` removed from the whitelist")
-                } else {
-                    format!("{user} as `{ckey}` is not in the whitelist")
-                })
-            })
-            .await?;
-        }
-        None => {
-            ctx.send(|b| {
-                b.ephemeral(true)
-                    .content(format!("{user} has no ckey mapping"))
-            })
-            .await?;
-        }
-    }
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let actor = Actor::User(ctx.author().id);
+    let result = guild
+        .members
+        .update(user.id, actor, |member| {
+            let unforced = member.flags.remove(&MemberFlag::AlwaysWhitelisted);
+            member.flags.insert(MemberFlag::NeverWhitelisted) || unforced
+        })
+        .await?;
+
+    // Entries added by hand aren't derived from the record, so they go too
+    guild.members.unwhitelist(&user.id, actor).await?;
+
+    ctx.send(|b| {
+        b.ephemeral(true).content(if result {
+            format!("{user} is now kept off the whitelist")
+        } else {
+            format!("{user} is already kept off the whitelist")
+        })
+    })
+    .await?;
 
     Ok(())
 }
@@ -1,6 +1,24 @@
+use std::path::PathBuf;
+
 use poise::serenity_prelude as serenity;
 
-use crate::app::{Context, Error};
+use crate::{
+    app::{services::Actor, Context, Error},
+    config::{GuildConfig, LogEvent, MessageKind},
+};
+
+/// Replies with the problem and returns false if the message template is invalid
+async fn check_template(ctx: Context<'_>, kind: MessageKind, source: &str) -> Result<bool, Error> {
+    match kind.parse(source) {
+        Ok(_) => Ok(true),
+        Err(e) => {
+            ctx.send(|b| b.ephemeral(true).content(format!("Invalid {kind}: {e}")))
+                .await?;
+
+            Ok(false)
+        }
+    }
+}
 
 /// Set config options
 #[poise::command(
@@ -10,13 +28,20 @@ use crate::app::{Context, Error};
     default_member_permissions = "ADMINISTRATOR",
     subcommands(
         "reload",
+        "init",
         "greeting_channel",
         "greeting_message",
         "log_channel",
+        "log_route",
+        "reset_log_route",
+        "log_routes",
         "verified_role",
         "verified_message",
         "rejected_message",
-        "ckey_prompt"
+        "embed",
+        "clear_embed",
+        "ckey_prompt",
+        "whitelist_comments"
     )
 )]
 pub async fn config(ctx: Context<'_>) -> Result<(), Error> {
@@ -34,6 +59,7 @@ pub async fn config(ctx: Context<'_>) -> Result<(), Error> {
 )]
 pub async fn reload(ctx: Context<'_>) -> Result<(), Error> {
     ctx.data().config.load().await?;
+    ctx.data().guilds.load().await?;
 
     ctx.send(|b| b.ephemeral(true).content("Config reloaded from disk"))
         .await?;
@@ -41,6 +67,60 @@ pub async fn reload(ctx: Context<'_>) -> Result<(), Error> {
     Ok(())
 }
 
+/// Set up verification for this server
+#[poise::command(
+    slash_command,
+    guild_only,
+    owners_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn init(
+    ctx: Context<'_>,
+    #[description = "Channel to greet newcomers in"]
+    #[channel_types("Text")]
+    greeting_channel: serenity::GuildChannel,
+    #[description = "Channel to log bot events to"]
+    #[channel_types("Text")]
+    log_channel: serenity::GuildChannel,
+    #[description = "Role to give to users who pass verification"] verified_role: serenity::Role,
+    #[description = "Whitelist file location on the bot host"] whitelist_path: String,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or(Error::GuildNotConfigured(None))?;
+
+    if ctx.data().guilds.get(guild_id).await.is_some() {
+        ctx.send(|b| {
+            b.ephemeral(true)
+                .content("This server is already configured")
+        })
+        .await?;
+
+        return Ok(());
+    }
+
+    let guild_config = GuildConfig::new(
+        greeting_channel.id,
+        log_channel.id,
+        verified_role.id,
+        PathBuf::from(whitelist_path),
+    );
+
+    ctx.data().guilds.init(guild_id, &guild_config).await?;
+
+    ctx.data()
+        .config
+        .insert_guild(guild_id, guild_config, Actor::User(ctx.author().id))
+        .await?;
+
+    ctx.send(|b| {
+        b.ephemeral(true)
+            .content("Server configured, use the other `/config` subcommands to customize messages")
+    })
+    .await?;
+
+    Ok(())
+}
+
 /// Set the greeting channel
 #[poise::command(
     slash_command,
@@ -54,9 +134,16 @@ pub async fn greeting_channel(
     #[channel_types("Text")]
     channel: serenity::GuildChannel,
 ) -> Result<(), Error> {
-    let config = &ctx.data().config;
-    config.config.write().await.greeting_channel_id = channel.id;
-    config.store().await?;
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    ctx.data()
+        .config
+        .update_guild(
+            guild.id,
+            Actor::User(ctx.author().id),
+            format!("greeting channel set to {channel}"),
+            |c| c.greeting_channel_id = channel.id,
+        )
+        .await?;
 
     ctx.send(|b| {
         b.ephemeral(true)
@@ -78,9 +165,21 @@ pub async fn greeting_message(
     ctx: Context<'_>,
     #[description = "Message to send with newcomer ping"] string: String,
 ) -> Result<(), Error> {
-    let config = &ctx.data().config;
-    config.config.write().await.messages.greeting = string.clone();
-    config.store().await?;
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+
+    if !check_template(ctx, MessageKind::Greeting, &string).await? {
+        return Ok(());
+    }
+
+    ctx.data()
+        .config
+        .update_guild(
+            guild.id,
+            Actor::User(ctx.author().id),
+            "greeting message set",
+            |c| c.messages.greeting = string.clone(),
+        )
+        .await?;
 
     ctx.send(|b| {
         b.ephemeral(true)
@@ -102,9 +201,21 @@ pub async fn config_greeting_message_ctx(
     ctx: Context<'_>,
     msg: serenity::Message,
 ) -> Result<(), Error> {
-    let config = &ctx.data().config;
-    config.config.write().await.messages.greeting = msg.content;
-    config.store().await?;
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+
+    if !check_template(ctx, MessageKind::Greeting, &msg.content).await? {
+        return Ok(());
+    }
+
+    ctx.data()
+        .config
+        .update_guild(
+            guild.id,
+            Actor::User(ctx.author().id),
+            "greeting message set",
+            |c| c.messages.greeting = msg.content,
+        )
+        .await?;
 
     ctx.send(|b| b.ephemeral(true).content("Greeting message set"))
         .await?;
@@ -125,9 +236,16 @@ pub async fn log_channel(
     #[channel_types("Text")]
     channel: serenity::GuildChannel,
 ) -> Result<(), Error> {
-    let config = &ctx.data().config;
-    config.config.write().await.log_channel_id = channel.id;
-    config.store().await?;
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    ctx.data()
+        .config
+        .update_guild(
+            guild.id,
+            Actor::User(ctx.author().id),
+            format!("log channel set to {channel}"),
+            |c| c.log_channel_id = channel.id,
+        )
+        .await?;
 
     ctx.send(|b| {
         b.ephemeral(true)
@@ -149,9 +267,16 @@ pub async fn verified_role(
     ctx: Context<'_>,
     #[description = "Role to give to users who pass verification"] role: serenity::Role,
 ) -> Result<(), Error> {
-    let config = &ctx.data().config;
-    config.config.write().await.verified_role_id = role.id;
-    config.store().await?;
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    ctx.data()
+        .config
+        .update_guild(
+            guild.id,
+            Actor::User(ctx.author().id),
+            format!("verified role set to <@&{}>", role.id),
+            |c| c.verified_role_id = role.id,
+        )
+        .await?;
 
     ctx.send(|b| {
         b.ephemeral(true)
@@ -173,9 +298,21 @@ pub async fn verified_message(
     ctx: Context<'_>,
     #[description = "Message to send to users who pass verification"] string: String,
 ) -> Result<(), Error> {
-    let config = &ctx.data().config;
-    config.config.write().await.messages.verified = string.clone();
-    config.store().await?;
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+
+    if !check_template(ctx, MessageKind::Verified, &string).await? {
+        return Ok(());
+    }
+
+    ctx.data()
+        .config
+        .update_guild(
+            guild.id,
+            Actor::User(ctx.author().id),
+            "verified message set",
+            |c| c.messages.verified = string.clone(),
+        )
+        .await?;
 
     ctx.send(|b| {
         b.ephemeral(true)
@@ -197,9 +334,21 @@ pub async fn config_verified_message_ctx(
     ctx: Context<'_>,
     msg: serenity::Message,
 ) -> Result<(), Error> {
-    let config = &ctx.data().config;
-    config.config.write().await.messages.verified = msg.content;
-    config.store().await?;
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+
+    if !check_template(ctx, MessageKind::Verified, &msg.content).await? {
+        return Ok(());
+    }
+
+    ctx.data()
+        .config
+        .update_guild(
+            guild.id,
+            Actor::User(ctx.author().id),
+            "verified message set",
+            |c| c.messages.verified = msg.content,
+        )
+        .await?;
 
     ctx.send(|b| b.ephemeral(true).content("Verified message set"))
         .await?;
@@ -218,9 +367,21 @@ pub async fn rejected_message(
     ctx: Context<'_>,
     #[description = "Message to send to users who fail verification"] string: String,
 ) -> Result<(), Error> {
-    let config = &ctx.data().config;
-    config.config.write().await.messages.rejected = string.clone();
-    config.store().await?;
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+
+    if !check_template(ctx, MessageKind::Rejected, &string).await? {
+        return Ok(());
+    }
+
+    ctx.data()
+        .config
+        .update_guild(
+            guild.id,
+            Actor::User(ctx.author().id),
+            "rejected message set",
+            |c| c.messages.rejected = string.clone(),
+        )
+        .await?;
 
     ctx.send(|b| {
         b.ephemeral(true)
@@ -242,9 +403,21 @@ pub async fn config_rejected_message_ctx(
     ctx: Context<'_>,
     msg: serenity::Message,
 ) -> Result<(), Error> {
-    let config = &ctx.data().config;
-    config.config.write().await.messages.rejected = msg.content;
-    config.store().await?;
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+
+    if !check_template(ctx, MessageKind::Rejected, &msg.content).await? {
+        return Ok(());
+    }
+
+    ctx.data()
+        .config
+        .update_guild(
+            guild.id,
+            Actor::User(ctx.author().id),
+            "rejected message set",
+            |c| c.messages.rejected = msg.content,
+        )
+        .await?;
 
     ctx.send(|b| b.ephemeral(true).content("Rejected message set"))
         .await?;
@@ -263,9 +436,16 @@ pub async fn ckey_prompt(
     ctx: Context<'_>,
     #[description = "Message to prompt users for their ckey with"] string: String,
 ) -> Result<(), Error> {
-    let config = &ctx.data().config;
-    config.config.write().await.ckey_prompt = string.clone();
-    config.store().await?;
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    ctx.data()
+        .config
+        .update_guild(
+            guild.id,
+            Actor::User(ctx.author().id),
+            "ckey prompt set",
+            |c| c.ckey_prompt = string.clone(),
+        )
+        .await?;
 
     ctx.send(|b| {
         b.ephemeral(true)
@@ -275,3 +455,286 @@ pub async fn ckey_prompt(
 
     Ok(())
 }
+
+/// Name members in comments next to their whitelist entries
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn whitelist_comments(
+    ctx: Context<'_>,
+    #[description = "Whether entries get a trailing comment with the member's user ID"]
+    enabled: bool,
+) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    ctx.data()
+        .config
+        .update_guild(
+            guild.id,
+            Actor::User(ctx.author().id),
+            if enabled {
+                "whitelist comments enabled"
+            } else {
+                "whitelist comments disabled"
+            },
+            |c| c.whitelist_comments = enabled,
+        )
+        .await?;
+    guild.whitelist.set_comments(guild.id, enabled).await;
+
+    ctx.send(|b| {
+        b.ephemeral(true).content(if enabled {
+            "Whitelist entries will name their member in a comment"
+        } else {
+            "Whitelist entries will no longer name their member"
+        })
+    })
+    .await?;
+
+    Ok(())
+}
+
+#[derive(poise::ChoiceParameter, Clone, Copy)]
+pub enum Message {
+    Greeting,
+    Verified,
+    Rejected,
+}
+
+impl From<Message> for MessageKind {
+    fn from(value: Message) -> Self {
+        match value {
+            Message::Greeting => MessageKind::Greeting,
+            Message::Verified => MessageKind::Verified,
+            Message::Rejected => MessageKind::Rejected,
+        }
+    }
+}
+
+/// Set the embed attached to a message
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn embed(
+    ctx: Context<'_>,
+    #[description = "Message to attach the embed to"] message: Message,
+    #[description = "Embed title"] title: Option<String>,
+    #[description = "Embed color as hex, like #5865F2"] color: Option<String>,
+    #[description = "Embed text"] description: Option<String>,
+    #[description = "Thumbnail URL, like {user.avatar}"] thumbnail: Option<String>,
+) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let kind = MessageKind::from(message);
+
+    for source in [&title, &description, &thumbnail].into_iter().flatten() {
+        if !check_template(ctx, kind, source).await? {
+            return Ok(());
+        }
+    }
+
+    let color = match color {
+        Some(color) => match u32::from_str_radix(color.trim().trim_start_matches('#'), 16) {
+            Ok(color) if color <= 0xFFFFFF => Some(color),
+            _ => {
+                ctx.send(|b| {
+                    b.ephemeral(true)
+                        .content(format!("Invalid color `{color}`"))
+                })
+                .await?;
+
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+
+    ctx.data()
+        .config
+        .update_guild(
+            guild.id,
+            Actor::User(ctx.author().id),
+            format!("embed set for {kind}"),
+            |c| {
+                let embed = c
+                    .messages
+                    .embed_mut(kind)
+                    .get_or_insert_with(Default::default);
+
+                if title.is_some() {
+                    embed.title = title;
+                }
+                if color.is_some() {
+                    embed.color = color;
+                }
+                if description.is_some() {
+                    embed.description = description;
+                }
+                if thumbnail.is_some() {
+                    embed.thumbnail = thumbnail;
+                }
+            },
+        )
+        .await?;
+
+    ctx.send(|b| b.ephemeral(true).content(format!("Embed set for {kind}")))
+        .await?;
+
+    Ok(())
+}
+
+/// Remove the embed attached to a message
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn clear_embed(
+    ctx: Context<'_>,
+    #[description = "Message to remove the embed from"] message: Message,
+) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let kind = MessageKind::from(message);
+
+    ctx.data()
+        .config
+        .update_guild(
+            guild.id,
+            Actor::User(ctx.author().id),
+            format!("embed removed from {kind}"),
+            |c| *c.messages.embed_mut(kind) = None,
+        )
+        .await?;
+
+    ctx.send(|b| {
+        b.ephemeral(true)
+            .content(format!("Embed removed from {kind}"))
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// Route a log event to a channel
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn log_route(
+    ctx: Context<'_>,
+    #[description = "Event to route"] event: LogEvent,
+    #[description = "Channel to log the event to instead of the log channel"]
+    #[channel_types("Text")]
+    channel: Option<serenity::GuildChannel>,
+    #[description = "Role to ping with the event"] mention: Option<serenity::Role>,
+    #[description = "Whether to log the event at all"] enabled: Option<bool>,
+) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+
+    ctx.data()
+        .config
+        .update_guild(
+            guild.id,
+            Actor::User(ctx.author().id),
+            format!("log route for `{event}` updated"),
+            |c| {
+                let route = c.log_routes.entry(event).or_default();
+
+                if let Some(channel) = channel {
+                    route.channel_id = Some(channel.id);
+                }
+                if let Some(mention) = mention {
+                    route.mention_role_id = Some(mention.id);
+                }
+                if let Some(enabled) = enabled {
+                    route.enabled = enabled;
+                }
+            },
+        )
+        .await?;
+
+    ctx.send(|b| {
+        b.ephemeral(true)
+            .content(format!("Route for `{event}` updated"))
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// Log an event to the log channel again
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn reset_log_route(
+    ctx: Context<'_>,
+    #[description = "Event to reset"] event: LogEvent,
+) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+
+    ctx.data()
+        .config
+        .update_guild(
+            guild.id,
+            Actor::User(ctx.author().id),
+            format!("log route for `{event}` reset"),
+            |c| {
+                c.log_routes.remove(&event);
+            },
+        )
+        .await?;
+
+    ctx.send(|b| {
+        b.ephemeral(true)
+            .content(format!("`{event}` is logged to the log channel"))
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// Show where each event is logged
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn log_routes(ctx: Context<'_>) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+
+    let response = {
+        let config = ctx
+            .data()
+            .config
+            .guild(guild.id)
+            .await
+            .ok_or(Error::GuildNotConfigured(Some(guild.id)))?;
+
+        LogEvent::ALL
+            .iter()
+            .map(|&event| match config.log_target(event) {
+                Some((channel_id, Some(role_id))) => {
+                    format!("`{event}`: <#{channel_id}>, pings <@&{role_id}>")
+                }
+                Some((channel_id, None)) => format!("`{event}`: <#{channel_id}>"),
+                None => format!("`{event}`: disabled"),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+
+    ctx.send(|b| b.ephemeral(true).content(response)).await?;
+
+    Ok(())
+}
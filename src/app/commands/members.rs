@@ -0,0 +1,283 @@
+use std::borrow::Cow;
+
+use poise::serenity_prelude as serenity;
+
+use crate::app::{
+    services::{Actor, Format, ImportMode, ImportPlan, Member, MemberFlag, MemberRecord},
+    Context, Error,
+};
+
+use super::bulk;
+
+/// Longer reports are sent as a file instead
+const MESSAGE_LIMIT: usize = 2000;
+/// Longer reports are cut down to their summary when asking to confirm,
+/// leaving room for the question
+const PREVIEW_LIMIT: usize = 1800;
+
+/// Inspect, annotate, export and import member data
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR",
+    subcommands("info", "note", "flag", "export", "import")
+)]
+pub async fn members(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.send(|b| b.ephemeral(true).content("Use one of the subcommands"))
+        .await?;
+    Ok(())
+}
+
+/// Everything kept about a member, one field per line
+fn describe(user: impl std::fmt::Display, member: &Member) -> String {
+    let mut lines = vec![format!("**{user}**")];
+
+    match member.status {
+        Some(ref status) => lines.push(match member.status_since {
+            Some(since) => format!(
+                "Status: {} since <t:{}:f>",
+                status.name(),
+                since.unix_timestamp()
+            ),
+            None => format!("Status: {}", status.name()),
+        }),
+        None => lines.push("Status: No data".into()),
+    }
+
+    let ckeys: Vec<_> = member
+        .ckeys
+        .iter()
+        .map(|mapped| {
+            if mapped.primary {
+                format!("`{}` (primary)", mapped.ckey)
+            } else {
+                format!("`{}`", mapped.ckey)
+            }
+        })
+        .collect();
+    if ckeys.is_empty() {
+        lines.push("Ckeys: none".into());
+    } else {
+        lines.push(format!("Ckeys: {}", ckeys.join(", ")));
+    }
+
+    lines.push(format!(
+        "Whitelisted: {}",
+        if member.whitelisted() { "yes" } else { "no" }
+    ));
+
+    if !member.flags.is_empty() {
+        let flags: Vec<_> = member.flags.iter().map(|flag| flag.name()).collect();
+        lines.push(format!("Flags: {}", flags.join(", ")));
+    }
+
+    if let Some(ref notes) = member.notes {
+        lines.push(format!("Notes: {notes}"));
+    }
+
+    lines.join("\n")
+}
+
+/// Show everything kept about a member
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn info(
+    ctx: Context<'_>,
+    #[description = "Target user"] member: serenity::Member,
+) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let record = guild.members.get(&member.user.id).await.unwrap_or_default();
+
+    ctx.send(|b| b.ephemeral(true).content(describe(&member, &record)))
+        .await?;
+
+    Ok(())
+}
+
+/// Set or clear staff notes on a member
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn note(
+    ctx: Context<'_>,
+    #[description = "Target user"] member: serenity::Member,
+    #[description = "Notes, cleared if not given"] text: Option<String>,
+) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let cleared = text.is_none();
+    guild
+        .members
+        .update(member.user.id, Actor::User(ctx.author().id), |record| {
+            record.notes = text
+        })
+        .await?;
+
+    ctx.send(|b| {
+        b.ephemeral(true).content(if cleared {
+            format!("{member} notes cleared")
+        } else {
+            format!("{member} notes updated")
+        })
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// Set or clear a flag on a member
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn flag(
+    ctx: Context<'_>,
+    #[description = "Target user"] member: serenity::Member,
+    #[description = "Flag to change"] flag: MemberFlag,
+    #[description = "Whether the flag is set, true if not given"] value: Option<bool>,
+) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let value = value.unwrap_or(true);
+    let result = guild
+        .members
+        .set_flag(member.user.id, flag, value, Actor::User(ctx.author().id))
+        .await?;
+
+    ctx.send(|b| {
+        b.ephemeral(true).content(match (result, value) {
+            (true, true) => format!("{member} flagged {}", flag.name()),
+            (true, false) => format!("{member} no longer flagged {}", flag.name()),
+            (false, true) => format!("{member} is already flagged {}", flag.name()),
+            (false, false) => format!("{member} isn't flagged {}", flag.name()),
+        })
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// Download ckeys and verification statuses of every member
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn export(
+    ctx: Context<'_>,
+    #[description = "File format, JSON if not given"] format: Option<Format>,
+) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let format = format.unwrap_or(Format::Json);
+
+    let records = MemberRecord::export(&guild).await;
+    let data = format.serialize(&records)?;
+
+    ctx.send(|b| {
+        b.ephemeral(true)
+            .content(format!("Exported {} members", records.len()))
+            .attachment(serenity::AttachmentType::Bytes {
+                data: Cow::Owned(data),
+                filename: format!("members-{}.{}", guild.id, format.extension()),
+            })
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// Load ckeys and verification statuses from an exported file
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn import(
+    ctx: Context<'_>,
+    #[description = "JSON or CSV file in the export format"] file: serenity::Attachment,
+    #[description = "How to treat existing data, merge if not given"] mode: Option<ImportMode>,
+    #[description = "Only report what would change"] dry_run: Option<bool>,
+) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+
+    let Some(format) = Format::from_file_name(&file.filename) else {
+        ctx.send(|b| {
+            b.ephemeral(true)
+                .content("Attach a file ending in `.json` or `.csv`")
+        })
+        .await?;
+
+        return Ok(());
+    };
+
+    ctx.defer_ephemeral().await?;
+
+    let data = file.download().await?;
+    let records = match format.deserialize(&data) {
+        Ok(records) => records,
+        Err(e) => {
+            ctx.send(|b| {
+                b.ephemeral(true)
+                    .content(format!("Couldn't read `{}`: {e}", file.filename))
+            })
+            .await?;
+
+            return Ok(());
+        }
+    };
+
+    let mode = mode.unwrap_or(ImportMode::Merge);
+    let plan = ImportPlan::new(&guild, records, mode).await;
+    let report = plan.report();
+
+    let summary = if dry_run.unwrap_or(false) {
+        "Dry run, nothing was changed"
+    } else {
+        // Replacing clears everyone missing from the file, which is hard to undo
+        if mode == ImportMode::Replace {
+            let preview = if report.len() <= PREVIEW_LIMIT {
+                report.clone()
+            } else {
+                format!(
+                    "{}\n\nConflicts are listed in the report once finished",
+                    plan.summary()
+                )
+            };
+            let preview = format!("{preview}\n\nReplace the current data with the file?");
+
+            if bulk::ask(ctx, &preview).await?.is_none() {
+                return Ok(());
+            }
+        }
+
+        plan.apply(&guild, Actor::User(ctx.author().id)).await?;
+        "Import finished"
+    };
+
+    let content = format!("{report}\n\n{summary}");
+    if content.len() <= MESSAGE_LIMIT {
+        ctx.send(|b| b.ephemeral(true).content(content)).await?;
+    } else {
+        ctx.send(|b| {
+            b.ephemeral(true)
+                .content(format!("{summary}, see the attached report"))
+                .attachment(serenity::AttachmentType::Bytes {
+                    data: Cow::Owned(report.into_bytes()),
+                    filename: "import-report.txt".into(),
+                })
+        })
+        .await?;
+    }
+
+    Ok(())
+}
@@ -1,17 +1,70 @@
-use poise::serenity_prelude as serenity;
+use std::{collections::HashSet, time::Duration};
+
+use poise::{serenity_prelude as serenity, Modal};
 
 use crate::app::{
-    services::{SendGreetingError, VerificationError, VerificationStatus},
-    Context, Error,
+    models::{Age, Ckey},
+    services::{Actor, Guild, SendGreetingError, VerificationError, VerificationStatus},
+    ApplicationContext, Context, Error,
 };
 
+/// Discord rejects messages longer than this
+const MESSAGE_LIMIT: usize = 2000;
+
+/// Current status followed by the member's verification timeline, oldest entries
+/// are dropped if it doesn't fit in one message
+async fn timeline(guild: &Guild, user_id: serenity::UserId) -> String {
+    let status = match guild.verification.get_status(&user_id).await {
+        None => "No data",
+        Some(status) => status.name(),
+    };
+    let header = format!("Status: **{status}**");
+
+    let mut entries: Vec<_> = guild
+        .verification
+        .get_history(&user_id)
+        .await
+        .iter()
+        .map(|entry| entry.describe(user_id))
+        .collect();
+
+    if entries.is_empty() {
+        return format!("{header}\nNo recorded history");
+    }
+
+    let mut skipped = 0;
+    loop {
+        let skipped_line = match skipped {
+            0 => String::new(),
+            n => format!("\n... {n} earlier entries"),
+        };
+        let response = format!("{header}{skipped_line}\n{}", entries.join("\n"));
+
+        if response.len() <= MESSAGE_LIMIT || entries.len() <= 1 {
+            return response;
+        }
+
+        entries.remove(0);
+        skipped += 1;
+    }
+}
+
 /// Modify and inspect verification data
 #[poise::command(
     slash_command,
     guild_only,
     required_permissions = "ADMINISTRATOR",
     default_member_permissions = "ADMINISTRATOR",
-    subcommands("status", "clear", "greet")
+    subcommands(
+        "status",
+        "stats",
+        "transcript",
+        "list",
+        "approve",
+        "reject",
+        "clear",
+        "greet"
+    )
 )]
 pub async fn verification(ctx: Context<'_>) -> Result<(), Error> {
     ctx.send(|b| b.ephemeral(true).content("Use one of the subcommands"))
@@ -19,7 +72,7 @@ pub async fn verification(ctx: Context<'_>) -> Result<(), Error> {
     Ok(())
 }
 
-/// Get member verification status
+/// Get member verification status and history
 #[poise::command(
     slash_command,
     guild_only,
@@ -27,25 +80,15 @@ pub async fn verification(ctx: Context<'_>) -> Result<(), Error> {
     default_member_permissions = "ADMINISTRATOR"
 )]
 pub async fn status(ctx: Context<'_>, member: serenity::Member) -> Result<(), Error> {
-    let response = match ctx.data().verification.get_status(&member.user.id).await {
-        None => "No data",
-        Some(status) => match status {
-            VerificationStatus::Greeted { greeting_id: _ } => "Greeted",
-            VerificationStatus::Pending {
-                form_data: _,
-                form_idx: _,
-            } => "Pending",
-            VerificationStatus::Rejected => "Rejected",
-            VerificationStatus::Verified => "Verified",
-        },
-    };
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let response = timeline(&guild, member.user.id).await;
 
     ctx.send(|b| b.ephemeral(true).content(response)).await?;
 
     Ok(())
 }
 
-/// Get member verification status
+/// Get member verification status and history
 #[poise::command(
     context_menu_command = "Verification status",
     guild_only,
@@ -53,24 +96,496 @@ pub async fn status(ctx: Context<'_>, member: serenity::Member) -> Result<(), Er
     default_member_permissions = "ADMINISTRATOR"
 )]
 pub async fn verification_status_ctx(ctx: Context<'_>, user: serenity::User) -> Result<(), Error> {
-    let response = match ctx.data().verification.get_status(&user.id).await {
-        None => "No data",
-        Some(status) => match status {
-            VerificationStatus::Greeted { greeting_id: _ } => "Greeted",
-            VerificationStatus::Pending {
-                form_data: _,
-                form_idx: _,
-            } => "Pending",
-            VerificationStatus::Rejected => "Rejected",
-            VerificationStatus::Verified => "Verified",
-        },
-    };
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let response = timeline(&guild, user.id).await;
 
     ctx.send(|b| b.ephemeral(true).content(response)).await?;
 
     Ok(())
 }
 
+/// One page per completed attempt, newest first
+async fn show_transcripts(ctx: Context<'_>, user: &serenity::User) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let transcripts = guild.verification.get_transcripts(&user.id).await;
+
+    if transcripts.is_empty() {
+        ctx.send(|b| {
+            b.ephemeral(true)
+                .content(format!("{user} has no stored answers"))
+        })
+        .await?;
+
+        return Ok(());
+    }
+
+    let count = transcripts.len();
+    let pages: Vec<_> = transcripts
+        .iter()
+        .enumerate()
+        .rev()
+        .map(|(i, transcript)| {
+            let mut page = format!(
+                "{user} attempt {} of {count}, **{}** <t:{}:f>\n",
+                i + 1,
+                if transcript.passed {
+                    "passed"
+                } else {
+                    "failed"
+                },
+                transcript.timestamp.unix_timestamp()
+            );
+
+            for answer in &transcript.answers {
+                let line = if answer.is_correct() {
+                    format!(
+                        "\n✅ `{}` {}\n> {}\n",
+                        answer.question_id, answer.prompt, answer.answer
+                    )
+                } else {
+                    format!(
+                        "\n❌ `{}` {}\n> {} (expected {})\n",
+                        answer.question_id, answer.prompt, answer.answer, answer.correct_answer
+                    )
+                };
+
+                if page.len() + line.len() > MESSAGE_LIMIT {
+                    break;
+                }
+                page.push_str(&line);
+            }
+
+            page
+        })
+        .collect();
+
+    poise::samples::paginate(ctx, &pages.iter().map(|s| &s[..]).collect::<Vec<_>>()).await?;
+
+    Ok(())
+}
+
+/// Show member's answers to the verification questions
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn transcript(ctx: Context<'_>, member: serenity::Member) -> Result<(), Error> {
+    show_transcripts(ctx, &member.user).await
+}
+
+/// Show member's answers to the verification questions
+#[poise::command(
+    context_menu_command = "Verification transcript",
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn verification_transcript_ctx(
+    ctx: Context<'_>,
+    user: serenity::User,
+) -> Result<(), Error> {
+    show_transcripts(ctx, &user).await
+}
+
+fn percent(rate: Option<f64>) -> String {
+    match rate {
+        Some(rate) => format!("{:.1}%", rate * 100.0),
+        None => "n/a".into(),
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+
+    match secs {
+        s if s >= 60 * 60 * 24 => format!(
+            "{}d {}h",
+            s / (60 * 60 * 24),
+            s % (60 * 60 * 24) / (60 * 60)
+        ),
+        s if s >= 60 * 60 => format!("{}h {}m", s / (60 * 60), s % (60 * 60) / 60),
+        s if s >= 60 => format!("{}m {}s", s / 60, s % 60),
+        s => format!("{s}s"),
+    }
+}
+
+/// Show verification statistics
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn stats(
+    ctx: Context<'_>,
+    #[description = "Only count activity this recent, like 7d"] window: Option<Age>,
+) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let stats = guild
+        .verification
+        .stats(window.map(|age| age.ago()))
+        .await?;
+
+    let window_name = match window {
+        Some(window) => format!("Last {window}"),
+        None => "All time".into(),
+    };
+
+    let mut response = vec![
+        "**Current state**".to_string(),
+        format!("Greeted: {}", stats.greeted),
+        format!("Pending: {}", stats.pending),
+        format!("Verified: {}", stats.verified),
+        format!("Rejected: {}", stats.rejected),
+        String::new(),
+        format!("**{window_name}**"),
+        format!("Greeted: {}", stats.window_greeted),
+        format!("Started: {}", stats.window_started),
+        format!(
+            "Completed: {} by {} greeted members ({} of greeted)",
+            stats.window_completed(),
+            stats.window_greeted_completed,
+            percent(stats.completion_rate())
+        ),
+        format!(
+            "Passed: {} ({} of completed)",
+            stats.window_passed,
+            percent(stats.pass_rate())
+        ),
+        format!(
+            "Median time to complete: {}",
+            stats
+                .median_completion
+                .map_or_else(|| "n/a".into(), format_duration)
+        ),
+    ];
+
+    if !stats.questions.is_empty() {
+        let mut questions: Vec<_> = stats.questions.iter().collect();
+        questions.sort_by(|(_, a), (_, b)| {
+            b.failure_rate()
+                .partial_cmp(&a.failure_rate())
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        response.push(String::new());
+        response.push("**Failure rate per question**".into());
+        response.extend(questions.into_iter().map(|(id, question)| {
+            format!(
+                "`{id}`: {} of {} answers",
+                percent(question.failure_rate()),
+                question.answered
+            )
+        }));
+    }
+
+    ctx.send(|b| b.ephemeral(true).content(response.join("\n")))
+        .await?;
+
+    Ok(())
+}
+
+#[derive(poise::ChoiceParameter, Clone, Copy)]
+pub enum State {
+    Greeted,
+    Pending,
+    Verified,
+    Rejected,
+}
+
+impl State {
+    fn matches(self, status: &VerificationStatus) -> bool {
+        matches!(
+            (self, status),
+            (State::Greeted, VerificationStatus::Greeted { .. })
+                | (State::Pending, VerificationStatus::Pending { .. })
+                | (State::Verified, VerificationStatus::Verified)
+                | (State::Rejected, VerificationStatus::Rejected)
+        )
+    }
+}
+
+/// Everyone currently in the guild, fetched a page at a time
+async fn guild_members(
+    ctx: Context<'_>,
+    guild_id: serenity::GuildId,
+) -> Result<HashSet<serenity::UserId>, Error> {
+    let mut members = HashSet::new();
+    let mut after = None;
+
+    loop {
+        let page = guild_id.members(ctx, Some(1000), after).await?;
+
+        let Some(last) = page.last() else {
+            break;
+        };
+        after = Some(last.user.id);
+
+        let full = page.len() == 1000;
+        members.extend(page.into_iter().map(|m| m.user.id));
+
+        if !full {
+            break;
+        }
+    }
+
+    Ok(members)
+}
+
+/// List members by verification state
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn list(
+    ctx: Context<'_>,
+    #[description = "Only members in this state"] state: Option<State>,
+    #[description = "Only members in their state for longer than this, like 7d"] older_than: Option<
+        Age,
+    >,
+    #[description = "Only members in their state for less than this, like 7d"] newer_than: Option<
+        Age,
+    >,
+    #[description = "Only members with or without a ckey mapping"] has_ckey: Option<bool>,
+    #[description = "Only members who have or haven't left the server"] left: Option<bool>,
+) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+
+    // Listing everyone in a big server takes a while
+    ctx.defer_ephemeral().await?;
+
+    let present = match left {
+        Some(_) => Some(guild_members(ctx, guild.id).await?),
+        None => None,
+    };
+    let older_than = older_than.map(|age| age.ago());
+    let newer_than = newer_than.map(|age| age.ago());
+
+    let mut members = Vec::new();
+
+    for member in guild.verification.list().await? {
+        if state.is_some_and(|state| !state.matches(&member.status)) {
+            continue;
+        }
+        // Members without a recorded history have no known age and never match
+        if older_than.is_some_and(|t| member.since.is_none_or(|since| since > t)) {
+            continue;
+        }
+        if newer_than.is_some_and(|t| member.since.is_none_or(|since| since < t)) {
+            continue;
+        }
+        if let (Some(left), Some(present)) = (left, &present) {
+            if present.contains(&member.user_id) == left {
+                continue;
+            }
+        }
+
+        let ckey = guild.members.get_ckey(&member.user_id).await;
+        if has_ckey.is_some_and(|has_ckey| has_ckey != ckey.is_some()) {
+            continue;
+        }
+
+        members.push((member, ckey));
+    }
+
+    if members.is_empty() {
+        ctx.send(|b| b.ephemeral(true).content("No matching members"))
+            .await?;
+
+        return Ok(());
+    }
+
+    // Longest in their state first, that's who is stuck
+    members.sort_by_key(|(member, _)| member.since);
+
+    let pages: Vec<_> = members
+        .chunks(20)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .map(|(member, ckey)| {
+                    let mut line = format!("<@{}> **{}**", member.user_id, member.status.name());
+                    if let Some(since) = member.since {
+                        line.push_str(&format!(" since <t:{}:R>", since.unix_timestamp()));
+                    }
+                    if let Some(ckey) = ckey {
+                        line.push_str(&format!(", `{ckey}`"));
+                    }
+                    line
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+        .collect();
+
+    poise::samples::paginate(ctx, &pages.iter().map(|s| &s[..]).collect::<Vec<_>>()).await?;
+
+    Ok(())
+}
+
+/// Verify member without the questions
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn approve(
+    ctx: Context<'_>,
+    #[description = "Member to verify"] member: serenity::Member,
+    #[description = "Member's ckey"] ckey: String,
+) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let ckey = Ckey::from(&ckey);
+
+    guild
+        .verification
+        .approve(
+            ctx.serenity_context(),
+            &member,
+            ckey.clone(),
+            Actor::User(ctx.author().id),
+        )
+        .await?;
+
+    ctx.send(|b| {
+        b.ephemeral(true)
+            .content(format!("{member} verified as `{ckey}`"))
+    })
+    .await?;
+
+    Ok(())
+}
+
+#[derive(Modal)]
+#[name = "Approve member"]
+struct ApproveModal {
+    #[name = "Ckey"]
+    ckey: String,
+}
+
+/// Verify member without the questions
+#[poise::command(
+    context_menu_command = "Approve",
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn verification_approve_ctx(
+    ctx: ApplicationContext<'_>,
+    user: serenity::User,
+) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+
+    let defaults = guild
+        .members
+        .get_ckey(&user.id)
+        .await
+        .map(|ckey| ApproveModal {
+            ckey: ckey.to_string(),
+        });
+
+    let Some(ApproveModal { ckey }) = poise::execute_modal(ctx, defaults, None).await? else {
+        return Ok(());
+    };
+    let ckey = Ckey::from(&ckey);
+
+    let member = guild.id.member(ctx.serenity_context, user.id).await?;
+
+    guild
+        .verification
+        .approve(
+            ctx.serenity_context,
+            &member,
+            ckey.clone(),
+            Actor::User(ctx.author().id),
+        )
+        .await?;
+
+    ctx.send(|b| {
+        b.ephemeral(true)
+            .content(format!("{user} verified as `{ckey}`"))
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// Reject member
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn reject(
+    ctx: Context<'_>,
+    #[description = "Member to reject"] member: serenity::Member,
+    #[description = "Reason, shown in the log and history"] reason: Option<String>,
+) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+
+    guild
+        .verification
+        .reject_member(
+            ctx.serenity_context(),
+            &member.user,
+            reason,
+            Actor::User(ctx.author().id),
+        )
+        .await?;
+
+    ctx.send(|b| b.ephemeral(true).content(format!("{member} rejected")))
+        .await?;
+
+    Ok(())
+}
+
+#[derive(Modal)]
+#[name = "Reject member"]
+struct RejectModal {
+    #[name = "Reason"]
+    #[paragraph]
+    reason: Option<String>,
+}
+
+/// Reject member
+#[poise::command(
+    context_menu_command = "Reject",
+    guild_only,
+    required_permissions = "ADMINISTRATOR",
+    default_member_permissions = "ADMINISTRATOR"
+)]
+pub async fn verification_reject_ctx(
+    ctx: ApplicationContext<'_>,
+    user: serenity::User,
+) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+
+    let Some(RejectModal { reason }) = RejectModal::execute(ctx).await? else {
+        return Ok(());
+    };
+
+    guild
+        .verification
+        .reject_member(
+            ctx.serenity_context,
+            &user,
+            reason,
+            Actor::User(ctx.author().id),
+        )
+        .await?;
+
+    ctx.send(|b| b.ephemeral(true).content(format!("{user} rejected")))
+        .await?;
+
+    Ok(())
+}
+
 /// Clear member verification
 #[poise::command(
     slash_command,
@@ -79,7 +594,11 @@ pub async fn verification_status_ctx(ctx: Context<'_>, user: serenity::User) ->
     default_member_permissions = "ADMINISTRATOR"
 )]
 pub async fn clear(ctx: Context<'_>, member: serenity::Member) -> Result<(), Error> {
-    let response = ctx.data().verification.remove(&member.user.id).await?;
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let response = guild
+        .verification
+        .remove(&member.user.id, Actor::User(ctx.author().id))
+        .await?;
 
     if response {
         ctx.send(|b| {
@@ -106,7 +625,11 @@ pub async fn clear(ctx: Context<'_>, member: serenity::Member) -> Result<(), Err
     default_member_permissions = "ADMINISTRATOR"
 )]
 pub async fn verification_clear_ctx(ctx: Context<'_>, user: serenity::User) -> Result<(), Error> {
-    let response = ctx.data().verification.remove(&user.id).await?;
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let response = guild
+        .verification
+        .remove(&user.id, Actor::User(ctx.author().id))
+        .await?;
 
     if response {
         ctx.send(|b| {
@@ -133,10 +656,14 @@ pub async fn verification_clear_ctx(ctx: Context<'_>, user: serenity::User) -> R
     default_member_permissions = "ADMINISTRATOR"
 )]
 pub async fn greet(ctx: Context<'_>, member: serenity::Member) -> Result<(), Error> {
-    let response = ctx
-        .data()
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let response = guild
         .verification
-        .send_greeting(ctx.serenity_context(), &member.user)
+        .send_greeting(
+            ctx.serenity_context(),
+            &member.user,
+            Actor::User(ctx.author().id),
+        )
         .await;
 
     let response = match response {
@@ -165,10 +692,10 @@ pub async fn greet(ctx: Context<'_>, member: serenity::Member) -> Result<(), Err
     default_member_permissions = "ADMINISTRATOR"
 )]
 pub async fn verification_greet_ctx(ctx: Context<'_>, user: serenity::User) -> Result<(), Error> {
-    let response = ctx
-        .data()
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let response = guild
         .verification
-        .send_greeting(ctx.serenity_context(), &user)
+        .send_greeting(ctx.serenity_context(), &user, Actor::User(ctx.author().id))
         .await;
 
     let response = match response {
@@ -1,4 +1,8 @@
-use crate::app::{models::Ckey, Context, Error};
+use crate::app::{
+    models::{Age, Ckey},
+    services::{Actor, EntrySource, WhitelistEntry},
+    Context, Error,
+};
 
 /// Modify and inspect whitelist
 #[poise::command(
@@ -14,7 +18,7 @@ pub async fn whitelist(ctx: Context<'_>) -> Result<(), Error> {
     Ok(())
 }
 
-/// Reload the whitelist from disk
+/// Take in changes made to the whitelist file right away
 #[poise::command(
     slash_command,
     guild_only,
@@ -22,14 +26,44 @@ pub async fn whitelist(ctx: Context<'_>) -> Result<(), Error> {
     default_member_permissions = "ADMINISTRATOR"
 )]
 pub async fn reload(ctx: Context<'_>) -> Result<(), Error> {
-    ctx.data().whitelist.load().await?;
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    // Pending changes are written along the way so the file and bot agree
+    let result = guild.whitelist.reload().await?;
 
-    ctx.send(|b| b.ephemeral(true).content("Whitelist reloaded from disk"))
-        .await?;
+    ctx.send(|b| {
+        b.ephemeral(true).content(if result {
+            "Whitelist reloaded from disk"
+        } else {
+            "Whitelist file hasn't changed"
+        })
+    })
+    .await?;
 
     Ok(())
 }
 
+/// One line about an entry, with who added it, when, why and until when
+fn describe(ckey: &str, entry: Option<&WhitelistEntry>) -> String {
+    let Some(entry) = entry else {
+        return format!("`{ckey}`");
+    };
+
+    let mut line = format!(
+        "`{ckey}` {}, by {} <t:{}:d>",
+        entry.source.name(),
+        entry.added_by,
+        entry.added_at.unix_timestamp()
+    );
+    if let Some(ref reason) = entry.reason {
+        line.push_str(&format!(": {reason}"));
+    }
+    if let Some(expires_at) = entry.expires_at {
+        line.push_str(&format!(" (expires <t:{}:R>)", expires_at.unix_timestamp()));
+    }
+
+    line
+}
+
 /// Show the entire whitelist
 #[poise::command(
     slash_command,
@@ -38,16 +72,16 @@ pub async fn reload(ctx: Context<'_>) -> Result<(), Error> {
     default_member_permissions = "ADMINISTRATOR"
 )]
 pub async fn list(ctx: Context<'_>) -> Result<(), Error> {
-    let list: Vec<_> = ctx
-        .data()
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
+    let list: Vec<_> = guild
         .whitelist
-        .list()
+        .list_entries()
         .await
-        .chunks(20)
+        .chunks(10)
         .map(|chunk| {
             chunk
                 .iter()
-                .map(|ckey| format!("`{ckey}`"))
+                .map(|(ckey, entry)| describe(ckey, entry.as_ref()))
                 .collect::<Vec<_>>()
                 .join("\n")
         })
@@ -75,15 +109,26 @@ pub async fn list(ctx: Context<'_>) -> Result<(), Error> {
 pub async fn add(
     ctx: Context<'_>,
     #[description = "Ckey to add to the whitelist"] ckey: String,
+    #[description = "Why the ckey is whitelisted"] reason: Option<String>,
+    #[description = "Remove it again after this long, like 7d, never if not given"]
+    duration: Option<Age>,
 ) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
     let ckey = Ckey::from(&ckey);
-    let result = ctx.data().whitelist.insert(&ckey).await?;
+
+    let mut entry = WhitelistEntry::new(Actor::User(ctx.author().id), EntrySource::Manual);
+    entry.reason = reason;
+    entry.expires_at = duration.map(|duration| duration.later());
+
+    let result = guild.whitelist.add(&ckey, entry).await?;
 
     ctx.send(|b| {
-        b.ephemeral(true).content(if result {
-            format!("`{ckey}` added to the whitelist")
-        } else {
-            format!("`{ckey}` is already in the whitelist")
+        b.ephemeral(true).content(match (result, duration) {
+            (true, Some(duration)) => {
+                format!("`{ckey}` added to the whitelist for {duration}")
+            }
+            (true, None) => format!("`{ckey}` added to the whitelist"),
+            (false, _) => format!("`{ckey}` is already in the whitelist"),
         })
     })
     .await?;
@@ -102,8 +147,28 @@ pub async fn remove(
     ctx: Context<'_>,
     #[description = "Ckey to remove from the whitelist"] ckey: String,
 ) -> Result<(), Error> {
+    let guild = ctx.data().guild(ctx.guild_id()).await?;
     let ckey = Ckey::from(&ckey);
-    let result = ctx.data().whitelist.remove(&ckey).await?;
+
+    let claimants = guild.whitelist.claimants(&ckey).await;
+    if !claimants.is_empty() {
+        let users: Vec<_> = claimants.iter().map(|id| format!("<@{id}>")).collect();
+        ctx.send(|b| {
+            b.ephemeral(true).content(format!(
+                "`{ckey}` belongs to {}, flag them `Never whitelisted` with `/members flag` \
+                or take it away with `/ckey remove` instead",
+                users.join(", ")
+            ))
+        })
+        .await?;
+
+        return Ok(());
+    }
+
+    let result = guild
+        .whitelist
+        .remove(&ckey, Actor::User(ctx.author().id))
+        .await?;
 
     ctx.send(|b| {
         b.ephemeral(true).content(if result {
@@ -12,25 +12,40 @@ pub fn event_handler<'a>(
     Box::pin(async move {
         match *event {
             poise::Event::GuildMemberAddition { ref new_member } => {
-                ctx.user_data
-                    .verification
-                    .on_join(sc, ctx, new_member)
-                    .await
+                let Some(guild) = ctx.user_data.guilds.get(new_member.guild_id).await else {
+                    return Ok(());
+                };
+
+                guild.verification.on_join(sc, new_member).await
             }
             poise::Event::GuildBanAddition {
                 guild_id,
                 ref banned_user,
             } => {
-                ctx.user_data
-                    .verification
-                    .on_ban(sc, ctx, guild_id, banned_user)
-                    .await
+                let Some(guild) = ctx.user_data.guilds.get(guild_id).await else {
+                    return Ok(());
+                };
+
+                guild.verification.on_ban(sc, banned_user).await
             }
             poise::Event::InteractionCreate { ref interaction } => {
-                ctx.user_data
-                    .verification
-                    .on_interaction(sc, ctx, interaction)
-                    .await
+                let guild_id = match *interaction {
+                    serenity::Interaction::MessageComponent(ref interaction) => {
+                        interaction.guild_id
+                    }
+                    serenity::Interaction::ModalSubmit(ref interaction) => interaction.guild_id,
+                    _ => None,
+                };
+
+                let Some(guild_id) = guild_id else {
+                    return Ok(());
+                };
+
+                let Some(guild) = ctx.user_data.guilds.get(guild_id).await else {
+                    return Ok(());
+                };
+
+                guild.verification.on_interaction(sc, interaction).await
             }
             _ => Ok(()),
         }
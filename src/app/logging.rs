@@ -0,0 +1,150 @@
+use poise::serenity_prelude as serenity;
+
+use crate::{
+    app::models::Ckey,
+    config::{LogEvent, Question},
+};
+
+// Embed limits imposed by Discord
+const FIELD_VALUE_LIMIT: usize = 1024;
+
+/// Structured message for the log channel
+pub struct LogEntry {
+    event: LogEvent,
+    embed: serenity::CreateEmbed,
+}
+
+impl LogEntry {
+    pub fn new(event: LogEvent) -> Self {
+        let (title, colour) = match event {
+            LogEvent::Started => ("Verification started", serenity::Colour::BLURPLE),
+            LogEvent::Passed => ("Answers accepted", serenity::Colour::BLURPLE),
+            LogEvent::Failed => ("Verification failed", serenity::Colour::RED),
+            LogEvent::Verified => ("Verified", serenity::Colour::DARK_GREEN),
+            LogEvent::CkeyConflict => (
+                "Ckey already belongs to another user",
+                serenity::Colour::ORANGE,
+            ),
+            LogEvent::Rejoined => (
+                "Rejoined with existing verification, role granted",
+                serenity::Colour::DARK_GREEN,
+            ),
+            LogEvent::RejoinedUnverified => (
+                "Rejoined without finished verification, greeted again",
+                serenity::Colour::ORANGE,
+            ),
+            LogEvent::RejoinedRejected => {
+                ("Rejoined with rejected verification", serenity::Colour::RED)
+            }
+            LogEvent::Banned => ("Banned", serenity::Colour::DARK_RED),
+            LogEvent::Approved => ("Approved by staff", serenity::Colour::DARK_GREEN),
+            LogEvent::Rejected => ("Rejected by staff", serenity::Colour::RED),
+            LogEvent::Aborted => (
+                "Verification couldn't be completed, changes undone",
+                serenity::Colour::RED,
+            ),
+        };
+
+        let mut embed = serenity::CreateEmbed::default();
+        embed
+            .title(title)
+            .colour(colour)
+            .timestamp(serenity::Timestamp::now());
+
+        Self { event, embed }
+    }
+
+    pub fn event(&self) -> LogEvent {
+        self.event
+    }
+
+    pub fn user(mut self, user: &serenity::User) -> Self {
+        self.embed
+            .thumbnail(user.face())
+            .field("User", format!("{user} `{}`", user.tag()), true)
+            .field("ID", format!("`{}`", user.id), true)
+            .field(
+                "Account created",
+                format!("<t:{}:R>", user.created_at().unix_timestamp()),
+                true,
+            );
+
+        self
+    }
+
+    pub fn ckey(mut self, ckey: Option<&Ckey>) -> Self {
+        self.embed.field(
+            "Ckey",
+            match ckey {
+                Some(ckey) => format!("`{ckey}`"),
+                None => "None".into(),
+            },
+            true,
+        );
+
+        self
+    }
+
+    pub fn answers(mut self, questions: &[Question], answers: &[String]) -> Self {
+        let lines: Vec<_> = answers
+            .iter()
+            .enumerate()
+            .map(|(i, answer)| match questions.get(i) {
+                Some(q) if q.answers.get(q.correct_answer) == Some(answer) => {
+                    format!("✅ `{}`: {answer}", q.id)
+                }
+                Some(q) => format!("❌ `{}`: {answer}", q.id),
+                None => format!("❔ {answer}"),
+            })
+            .collect();
+
+        self.embed.field(
+            "Answers",
+            if lines.is_empty() {
+                "None".into()
+            } else {
+                truncate(&lines.join("\n"), FIELD_VALUE_LIMIT)
+            },
+            false,
+        );
+
+        self
+    }
+
+    pub fn field(mut self, name: &str, value: &str, inline: bool) -> Self {
+        self.embed
+            .field(name, truncate(value, FIELD_VALUE_LIMIT), inline);
+
+        self
+    }
+
+    pub async fn send(
+        self,
+        sc: &serenity::Context,
+        channel_id: serenity::ChannelId,
+        mention_role_id: Option<serenity::RoleId>,
+    ) -> Result<serenity::Message, serenity::Error> {
+        channel_id
+            .send_message(sc, |b| {
+                if let Some(role_id) = mention_role_id {
+                    b.content(format!("<@&{role_id}>"))
+                        .allowed_mentions(|b| b.roles([role_id]));
+                }
+
+                b.set_embed(self.embed)
+            })
+            .await
+    }
+}
+
+fn truncate(value: &str, limit: usize) -> String {
+    if value.chars().count() <= limit {
+        return value.into();
+    }
+
+    value
+        .chars()
+        .take(limit - 1)
+        .chain(std::iter::once('…'))
+        .collect()
+}
@@ -1,4 +1,6 @@
-use std::fmt;
+use std::{fmt, str::FromStr, time::Duration};
+
+use poise::serenity_prelude::Timestamp;
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Ckey(String);
@@ -33,3 +35,128 @@ impl fmt::Display for Ckey {
         fmt::Display::fmt(&self.0, f)
     }
 }
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidAge;
+
+impl fmt::Display for InvalidAge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "expected a duration like `30m`, `12h`, `7d` or `2w`, up to {}w",
+            MAX_AGE_SECS / WEEK_SECS
+        )
+    }
+}
+
+impl std::error::Error for InvalidAge {}
+
+const WEEK_SECS: u64 = 60 * 60 * 24 * 7;
+/// Longest age accepted, about a century. Long enough for anything meant, and
+/// short enough that moving the current time by it stays a valid timestamp
+const MAX_AGE_SECS: u64 = 5200 * WEEK_SECS;
+
+/// Span of time given as a number and a unit, like `30m`, `12h`, `7d` or `2w`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Age(Duration);
+
+impl Age {
+    /// Point in time this long ago
+    pub fn ago(&self) -> Timestamp {
+        let secs = Timestamp::now().unix_timestamp() - self.0.as_secs() as i64;
+
+        Timestamp::from_unix_timestamp(secs).expect("Age is within timestamp range")
+    }
+
+    /// Point in time this long from now
+    pub fn later(&self) -> Timestamp {
+        let secs = Timestamp::now().unix_timestamp() + self.0.as_secs() as i64;
+
+        Timestamp::from_unix_timestamp(secs).expect("Age is within timestamp range")
+    }
+}
+
+impl FromStr for Age {
+    type Err = InvalidAge;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        let split = s.find(|c: char| !c.is_ascii_digit()).ok_or(InvalidAge)?;
+        let (amount, unit) = s.split_at(split);
+        let amount: u64 = amount.parse().map_err(|_| InvalidAge)?;
+
+        let unit_secs = match unit.trim() {
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 60 * 60 * 24,
+            "w" => WEEK_SECS,
+            _ => return Err(InvalidAge),
+        };
+
+        amount
+            .checked_mul(unit_secs)
+            .filter(|secs| (1..=MAX_AGE_SECS).contains(secs))
+            .map(|secs| Age(Duration::from_secs(secs)))
+            .ok_or(InvalidAge)
+    }
+}
+
+impl fmt::Display for Age {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.0.as_secs();
+
+        match secs {
+            s if s % (60 * 60 * 24 * 7) == 0 && s > 0 => write!(f, "{}w", s / (60 * 60 * 24 * 7)),
+            s if s % (60 * 60 * 24) == 0 && s > 0 => write!(f, "{}d", s / (60 * 60 * 24)),
+            s if s % (60 * 60) == 0 && s > 0 => write!(f, "{}h", s / (60 * 60)),
+            s => write!(f, "{}m", s / 60),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ages() {
+        assert_eq!("30m".parse(), Ok(Age(Duration::from_secs(30 * 60))));
+        assert_eq!(" 2 w ".parse(), Ok(Age(Duration::from_secs(2 * WEEK_SECS))));
+        assert_eq!("5200w".parse(), Ok(Age(Duration::from_secs(MAX_AGE_SECS))));
+    }
+
+    #[test]
+    fn rejects_invalid_ages() {
+        for s in [
+            "",
+            "d",
+            "7",
+            "7s",
+            "-7d",
+            "0m",
+            "0w",
+            "5201w",
+            "99999999999999999w",
+        ] {
+            assert!(s.parse::<Age>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn formats_ages_in_largest_unit() {
+        for s in ["90m", "12h", "3d", "2w"] {
+            assert_eq!(s.parse::<Age>().unwrap().to_string(), s);
+        }
+        assert_eq!("14d".parse::<Age>().unwrap().to_string(), "2w");
+    }
+
+    #[test]
+    fn longest_age_stays_in_range() {
+        let age: Age = "5200w".parse().unwrap();
+        let now = Timestamp::now().unix_timestamp();
+
+        assert!(age.later().unix_timestamp() > now);
+        assert!(age.ago().unix_timestamp() < now);
+    }
+}
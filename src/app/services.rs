@@ -1,21 +1,35 @@
-mod ckey;
+mod audit;
 mod config;
+mod guild;
+mod member;
+mod storage;
+mod transfer;
 mod verification;
 mod whitelist;
+mod write_behind;
 
 use std::fmt;
 
-pub use ckey::{CkeyService, Error as CkeyError};
-pub use config::{ConfigService, Error as ConfigError};
+pub use audit::{Actor, AuditFilter, AuditService};
+pub use config::ConfigService;
+pub use guild::{Guild, GuildService};
+pub use member::{MappedCkey, Member, MemberFlag, MemberService};
+pub use storage::{archive_ron_files, migrate_to_sqlite};
+pub use transfer::{Format, ImportMode, ImportPlan, MemberRecord};
 pub use verification::{
     Error as VerificationError, SendGreetingError, VerificationService, VerificationStatus,
 };
-pub use whitelist::{Error as WhitelistError, WhitelistService};
+pub use whitelist::{EntrySource, WhitelistEntry, WhitelistService};
+pub use write_behind::WriteBehind;
 
 #[derive(Debug)]
 pub enum Error {
-    Ckey(ckey::Error),
+    Audit(audit::Error),
     Config(config::Error),
+    Guild(guild::Error),
+    Member(member::Error),
+    Storage(storage::Error),
+    Transfer(transfer::Error),
     Verification(verification::Error),
     Whitelist(whitelist::Error),
 }
@@ -23,10 +37,20 @@ pub enum Error {
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match *self {
-            Error::Ckey(ref e) => write!(f, "ckey: {e}"),
+            Error::Audit(ref e) => write!(f, "audit: {e}"),
             Error::Config(ref e) => write!(f, "config: {e}"),
+            Error::Guild(ref e) => write!(f, "guild: {e}"),
+            Error::Member(ref e) => write!(f, "member: {e}"),
+            Error::Storage(ref e) => write!(f, "storage: {e}"),
+            Error::Transfer(ref e) => write!(f, "transfer: {e}"),
             Error::Verification(ref e) => write!(f, "verification: {e}"),
             Error::Whitelist(ref e) => write!(f, "whitelist: {e}"),
         }
     }
 }
+
+impl From<storage::Error> for Error {
+    fn from(value: storage::Error) -> Self {
+        Self::Storage(value)
+    }
+}
@@ -0,0 +1,311 @@
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use poise::serenity_prelude as serenity;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+#[derive(Debug)]
+pub enum Error {
+    Read(io::Error),
+    Write(ron::Error),
+}
+
+impl From<Error> for super::Error {
+    fn from(value: Error) -> Self {
+        Self::Audit(value)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Error::Read(ref e) => write!(f, "read: {e}"),
+            Error::Write(ref e) => write!(f, "write: {e}"),
+        }
+    }
+}
+
+/// Who caused a change
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Actor {
+    /// The bot reacting to a Discord event nobody can be attributed with
+    System,
+    User(serenity::UserId),
+    /// Someone on the bot host using the command line
+    Console,
+    /// Someone or something editing the whitelist file directly
+    File,
+}
+
+impl fmt::Display for Actor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Actor::System => write!(f, "system"),
+            Actor::User(id) => write!(f, "<@{id}>"),
+            Actor::Console => write!(f, "console"),
+            Actor::File => write!(f, "whitelist file"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum AuditAction {
+    CkeySet {
+        user_id: serenity::UserId,
+        ckey: String,
+    },
+    CkeyUnset {
+        user_id: serenity::UserId,
+        ckey: String,
+    },
+    CkeyPrimary {
+        user_id: serenity::UserId,
+        ckey: String,
+    },
+    WhitelistAdd {
+        ckey: String,
+    },
+    WhitelistRemove {
+        ckey: String,
+    },
+    StatusSet {
+        user_id: serenity::UserId,
+        status: String,
+    },
+    StatusCleared {
+        user_id: serenity::UserId,
+    },
+    MemberUpdated {
+        user_id: serenity::UserId,
+        change: String,
+    },
+    ConfigChanged {
+        change: String,
+    },
+}
+
+impl AuditAction {
+    pub fn user_id(&self) -> Option<serenity::UserId> {
+        match *self {
+            AuditAction::CkeySet { user_id, .. }
+            | AuditAction::CkeyUnset { user_id, .. }
+            | AuditAction::CkeyPrimary { user_id, .. }
+            | AuditAction::StatusSet { user_id, .. }
+            | AuditAction::StatusCleared { user_id }
+            | AuditAction::MemberUpdated { user_id, .. } => Some(user_id),
+            _ => None,
+        }
+    }
+
+    pub fn ckey(&self) -> Option<&str> {
+        match *self {
+            AuditAction::CkeySet { ref ckey, .. }
+            | AuditAction::CkeyUnset { ref ckey, .. }
+            | AuditAction::CkeyPrimary { ref ckey, .. }
+            | AuditAction::WhitelistAdd { ref ckey }
+            | AuditAction::WhitelistRemove { ref ckey } => Some(ckey),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AuditAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            AuditAction::CkeySet { user_id, ref ckey } => {
+                write!(f, "mapped <@{user_id}> to `{ckey}`")
+            }
+            AuditAction::CkeyUnset { user_id, ref ckey } => {
+                write!(f, "unmapped <@{user_id}> from `{ckey}`")
+            }
+            AuditAction::CkeyPrimary { user_id, ref ckey } => {
+                write!(f, "made `{ckey}` the primary ckey of <@{user_id}>")
+            }
+            AuditAction::WhitelistAdd { ref ckey } => write!(f, "whitelisted `{ckey}`"),
+            AuditAction::WhitelistRemove { ref ckey } => write!(f, "unwhitelisted `{ckey}`"),
+            AuditAction::StatusSet {
+                user_id,
+                ref status,
+            } => write!(f, "set <@{user_id}> verification to {status}"),
+            AuditAction::StatusCleared { user_id } => {
+                write!(f, "cleared <@{user_id}> verification")
+            }
+            AuditAction::MemberUpdated {
+                user_id,
+                ref change,
+            } => write!(f, "updated <@{user_id}>: {change}"),
+            AuditAction::ConfigChanged { ref change } => write!(f, "changed config: {change}"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditEntry {
+    pub timestamp: serenity::Timestamp,
+    pub guild_id: Option<serenity::GuildId>,
+    /// Whitelist file changed, for changes that aren't tied to a guild since
+    /// the file may be shared between several
+    #[serde(default)]
+    pub whitelist: Option<PathBuf>,
+    pub actor: Actor,
+    pub action: AuditAction,
+}
+
+impl fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "<t:{}:f> {} {}",
+            self.timestamp.unix_timestamp(),
+            self.actor,
+            self.action
+        )
+    }
+}
+
+#[derive(Default)]
+pub struct AuditFilter {
+    pub guild_id: Option<serenity::GuildId>,
+    /// Changes to this whitelist file are shown along with the guild's
+    pub whitelist: Option<PathBuf>,
+    /// Whether changes tied to neither a guild nor a whitelist are shown
+    pub global: bool,
+    pub user_id: Option<serenity::UserId>,
+    pub ckey: Option<String>,
+    pub actor: Option<Actor>,
+    pub after: Option<serenity::Timestamp>,
+    pub before: Option<serenity::Timestamp>,
+}
+
+impl AuditFilter {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        let scoped = match (entry.guild_id, &entry.whitelist) {
+            (Some(id), _) => self.guild_id.is_none_or(|g| g == id),
+            (None, Some(path)) => self.whitelist.as_ref().is_none_or(|p| p == path),
+            (None, None) => self.global,
+        };
+
+        scoped
+            && self
+                .user_id
+                .is_none_or(|id| entry.action.user_id() == Some(id))
+            && self
+                .ckey
+                .as_deref()
+                .is_none_or(|ckey| entry.action.ckey() == Some(ckey))
+            && self.actor.is_none_or(|actor| entry.actor == actor)
+            && self.after.is_none_or(|t| entry.timestamp >= t)
+            && self.before.is_none_or(|t| entry.timestamp <= t)
+    }
+}
+
+/// Append-only record of every state change
+pub struct AuditService {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl AuditService {
+    pub fn new(data_path: &Path) -> Self {
+        Self {
+            path: data_path.join("audit.ron"),
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub async fn record(
+        &self,
+        guild_id: Option<serenity::GuildId>,
+        actor: Actor,
+        action: AuditAction,
+    ) -> Result<(), Error> {
+        self.append(AuditEntry {
+            timestamp: serenity::Timestamp::now(),
+            guild_id,
+            whitelist: None,
+            actor,
+            action,
+        })
+        .await
+    }
+
+    /// Records a change to a whitelist file, shown to every guild using it
+    pub async fn record_whitelist(
+        &self,
+        path: &Path,
+        actor: Actor,
+        action: AuditAction,
+    ) -> Result<(), Error> {
+        self.append(AuditEntry {
+            timestamp: serenity::Timestamp::now(),
+            guild_id: None,
+            whitelist: Some(path.into()),
+            actor,
+            action,
+        })
+        .await
+    }
+
+    async fn append(&self, entry: AuditEntry) -> Result<(), Error> {
+        let line = ron::ser::to_string(&entry).map_err(Error::Write)?;
+        let path = self.path.clone();
+
+        let _guard = self.lock.lock().await;
+
+        tokio::task::spawn_blocking(move || {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| Error::Write(e.into()))?;
+
+            writeln!(file, "{line}").map_err(|e| Error::Write(e.into()))?;
+
+            file.sync_data().map_err(|e| Error::Write(e.into()))
+        })
+        .await
+        .expect("Thread panicked")
+    }
+
+    /// Matching entries, newest first
+    pub async fn query(&self, filter: AuditFilter) -> Result<Vec<AuditEntry>, Error> {
+        let path = self.path.clone();
+
+        let _guard = self.lock.lock().await;
+
+        tokio::task::spawn_blocking(move || {
+            let file = match File::open(path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(Error::Read(e)),
+            };
+            let reader = BufReader::new(file);
+
+            let mut entries = Vec::new();
+
+            for (i, line) in reader.lines().enumerate() {
+                let line = line.map_err(Error::Read)?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                match ron::de::from_str::<AuditEntry>(&line) {
+                    Ok(entry) if filter.matches(&entry) => entries.push(entry),
+                    Ok(_) => (),
+                    Err(e) => log::warn!("Skipping malformed audit entry on line {}: {e}", i + 1),
+                }
+            }
+
+            entries.reverse();
+
+            Ok(entries)
+        })
+        .await
+        .expect("Thread panicked")
+    }
+}
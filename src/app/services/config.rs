@@ -1,19 +1,26 @@
 use std::{
     fmt,
-    fs::File,
-    io::{self, BufReader},
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use ron::error::SpannedError;
+use poise::serenity_prelude as serenity;
 use tokio::sync::{RwLock, RwLockReadGuard};
 
-use crate::AppConfig;
+use crate::{
+    config::{self, GuildConfig, StorageBackend},
+    AppConfig,
+};
+
+use super::audit::{self, Actor, AuditAction, AuditService};
 
 #[derive(Debug)]
 pub enum Error {
-    Read(SpannedError),
+    Read(config::Error),
     Write(ron::Error),
+    Missing(PathBuf),
+    UnknownGuild(serenity::GuildId),
+    Audit(audit::Error),
 }
 
 impl From<Error> for super::Error {
@@ -27,41 +34,38 @@ impl fmt::Display for Error {
         match *self {
             Error::Read(ref e) => write!(f, "read: {}", e),
             Error::Write(ref e) => write!(f, "write: {}", e),
+            Error::Missing(ref path) => write!(f, "{} doesn't exist", path.display()),
+            Error::UnknownGuild(ref id) => write!(f, "server {} is not configured", id),
+            Error::Audit(ref e) => write!(f, "audit: {}", e),
         }
     }
 }
 
 pub struct ConfigService {
     config_path: PathBuf,
-    pub config: RwLock<AppConfig>,
+    audit: Arc<AuditService>,
+    config: RwLock<AppConfig>,
 }
 
 impl ConfigService {
-    pub fn new(config: AppConfig, config_path: &Path) -> Self {
+    pub fn new(config: AppConfig, config_path: &Path, audit: Arc<AuditService>) -> Self {
         Self {
             config_path: config_path.into(),
+            audit,
             config: RwLock::new(config),
         }
     }
 
+    /// Reads the config again, keeping the current one if the file is gone
     pub async fn load(&self) -> Result<(), Error> {
         let path = self.config_path.clone();
-        let config = tokio::task::spawn_blocking(move || {
-            let file = match File::open(path) {
-                Ok(file) => file,
-                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
-                Err(e) => return Err(Error::Read(e.into())),
-            };
-            let reader = BufReader::new(file);
-
-            Ok(Some(ron::de::from_reader(reader).map_err(Error::Read)?))
-        })
-        .await
-        .expect("Thread panicked")?;
-
-        if let Some(config) = config {
-            *self.config.write().await = config
-        }
+        let config = tokio::task::spawn_blocking(move || AppConfig::read(&path))
+            .await
+            .expect("Thread panicked")
+            .map_err(Error::Read)?
+            .ok_or_else(|| Error::Missing(self.config_path.clone()))?;
+
+        *self.config.write().await = config;
 
         Ok(())
     }
@@ -82,4 +86,60 @@ impl ConfigService {
     pub async fn get(&self) -> RwLockReadGuard<'_, AppConfig> {
         self.config.read().await
     }
+
+    pub async fn guild(&self, id: serenity::GuildId) -> Option<RwLockReadGuard<'_, GuildConfig>> {
+        RwLockReadGuard::try_map(self.config.read().await, |c| c.guilds.get(&id)).ok()
+    }
+
+    async fn record(
+        &self,
+        id: Option<serenity::GuildId>,
+        actor: Actor,
+        change: String,
+    ) -> Result<(), Error> {
+        self.audit
+            .record(id, actor, AuditAction::ConfigChanged { change })
+            .await
+            .map_err(Error::Audit)
+    }
+
+    /// Adds a new guild, replacing any existing config for it
+    pub async fn insert_guild(
+        &self,
+        id: serenity::GuildId,
+        config: GuildConfig,
+        actor: Actor,
+    ) -> Result<(), Error> {
+        self.config.write().await.guilds.insert(id, config);
+
+        self.store().await?;
+        self.record(Some(id), actor, "server configured".into())
+            .await
+    }
+
+    /// Applies `f` to a guild config and records `change` as what it did
+    pub async fn update_guild<F: FnOnce(&mut GuildConfig)>(
+        &self,
+        id: serenity::GuildId,
+        actor: Actor,
+        change: impl Into<String>,
+        f: F,
+    ) -> Result<(), Error> {
+        {
+            let mut guard = self.config.write().await;
+            let guild = guard.guilds.get_mut(&id).ok_or(Error::UnknownGuild(id))?;
+            f(guild);
+        }
+
+        self.store().await?;
+        self.record(Some(id), actor, change.into()).await
+    }
+
+    pub async fn set_storage(&self, backend: StorageBackend, actor: Actor) -> Result<(), Error> {
+        self.config.write().await.storage = backend;
+
+        self.store().await?;
+        self.record(None, actor, format!("storage set to {backend:?}"))
+            .await
+    }
 }
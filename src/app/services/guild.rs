@@ -0,0 +1,321 @@
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Weak},
+};
+
+use poise::serenity_prelude as serenity;
+use tokio::sync::{Mutex, RwLock};
+
+use crate::config::GuildConfig;
+
+use super::{
+    member, storage, verification, whitelist,
+    write_behind::{self, WriteBehind},
+};
+use super::{AuditService, ConfigService, MemberService, VerificationService, WhitelistService};
+
+#[derive(Debug)]
+pub enum Error {
+    DataDir(io::Error),
+    Dependency(&'static str),
+    LegacyData(io::Error),
+    LegacyDataOwner,
+    LegacyDataConflict(PathBuf),
+    Member(member::Error),
+    Storage(storage::Error),
+    Verification(verification::Error),
+    Whitelist(whitelist::Error),
+}
+
+impl From<Error> for super::Error {
+    fn from(value: Error) -> Self {
+        Self::Guild(value)
+    }
+}
+
+impl From<member::Error> for Error {
+    fn from(value: member::Error) -> Self {
+        Self::Member(value)
+    }
+}
+
+impl From<storage::Error> for Error {
+    fn from(value: storage::Error) -> Self {
+        Self::Storage(value)
+    }
+}
+
+impl From<verification::Error> for Error {
+    fn from(value: verification::Error) -> Self {
+        Self::Verification(value)
+    }
+}
+
+impl From<whitelist::Error> for Error {
+    fn from(value: whitelist::Error) -> Self {
+        Self::Whitelist(value)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Error::DataDir(ref e) => write!(f, "data directory: {e}"),
+            Error::Dependency(ref e) => write!(f, "dependency not loaded: {e}"),
+            Error::LegacyData(ref e) => write!(f, "moving single server data: {e}"),
+            Error::LegacyDataOwner => write!(
+                f,
+                "single server data found at the top of the data directory, \
+                but it's unclear which of the configured servers it belongs to"
+            ),
+            Error::LegacyDataConflict(ref path) => write!(
+                f,
+                "single server data found at the top of the data directory, \
+                but {} already exists",
+                path.display()
+            ),
+            Error::Member(ref e) => write!(f, "member: {e}"),
+            Error::Storage(ref e) => write!(f, "storage: {e}"),
+            Error::Verification(ref e) => write!(f, "verification: {e}"),
+            Error::Whitelist(ref e) => write!(f, "whitelist: {e}"),
+        }
+    }
+}
+
+/// Data files kept at the top of the data directory back when only a single
+/// server could be served
+const LEGACY_FILES: [&str; 2] = ["ckeys.ron", "verification.ron"];
+
+/// Moves data of a single server setup into the directory of that server,
+/// which has to be the only one configured
+fn adopt_legacy_files(data_path: &Path, guilds: &[serenity::GuildId]) -> Result<(), Error> {
+    let legacy: Vec<_> = LEGACY_FILES
+        .iter()
+        .map(|name| data_path.join(name))
+        .filter(|path| path.exists())
+        .collect();
+
+    if legacy.is_empty() {
+        return Ok(());
+    }
+
+    let [id] = guilds else {
+        return Err(Error::LegacyDataOwner);
+    };
+
+    let guild_path = data_path.join(id.to_string());
+    for path in &legacy {
+        let target = guild_path.join(path.file_name().unwrap_or_default());
+        if target.exists() {
+            return Err(Error::LegacyDataConflict(target));
+        }
+    }
+
+    match fs::create_dir(&guild_path) {
+        Ok(_) => (),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+        Err(e) => return Err(Error::DataDir(e)),
+    }
+
+    for path in legacy {
+        let target = guild_path.join(path.file_name().unwrap_or_default());
+        fs::rename(&path, &target).map_err(Error::LegacyData)?;
+        log::info!("Moved {} to {}", path.display(), target.display());
+    }
+
+    Ok(())
+}
+
+/// Services bound to a single configured guild
+pub struct Guild {
+    pub id: serenity::GuildId,
+    pub members: Arc<MemberService>,
+    pub verification: Arc<VerificationService>,
+    pub whitelist: Arc<WhitelistService>,
+}
+
+impl Guild {
+    /// Writes out every pending change of the guild
+    pub async fn flush(&self) -> Result<(), super::Error> {
+        self.members.flush().await?;
+        self.verification.flush().await?;
+        self.whitelist.flush().await?;
+
+        Ok(())
+    }
+}
+
+pub struct GuildService {
+    data_path: PathBuf,
+    audit: Arc<AuditService>,
+    config: Weak<ConfigService>,
+    guilds: RwLock<HashMap<serenity::GuildId, Arc<Guild>>>,
+    whitelists: Mutex<HashMap<PathBuf, Weak<WhitelistService>>>,
+}
+
+impl GuildService {
+    pub fn new(audit: Arc<AuditService>, config: Weak<ConfigService>, data_path: &Path) -> Self {
+        Self {
+            data_path: data_path.into(),
+            audit,
+            config,
+            guilds: RwLock::new(HashMap::new()),
+            whitelists: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn config(&self) -> Result<Arc<ConfigService>, Error> {
+        self.config.upgrade().ok_or(Error::Dependency("config"))
+    }
+
+    /// Initializes services for every configured guild that doesn't have them
+    /// yet and stops serving guilds that aren't configured anymore
+    pub async fn load(&self) -> Result<(), Error> {
+        let guilds: Vec<_> = {
+            let config = self.config()?;
+            let guard = config.get().await;
+            guard
+                .guilds
+                .iter()
+                .map(|(id, guild)| (*id, guild.clone()))
+                .collect()
+        };
+
+        let ids: Vec<_> = guilds.iter().map(|(id, _)| *id).collect();
+        adopt_legacy_files(&self.data_path, &ids)?;
+
+        for (id, guild) in guilds {
+            if let Some(existing) = self.get(id).await {
+                existing
+                    .whitelist
+                    .set_comments(id, guild.whitelist_comments)
+                    .await;
+                continue;
+            }
+
+            self.init(id, &guild).await?;
+        }
+
+        let removed: Vec<_> = {
+            let mut guard = self.guilds.write().await;
+            let gone: Vec<_> = guard
+                .keys()
+                .filter(|id| !ids.contains(id))
+                .copied()
+                .collect();
+            gone.iter().filter_map(|id| guard.remove(id)).collect()
+        };
+        for guild in removed {
+            if let Err(e) = guild.flush().await {
+                log::error!("Error while writing changes of guild {}: {e}", guild.id);
+            }
+            log::info!("No longer serving guild {}", guild.id);
+        }
+
+        Ok(())
+    }
+
+    /// Loads data for a guild and starts serving it
+    pub async fn init(
+        &self,
+        id: serenity::GuildId,
+        config: &GuildConfig,
+    ) -> Result<Arc<Guild>, Error> {
+        let data_path = self.data_path.join(id.to_string());
+        match fs::create_dir(&data_path) {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(Error::DataDir(e)),
+        }
+
+        let whitelist = self.whitelist(&config.whitelist_path).await?;
+        whitelist.set_comments(id, config.whitelist_comments).await;
+
+        let backend = self.config()?.get().await.storage;
+        let storage = storage::open(backend, &data_path)?;
+
+        let members = Arc::new(MemberService::new(
+            id,
+            self.audit.clone(),
+            Arc::downgrade(&whitelist),
+            storage,
+        ));
+        members.load().await?;
+        write_behind::spawn(members.clone());
+
+        let verification = Arc::new(VerificationService::new(
+            id,
+            Arc::downgrade(&members),
+            self.config.clone(),
+            &data_path,
+        ));
+        verification.load().await?;
+        write_behind::spawn(verification.clone());
+
+        let guild = Arc::new(Guild {
+            id,
+            members,
+            verification,
+            whitelist,
+        });
+
+        self.guilds.write().await.insert(id, guild.clone());
+
+        log::info!("Serving guild {id}");
+
+        Ok(guild)
+    }
+
+    /// Guilds pointing at the same whitelist file share one service so their
+    /// writes don't clobber each other
+    async fn whitelist(&self, path: &Path) -> Result<Arc<WhitelistService>, Error> {
+        let canonical_path =
+            fs::canonicalize(path).map_err(|e| Error::Whitelist(whitelist::Error::Read(e)))?;
+
+        let mut guard = self.whitelists.lock().await;
+
+        if let Some(whitelist) = guard.get(&canonical_path).and_then(Weak::upgrade) {
+            return Ok(whitelist);
+        }
+
+        let whitelist = Arc::new(WhitelistService::new(&canonical_path, self.audit.clone()));
+        whitelist.load().await?;
+        write_behind::spawn(whitelist.clone());
+        whitelist::watch(Arc::downgrade(&whitelist));
+        whitelist::expire(Arc::downgrade(&whitelist));
+        log::info!("Whitelist loaded from {}", canonical_path.display());
+
+        guard.insert(canonical_path, Arc::downgrade(&whitelist));
+
+        Ok(whitelist)
+    }
+
+    pub async fn get(&self, id: serenity::GuildId) -> Option<Arc<Guild>> {
+        self.guilds.read().await.get(&id).cloned()
+    }
+
+    /// Writes out every pending change of every guild, for when it has to be
+    /// on disk right now rather than in a moment
+    pub async fn flush(&self) -> Result<(), super::Error> {
+        let guilds: Vec<_> = self.guilds.read().await.values().cloned().collect();
+
+        for guild in guilds {
+            guild.flush().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Guilds writing to the same whitelist file as the given one, including itself
+    pub async fn sharing_whitelist(&self, guild: &Guild) -> Vec<Arc<Guild>> {
+        self.guilds
+            .read()
+            .await
+            .values()
+            .filter(|other| Arc::ptr_eq(&other.whitelist, &guild.whitelist))
+            .cloned()
+            .collect()
+    }
+}
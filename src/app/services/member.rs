@@ -0,0 +1,741 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+};
+
+use poise::serenity_prelude as serenity;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, Notify, RwLock};
+
+use crate::app::models::Ckey;
+
+use super::{
+    audit::{self, Actor, AuditAction, AuditService},
+    storage::{self, Storage},
+    whitelist,
+    write_behind::WriteBehind,
+    VerificationStatus, WhitelistService,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    Storage(storage::Error),
+    Audit(audit::Error),
+    Whitelist(whitelist::Error),
+    Dependency(&'static str),
+}
+
+impl From<Error> for super::Error {
+    fn from(value: Error) -> Self {
+        Self::Member(value)
+    }
+}
+
+impl From<storage::Error> for Error {
+    fn from(value: storage::Error) -> Self {
+        Self::Storage(value)
+    }
+}
+
+impl From<whitelist::Error> for Error {
+    fn from(value: whitelist::Error) -> Self {
+        Self::Whitelist(value)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Error::Storage(ref e) => write!(f, "storage: {e}"),
+            Error::Audit(ref e) => write!(f, "audit: {e}"),
+            Error::Whitelist(ref e) => write!(f, "whitelist: {e}"),
+            Error::Dependency(ref e) => write!(f, "dependency not loaded: {e}"),
+        }
+    }
+}
+
+/// One of the ckeys a member owns. Exactly one of a member's ckeys is primary,
+/// it's the one shown in logs and messages
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct MappedCkey {
+    pub ckey: String,
+    pub primary: bool,
+}
+
+/// Overrides staff can put on a member
+#[derive(
+    poise::ChoiceParameter,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Debug,
+)]
+pub enum MemberFlag {
+    /// Whitelisted even without being verified
+    #[name = "Always whitelisted"]
+    AlwaysWhitelisted,
+    /// Kept off the whitelist even once verified
+    #[name = "Never whitelisted"]
+    NeverWhitelisted,
+}
+
+/// Everything the bot keeps about a member of a guild
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct Member {
+    #[serde(default)]
+    pub status: Option<VerificationStatus>,
+    #[serde(default)]
+    pub status_since: Option<serenity::Timestamp>,
+    #[serde(default)]
+    pub ckeys: Vec<MappedCkey>,
+    #[serde(default)]
+    pub ckeys_since: Option<serenity::Timestamp>,
+    #[serde(default)]
+    pub notes: Option<String>,
+    #[serde(default)]
+    pub flags: BTreeSet<MemberFlag>,
+}
+
+impl Member {
+    pub fn primary_ckey(&self) -> Option<Ckey> {
+        self.ckeys
+            .iter()
+            .find(|mapped| mapped.primary)
+            .map(|mapped| Ckey::from(&mapped.ckey))
+    }
+
+    pub fn has_ckey(&self, ckey: &str) -> bool {
+        self.ckeys.iter().any(|mapped| mapped.ckey == ckey)
+    }
+
+    /// Whether the member's ckeys belong in the whitelist
+    pub fn whitelisted(&self) -> bool {
+        !self.flags.contains(&MemberFlag::NeverWhitelisted)
+            && (self.status == Some(VerificationStatus::Verified)
+                || self.flags.contains(&MemberFlag::AlwaysWhitelisted))
+    }
+
+    fn whitelisted_ckeys(&self) -> HashSet<String> {
+        if !self.whitelisted() {
+            return HashSet::new();
+        }
+
+        self.ckeys
+            .iter()
+            .map(|mapped| mapped.ckey.clone())
+            .collect()
+    }
+
+    /// Gives the member a ckey, or makes one they have primary. Their first
+    /// ckey is always primary. Returns whether anything changed
+    pub fn add_ckey(&mut self, ckey: &Ckey, primary: bool) -> bool {
+        let primary = primary || self.ckeys.is_empty();
+
+        match self
+            .ckeys
+            .iter()
+            .position(|mapped| mapped.ckey == ckey.as_str())
+        {
+            Some(index) if primary && !self.ckeys[index].primary => {
+                for (i, mapped) in self.ckeys.iter_mut().enumerate() {
+                    mapped.primary = i == index;
+                }
+                true
+            }
+            Some(_) => false,
+            None => {
+                if primary {
+                    self.ckeys
+                        .iter_mut()
+                        .for_each(|mapped| mapped.primary = false);
+                }
+                self.ckeys.push(MappedCkey {
+                    ckey: ckey.as_str().to_string(),
+                    primary,
+                });
+                true
+            }
+        }
+    }
+
+    /// Takes a ckey away, promoting the next one if it was primary. Returns
+    /// whether the member had it
+    pub fn remove_ckey(&mut self, ckey: &str) -> bool {
+        let Some(index) = self.ckeys.iter().position(|mapped| mapped.ckey == ckey) else {
+            return false;
+        };
+
+        let removed = self.ckeys.remove(index);
+        if removed.primary {
+            if let Some(next) = self.ckeys.first_mut() {
+                next.primary = true;
+            }
+        }
+
+        true
+    }
+
+    /// Restores the invariants closures given to `update` may have broken
+    fn normalize(&mut self) {
+        let mut seen = HashSet::new();
+        self.ckeys.retain(|mapped| seen.insert(mapped.ckey.clone()));
+
+        let primary = self
+            .ckeys
+            .iter()
+            .position(|mapped| mapped.primary)
+            .unwrap_or(0);
+        for (i, mapped) in self.ckeys.iter_mut().enumerate() {
+            mapped.primary = i == primary;
+        }
+
+        if self
+            .notes
+            .as_deref()
+            .is_some_and(|notes| notes.trim().is_empty())
+        {
+            self.notes = None;
+        }
+    }
+
+    /// Moves timestamps along with whatever changed since `before`, unless
+    /// they were set explicitly like when a record is put back
+    fn touch(&mut self, before: &Member) {
+        let now = serenity::Timestamp::now();
+
+        if self.status.as_ref().map(VerificationStatus::name)
+            != before.status.as_ref().map(VerificationStatus::name)
+            && self.status_since == before.status_since
+        {
+            self.status_since = self.status.is_some().then_some(now);
+        }
+
+        let ckeys: HashSet<_> = self.ckeys.iter().map(|mapped| &mapped.ckey).collect();
+        let before_ckeys: HashSet<_> = before.ckeys.iter().map(|mapped| &mapped.ckey).collect();
+        if ckeys != before_ckeys && self.ckeys_since == before.ckeys_since {
+            self.ckeys_since = (!ckeys.is_empty()).then_some(now);
+        }
+    }
+
+    /// Nothing worth keeping, the record can be dropped
+    fn is_empty(&self) -> bool {
+        self.status.is_none()
+            && self.ckeys.is_empty()
+            && self.notes.is_none()
+            && self.flags.is_empty()
+    }
+
+    /// Audit log entries describing the change from `before`
+    fn diff(&self, user_id: serenity::UserId, before: &Member) -> Vec<AuditAction> {
+        let mut actions = Vec::new();
+
+        for mapped in &before.ckeys {
+            if !self.has_ckey(&mapped.ckey) {
+                actions.push(AuditAction::CkeyUnset {
+                    user_id,
+                    ckey: mapped.ckey.clone(),
+                });
+            }
+        }
+        for mapped in &self.ckeys {
+            if !before.has_ckey(&mapped.ckey) {
+                actions.push(AuditAction::CkeySet {
+                    user_id,
+                    ckey: mapped.ckey.clone(),
+                });
+            } else if mapped.primary
+                && before.primary_ckey().as_ref().map(Ckey::as_str) != Some(&mapped.ckey)
+            {
+                actions.push(AuditAction::CkeyPrimary {
+                    user_id,
+                    ckey: mapped.ckey.clone(),
+                });
+            }
+        }
+
+        // Only transitions between states are audited, not progress through the form
+        match (&before.status, &self.status) {
+            (Some(_), None) => actions.push(AuditAction::StatusCleared { user_id }),
+            (before, Some(status))
+                if before.as_ref().map(VerificationStatus::name) != Some(status.name()) =>
+            {
+                actions.push(AuditAction::StatusSet {
+                    user_id,
+                    status: status.name().into(),
+                })
+            }
+            _ => (),
+        }
+
+        for flag in self.flags.difference(&before.flags) {
+            actions.push(AuditAction::MemberUpdated {
+                user_id,
+                change: format!("flagged {}", flag.name()),
+            });
+        }
+        for flag in before.flags.difference(&self.flags) {
+            actions.push(AuditAction::MemberUpdated {
+                user_id,
+                change: format!("unflagged {}", flag.name()),
+            });
+        }
+        if self.notes != before.notes {
+            actions.push(AuditAction::MemberUpdated {
+                user_id,
+                change: match self.notes {
+                    Some(ref notes) => format!("notes set to \"{notes}\""),
+                    None => "notes cleared".into(),
+                },
+            });
+        }
+
+        actions
+    }
+}
+
+/// Member records along with who owns each ckey, kept in sync so lookups both
+/// ways don't have to scan
+#[derive(Default)]
+struct Members {
+    records: HashMap<serenity::UserId, Member>,
+    owners: HashMap<String, serenity::UserId>,
+}
+
+impl Members {
+    fn new(records: HashMap<serenity::UserId, Member>) -> Self {
+        let mut owners = HashMap::new();
+
+        let mut ids: Vec<_> = records.keys().copied().collect();
+        ids.sort();
+        for id in ids {
+            for mapped in &records[&id].ckeys {
+                if let Some(owner) = owners.get(&mapped.ckey) {
+                    log::warn!(
+                        "{} is mapped to both {owner} and {id}, looking it up gives {owner}",
+                        mapped.ckey
+                    );
+                    continue;
+                }
+                owners.insert(mapped.ckey.clone(), id);
+            }
+        }
+
+        Self { records, owners }
+    }
+
+    fn get(&self, id: serenity::UserId) -> Member {
+        self.records.get(&id).cloned().unwrap_or_default()
+    }
+
+    fn apply(&mut self, id: serenity::UserId, before: &Member, after: &Member) {
+        for mapped in &before.ckeys {
+            if self.owners.get(&mapped.ckey) == Some(&id) {
+                self.owners.remove(&mapped.ckey);
+            }
+        }
+        for mapped in &after.ckeys {
+            self.owners.insert(mapped.ckey.clone(), id);
+        }
+
+        if after.is_empty() {
+            self.records.remove(&id);
+        } else {
+            self.records.insert(id, after.clone());
+        }
+    }
+}
+
+/// A member record before and after an update
+struct Change {
+    id: serenity::UserId,
+    before: Member,
+    after: Member,
+}
+
+/// Records changed together by `update_many`, copied as they're first touched
+pub struct Batch<'a> {
+    members: &'a Members,
+    changes: Vec<Change>,
+    index: HashMap<serenity::UserId, usize>,
+}
+
+impl<'a> Batch<'a> {
+    fn new(members: &'a Members) -> Self {
+        Self {
+            members,
+            changes: Vec::new(),
+            index: HashMap::new(),
+        }
+    }
+
+    /// Record of a member as changed so far, empty if they have none
+    pub fn get(&mut self, id: serenity::UserId) -> &mut Member {
+        let index = *self.index.entry(id).or_insert_with(|| {
+            let before = self.members.get(id);
+            self.changes.push(Change {
+                id,
+                after: before.clone(),
+                before,
+            });
+            self.changes.len() - 1
+        });
+
+        &mut self.changes[index].after
+    }
+
+    /// Takes ckeys given to a member away from whoever had them before, be it
+    /// earlier in the batch or not
+    fn settle(&mut self) {
+        let mut given: HashMap<String, serenity::UserId> = HashMap::new();
+
+        let mut i = 0;
+        while i < self.changes.len() {
+            let id = self.changes[i].id;
+            let ckeys: Vec<_> = self.changes[i]
+                .after
+                .ckeys
+                .iter()
+                .map(|mapped| mapped.ckey.clone())
+                .collect();
+
+            for ckey in ckeys {
+                let owner = given
+                    .get(&ckey)
+                    .or_else(|| self.members.owners.get(&ckey))
+                    .copied();
+                if let Some(owner) = owner.filter(|owner| *owner != id) {
+                    let owner = self.get(owner);
+                    owner.remove_ckey(&ckey);
+                }
+                given.insert(ckey, id);
+            }
+
+            i += 1;
+        }
+    }
+}
+
+/// Single store of every member of a guild. All changes go through `update`
+pub struct MemberService {
+    guild_id: serenity::GuildId,
+    storage: Arc<dyn Storage<Member>>,
+    audit: Arc<AuditService>,
+    whitelist: Weak<WhitelistService>,
+    members: RwLock<Members>,
+    dirty: AtomicBool,
+    changed: Notify,
+    /// Held by a flush from taking the dirty flag until it's written, so
+    /// another one waits for it rather than returning early
+    flushing: Mutex<()>,
+}
+
+impl MemberService {
+    pub fn new(
+        guild_id: serenity::GuildId,
+        audit: Arc<AuditService>,
+        whitelist: Weak<WhitelistService>,
+        storage: Arc<dyn Storage<Member>>,
+    ) -> Self {
+        Self {
+            guild_id,
+            storage,
+            audit,
+            whitelist,
+            members: RwLock::new(Members::default()),
+            dirty: AtomicBool::new(false),
+            changed: Notify::new(),
+            flushing: Mutex::new(()),
+        }
+    }
+
+    fn whitelist(&self) -> Result<Arc<WhitelistService>, Error> {
+        self.whitelist
+            .upgrade()
+            .ok_or(Error::Dependency("whitelist"))
+    }
+
+    pub async fn load(&self) -> Result<(), Error> {
+        let records = storage::blocking(&self.storage, |s| s.load()).await?;
+
+        let whitelisted = records
+            .iter()
+            .flat_map(|(id, member)| {
+                member
+                    .whitelisted_ckeys()
+                    .into_iter()
+                    .map(move |ckey| (ckey, *id))
+            })
+            .collect();
+        let added = self
+            .whitelist()?
+            .register(self.guild_id, whitelisted)
+            .await?;
+        if added > 0 {
+            log::info!("Whitelisted {added} ckeys of members that were missing from the whitelist");
+        }
+
+        *self.members.write().await = Members::new(records);
+
+        Ok(())
+    }
+
+    fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Release);
+        self.changed.notify_one();
+    }
+
+    async fn record(&self, actor: Actor, action: AuditAction) -> Result<(), Error> {
+        self.audit
+            .record(Some(self.guild_id), actor, action)
+            .await
+            .map_err(Error::Audit)
+    }
+
+    /// Changes a member's record. Ckeys given to them are taken away from
+    /// whoever had them before, and every record touched is stored at once or
+    /// not at all. The whitelist and audit log follow once it's stored
+    pub async fn update<T>(
+        &self,
+        id: serenity::UserId,
+        actor: Actor,
+        f: impl FnOnce(&mut Member) -> T,
+    ) -> Result<T, Error> {
+        self.update_many(actor, |batch| f(batch.get(id))).await
+    }
+
+    /// Changes several records like `update`, all of them stored together or
+    /// none at all
+    pub async fn update_many<T>(
+        &self,
+        actor: Actor,
+        f: impl FnOnce(&mut Batch) -> T,
+    ) -> Result<T, Error> {
+        let (result, changes) = {
+            let mut members = self.members.write().await;
+
+            let mut batch = Batch::new(&members);
+            let result = f(&mut batch);
+            for change in &mut batch.changes {
+                change.after.normalize();
+            }
+            batch.settle();
+
+            let mut changes = batch.changes;
+            changes.retain(|change| change.after != change.before);
+            if changes.is_empty() {
+                return Ok(result);
+            }
+            for change in &mut changes {
+                change.after.touch(&change.before);
+            }
+
+            let stored: Vec<_> = changes
+                .iter()
+                .map(|change| {
+                    (
+                        change.id,
+                        (!change.after.is_empty()).then(|| change.after.clone()),
+                    )
+                })
+                .collect();
+            storage::blocking(&self.storage, move |s| s.update(&stored)).await?;
+
+            for change in &changes {
+                members.apply(change.id, &change.before, &change.after);
+            }
+            self.mark_dirty();
+
+            (result, changes)
+        };
+
+        let whitelist = self.whitelist()?;
+        for change in &changes {
+            let before = change.before.whitelisted_ckeys();
+            let after = change.after.whitelisted_ckeys();
+
+            for ckey in after.difference(&before) {
+                whitelist
+                    .claim(self.guild_id, change.id, &Ckey::from(ckey), actor)
+                    .await?;
+            }
+            for ckey in before.difference(&after) {
+                whitelist
+                    .release(self.guild_id, &Ckey::from(ckey), actor)
+                    .await?;
+            }
+
+            for action in change.after.diff(change.id, &change.before) {
+                self.record(actor, action).await?;
+            }
+        }
+
+        Ok(result)
+    }
+
+    pub async fn get(&self, id: &serenity::UserId) -> Option<Member> {
+        self.members.read().await.records.get(id).cloned()
+    }
+
+    pub async fn list(&self) -> HashMap<serenity::UserId, Member> {
+        self.members.read().await.records.clone()
+    }
+
+    pub async fn get_status(&self, id: &serenity::UserId) -> Option<VerificationStatus> {
+        self.members
+            .read()
+            .await
+            .records
+            .get(id)
+            .and_then(|member| member.status.clone())
+    }
+
+    /// Member's primary ckey
+    pub async fn get_ckey(&self, id: &serenity::UserId) -> Option<Ckey> {
+        self.members
+            .read()
+            .await
+            .records
+            .get(id)
+            .and_then(Member::primary_ckey)
+    }
+
+    /// All of a member's ckeys, primary first
+    pub async fn get_ckeys(&self, id: &serenity::UserId) -> Vec<MappedCkey> {
+        let mut ckeys = self
+            .members
+            .read()
+            .await
+            .records
+            .get(id)
+            .map(|member| member.ckeys.clone())
+            .unwrap_or_default();
+        ckeys.sort_by_key(|mapped| !mapped.primary);
+
+        ckeys
+    }
+
+    pub async fn get_user(&self, ckey: &Ckey) -> Option<serenity::UserId> {
+        let lookup = ckey.as_str().to_string();
+        match storage::blocking(&self.storage, move |s| s.get_user(&lookup)).await {
+            Ok(user_id) => user_id,
+            Err(e) => {
+                log::warn!("Couldn't look up the owner of {ckey} in storage: {e}");
+                self.members.read().await.owners.get(ckey.as_str()).copied()
+            }
+        }
+    }
+
+    /// Every ckey owned by any member
+    pub async fn mapped_ckeys(&self) -> HashSet<String> {
+        self.members.read().await.owners.keys().cloned().collect()
+    }
+
+    pub async fn add_ckey(
+        &self,
+        id: serenity::UserId,
+        ckey: &Ckey,
+        primary: bool,
+        actor: Actor,
+    ) -> Result<bool, Error> {
+        self.update(id, actor, |member| member.add_ckey(ckey, primary))
+            .await
+    }
+
+    /// Makes a ckey the member's only one. Returns whether anything changed
+    pub async fn set_ckey(
+        &self,
+        id: serenity::UserId,
+        ckey: &Ckey,
+        actor: Actor,
+    ) -> Result<bool, Error> {
+        self.update(id, actor, |member| {
+            let ckeys = vec![MappedCkey {
+                ckey: ckey.as_str().to_string(),
+                primary: true,
+            }];
+            std::mem::replace(&mut member.ckeys, ckeys) != member.ckeys
+        })
+        .await
+    }
+
+    pub async fn remove_ckey(
+        &self,
+        id: serenity::UserId,
+        ckey: &Ckey,
+        actor: Actor,
+    ) -> Result<bool, Error> {
+        self.update(id, actor, |member| member.remove_ckey(ckey.as_str()))
+            .await
+    }
+
+    /// Takes every ckey away from a member, returns the ones they had
+    pub async fn remove_ckeys(
+        &self,
+        id: serenity::UserId,
+        actor: Actor,
+    ) -> Result<Vec<MappedCkey>, Error> {
+        self.update(id, actor, |member| std::mem::take(&mut member.ckeys))
+            .await
+    }
+
+    /// Removes a member's ckeys from the whitelist, entries added by hand
+    /// included. Returns whether any of them was whitelisted
+    pub async fn unwhitelist(&self, id: &serenity::UserId, actor: Actor) -> Result<bool, Error> {
+        let whitelist = self.whitelist()?;
+
+        let mut result = false;
+        for mapped in self.get_ckeys(id).await {
+            result |= whitelist.remove(&Ckey::from(&mapped.ckey), actor).await?;
+        }
+
+        Ok(result)
+    }
+
+    /// Sets or clears a flag, returns whether it changed
+    pub async fn set_flag(
+        &self,
+        id: serenity::UserId,
+        flag: MemberFlag,
+        value: bool,
+        actor: Actor,
+    ) -> Result<bool, Error> {
+        self.update(id, actor, |member| {
+            if value {
+                member.flags.insert(flag)
+            } else {
+                member.flags.remove(&flag)
+            }
+        })
+        .await
+    }
+}
+
+impl WriteBehind for MemberService {
+    fn changed(&self) -> &Notify {
+        &self.changed
+    }
+
+    async fn flush(&self) -> Result<(), super::Error> {
+        let _flushing = self.flushing.lock().await;
+
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+
+        if let Err(e) = storage::blocking(&self.storage, |s| s.flush()).await {
+            self.dirty.store(true, Ordering::Release);
+            return Err(Error::Storage(e).into());
+        }
+
+        Ok(())
+    }
+}
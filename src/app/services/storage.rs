@@ -0,0 +1,248 @@
+mod ron_file;
+mod sqlite;
+
+use std::{collections::HashMap, fmt, fs, io, path::Path, sync::Arc};
+
+use poise::serenity_prelude as serenity;
+use ron::{error::SpannedError, ser::PrettyConfig};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    config::StorageBackend,
+    data_file::{self, Schema},
+};
+
+use super::{
+    member::{MappedCkey, Member},
+    VerificationStatus,
+};
+
+use ron_file::RonStorage;
+use sqlite::SqliteStorage;
+
+const CKEYS_FILE: &str = "ckeys.ron";
+const VERIFICATION_FILE: &str = "verification.ron";
+const MEMBERS_FILE: &str = "members.ron";
+const DATABASE_FILE: &str = "data.sqlite3";
+
+const CKEYS_SCHEMA: Schema = Schema {
+    migrations: &[data_file::unversioned, single_to_multiple_ckeys],
+};
+const VERIFICATION_SCHEMA: Schema = Schema {
+    migrations: &[data_file::unversioned],
+};
+const MEMBERS_SCHEMA: Schema = Schema {
+    migrations: &[data_file::unversioned],
+};
+
+#[derive(Debug)]
+pub enum Error {
+    Read(data_file::Error),
+    Write(ron::Error),
+    Sqlite(rusqlite::Error),
+}
+
+impl From<data_file::Error> for Error {
+    fn from(value: data_file::Error) -> Self {
+        Self::Read(value)
+    }
+}
+
+impl From<SpannedError> for Error {
+    fn from(value: SpannedError) -> Self {
+        Self::Read(value.into())
+    }
+}
+
+impl From<ron::Error> for Error {
+    fn from(value: ron::Error) -> Self {
+        Self::Write(value)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {
+        Self::Write(value.into())
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(value: rusqlite::Error) -> Self {
+        Self::Sqlite(value)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Error::Read(ref e) => write!(f, "read: {e}"),
+            Error::Write(ref e) => write!(f, "write: {e}"),
+            Error::Sqlite(ref e) => write!(f, "sqlite: {e}"),
+        }
+    }
+}
+
+/// Values kept per Discord user
+pub trait Storable: Serialize + DeserializeOwned + Clone + Send + Sync + 'static {
+    /// Ckeys the value can be looked up by
+    fn ckeys(&self) -> Vec<String>;
+}
+
+impl Storable for Member {
+    fn ckeys(&self) -> Vec<String> {
+        self.ckeys
+            .iter()
+            .map(|mapped| mapped.ckey.clone())
+            .collect()
+    }
+}
+
+/// Persistent map from Discord users to values. Changes may be held back
+/// until `flush` depending on the backend
+pub trait Storage<V: Storable>: Send + Sync {
+    fn load(&self) -> Result<HashMap<serenity::UserId, V>, Error>;
+
+    /// User whose value has a ckey, the lowest ID if several do
+    fn get_user(&self, ckey: &str) -> Result<Option<serenity::UserId>, Error>;
+
+    /// Sets or, given `None`, removes the values of several users at once,
+    /// either every change is made or none is
+    fn update(&self, changes: &[(serenity::UserId, Option<V>)]) -> Result<(), Error>;
+
+    /// Makes every change so far durable
+    fn flush(&self) -> Result<(), Error>;
+
+    /// Swaps all values at once, either everything is written or nothing is
+    fn replace_all(&self, values: &HashMap<serenity::UserId, V>) -> Result<(), Error>;
+}
+
+/// Runs a storage operation off the async runtime
+pub async fn blocking<V, T, F>(storage: &Arc<dyn Storage<V>>, f: F) -> Result<T, Error>
+where
+    V: Storable,
+    T: Send + 'static,
+    F: FnOnce(&dyn Storage<V>) -> Result<T, Error> + Send + 'static,
+{
+    let storage = storage.clone();
+
+    tokio::task::spawn_blocking(move || f(&*storage))
+        .await
+        .expect("Thread panicked")
+}
+
+/// Members could only have a single ckey in version 1
+fn single_to_multiple_ckeys(contents: String) -> Result<String, String> {
+    let ckeys: HashMap<serenity::UserId, String> =
+        ron::de::from_str(&contents).map_err(|e| e.to_string())?;
+
+    let ckeys: HashMap<_, _> = ckeys
+        .into_iter()
+        .map(|(id, ckey)| {
+            (
+                id,
+                vec![MappedCkey {
+                    ckey,
+                    primary: true,
+                }],
+            )
+        })
+        .collect();
+
+    ron::ser::to_string_pretty(&ckeys, PrettyConfig::default()).map_err(|e| e.to_string())
+}
+
+/// Ckeys and verification statuses used to be kept apart, joins them into
+/// member records
+fn merge_legacy(
+    ckeys: HashMap<serenity::UserId, Vec<MappedCkey>>,
+    statuses: HashMap<serenity::UserId, VerificationStatus>,
+) -> HashMap<serenity::UserId, Member> {
+    let mut members: HashMap<_, Member> = HashMap::new();
+
+    for (id, ckeys) in ckeys {
+        members.entry(id).or_default().ckeys = ckeys;
+    }
+    for (id, status) in statuses {
+        members.entry(id).or_default().status = Some(status);
+    }
+
+    members
+}
+
+/// Joins `ckeys.ron` and `verification.ron` into `members.ron`, keeping the
+/// old files as `*.ron.merged`
+fn merge_legacy_files(data_path: &Path) -> Result<(), Error> {
+    let members_path = data_path.join(MEMBERS_FILE);
+    let ckeys_path = data_path.join(CKEYS_FILE);
+    let verification_path = data_path.join(VERIFICATION_FILE);
+
+    if members_path.exists() || !(ckeys_path.exists() || verification_path.exists()) {
+        return Ok(());
+    }
+
+    let ckeys = data_file::read_versioned(&ckeys_path, &CKEYS_SCHEMA)?.unwrap_or_default();
+    let statuses =
+        data_file::read_versioned(&verification_path, &VERIFICATION_SCHEMA)?.unwrap_or_default();
+    let members = merge_legacy(ckeys, statuses);
+
+    data_file::write_versioned(&members_path, &MEMBERS_SCHEMA, &members)?;
+
+    for path in [ckeys_path, verification_path] {
+        match fs::rename(&path, path.with_extension("ron.merged")) {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    log::info!(
+        "Merged {} and {} into {}, the old files are kept as *.ron.merged",
+        CKEYS_FILE,
+        VERIFICATION_FILE,
+        members_path.display()
+    );
+
+    Ok(())
+}
+
+/// Opens member storage of a guild
+pub fn open(backend: StorageBackend, data_path: &Path) -> Result<Arc<dyn Storage<Member>>, Error> {
+    Ok(match backend {
+        StorageBackend::Ron => {
+            merge_legacy_files(data_path)?;
+            Arc::new(RonStorage::new(
+                data_path.join(MEMBERS_FILE),
+                &MEMBERS_SCHEMA,
+            ))
+        }
+        StorageBackend::Sqlite => {
+            let connection = sqlite::open(&data_path.join(DATABASE_FILE))?;
+            Arc::new(SqliteStorage::new(connection, "members", "member_ckeys")?)
+        }
+    })
+}
+
+/// Copies a guild's RON file into SQLite, replacing whatever the database
+/// had since the RON file is what's in use until the config is switched
+pub fn migrate_to_sqlite(data_path: &Path) -> Result<usize, Error> {
+    let ron = open(StorageBackend::Ron, data_path)?;
+    let sqlite = open(StorageBackend::Sqlite, data_path)?;
+
+    let members = ron.load()?;
+    sqlite.replace_all(&members)?;
+
+    Ok(members.len())
+}
+
+/// Renames migrated RON files rather than deleting them, so nothing is lost
+/// if the move has to be undone by hand
+pub fn archive_ron_files(data_path: &Path) -> Result<(), Error> {
+    let path = data_path.join(MEMBERS_FILE);
+    match fs::rename(&path, path.with_extension("ron.migrated")) {
+        Ok(_) => (),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,74 @@
+use std::{collections::HashMap, path::PathBuf, sync::Mutex};
+
+use poise::serenity_prelude as serenity;
+
+use crate::data_file::{self, Schema};
+
+use super::{Error, Storable, Storage};
+
+/// Whole map in a single RON file, replaced when flushed
+pub struct RonStorage<V> {
+    path: PathBuf,
+    schema: &'static Schema,
+    values: Mutex<HashMap<serenity::UserId, V>>,
+}
+
+impl<V: Storable> RonStorage<V> {
+    pub fn new(path: PathBuf, schema: &'static Schema) -> Self {
+        Self {
+            path,
+            schema,
+            values: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn write(&self, values: &HashMap<serenity::UserId, V>) -> Result<(), Error> {
+        Ok(data_file::write_versioned(&self.path, self.schema, values)?)
+    }
+}
+
+impl<V: Storable> Storage<V> for RonStorage<V> {
+    fn load(&self) -> Result<HashMap<serenity::UserId, V>, Error> {
+        let values: HashMap<_, _> =
+            data_file::read_versioned(&self.path, self.schema)?.unwrap_or_default();
+
+        *self.values.lock().expect("Lock poisoned") = values.clone();
+
+        Ok(values)
+    }
+
+    fn get_user(&self, ckey: &str) -> Result<Option<serenity::UserId>, Error> {
+        let guard = self.values.lock().expect("Lock poisoned");
+
+        Ok(guard
+            .iter()
+            .filter(|(_, value)| value.ckeys().iter().any(|owned| owned == ckey))
+            .map(|(id, _)| *id)
+            .min())
+    }
+
+    fn update(&self, changes: &[(serenity::UserId, Option<V>)]) -> Result<(), Error> {
+        let mut guard = self.values.lock().expect("Lock poisoned");
+        for (id, value) in changes {
+            match value {
+                Some(value) => guard.insert(*id, value.clone()),
+                None => guard.remove(id),
+            };
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        let guard = self.values.lock().expect("Lock poisoned");
+
+        self.write(&guard)
+    }
+
+    fn replace_all(&self, values: &HashMap<serenity::UserId, V>) -> Result<(), Error> {
+        let mut guard = self.values.lock().expect("Lock poisoned");
+        *guard = values.clone();
+
+        self.write(&guard)
+    }
+}
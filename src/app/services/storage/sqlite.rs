@@ -0,0 +1,262 @@
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use poise::serenity_prelude as serenity;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+
+use crate::data_file;
+
+use super::{Error, Storable, Storage};
+
+/// Bumped whenever the tables change, kept in the database's `user_version`
+const SCHEMA_VERSION: u32 = 1;
+
+/// Opens a database shared by the tables of one guild
+pub fn open(path: &Path) -> Result<Arc<Mutex<Connection>>, Error> {
+    let connection = Connection::open(path)?;
+    connection.pragma_update(None, "journal_mode", "WAL")?;
+
+    let version: u32 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > SCHEMA_VERSION {
+        return Err(data_file::Error::TooNew {
+            version,
+            supported: SCHEMA_VERSION,
+        }
+        .into());
+    }
+    if version < SCHEMA_VERSION {
+        connection.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    }
+
+    Ok(Arc::new(Mutex::new(connection)))
+}
+
+/// A table of values and one of the ckeys each value can be found by, indexed
+/// for lookups and other tools querying the database
+pub struct SqliteStorage<V> {
+    connection: Arc<Mutex<Connection>>,
+    table: &'static str,
+    ckeys_table: &'static str,
+    values: PhantomData<fn() -> V>,
+}
+
+impl<V: Storable> SqliteStorage<V> {
+    pub fn new(
+        connection: Arc<Mutex<Connection>>,
+        table: &'static str,
+        ckeys_table: &'static str,
+    ) -> Result<Self, Error> {
+        connection
+            .lock()
+            .expect("Lock poisoned")
+            .execute_batch(&format!(
+                "CREATE TABLE IF NOT EXISTS {table} (
+                    user_id INTEGER PRIMARY KEY,
+                    value TEXT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS {ckeys_table} (
+                    user_id INTEGER NOT NULL,
+                    ckey TEXT NOT NULL,
+                    PRIMARY KEY (user_id, ckey)
+                );
+                CREATE INDEX IF NOT EXISTS {ckeys_table}_ckey ON {ckeys_table} (ckey);"
+            ))?;
+
+        Ok(Self {
+            connection,
+            table,
+            ckeys_table,
+            values: PhantomData,
+        })
+    }
+
+    fn insert(
+        &self,
+        transaction: &Transaction,
+        id: serenity::UserId,
+        value: &V,
+    ) -> Result<(), Error> {
+        transaction.execute(
+            &format!(
+                "INSERT INTO {} (user_id, value) VALUES (?1, ?2)
+                ON CONFLICT (user_id) DO UPDATE SET value = ?2",
+                self.table
+            ),
+            params![to_sql_id(id), ron::ser::to_string(value)?],
+        )?;
+
+        let mut statement = transaction.prepare_cached(&format!(
+            "INSERT OR IGNORE INTO {} (user_id, ckey) VALUES (?1, ?2)",
+            self.ckeys_table
+        ))?;
+        for ckey in value.ckeys() {
+            statement.execute(params![to_sql_id(id), ckey])?;
+        }
+
+        Ok(())
+    }
+}
+
+// Discord IDs use 63 bits at most, so they fit SQLite's signed integers
+fn to_sql_id(id: serenity::UserId) -> i64 {
+    id.0 as i64
+}
+
+impl<V: Storable> Storage<V> for SqliteStorage<V> {
+    fn load(&self) -> Result<HashMap<serenity::UserId, V>, Error> {
+        let connection = self.connection.lock().expect("Lock poisoned");
+
+        let mut values = HashMap::new();
+        let mut statement =
+            connection.prepare(&format!("SELECT user_id, value FROM {}", self.table))?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?;
+        for row in rows {
+            let (id, value) = row?;
+            values.insert(serenity::UserId(id as u64), ron::de::from_str(&value)?);
+        }
+
+        Ok(values)
+    }
+
+    fn get_user(&self, ckey: &str) -> Result<Option<serenity::UserId>, Error> {
+        let connection = self.connection.lock().expect("Lock poisoned");
+
+        let id: Option<i64> = connection
+            .query_row(
+                &format!(
+                    "SELECT user_id FROM {} WHERE ckey = ?1 ORDER BY user_id LIMIT 1",
+                    self.ckeys_table
+                ),
+                params![ckey],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(id.map(|id| serenity::UserId(id as u64)))
+    }
+
+    fn update(&self, changes: &[(serenity::UserId, Option<V>)]) -> Result<(), Error> {
+        let mut connection = self.connection.lock().expect("Lock poisoned");
+        let transaction = connection.transaction()?;
+
+        for (id, value) in changes {
+            transaction.execute(
+                &format!("DELETE FROM {} WHERE user_id = ?1", self.ckeys_table),
+                params![to_sql_id(*id)],
+            )?;
+
+            match value {
+                Some(value) => self.insert(&transaction, *id, value)?,
+                None => {
+                    transaction.execute(
+                        &format!("DELETE FROM {} WHERE user_id = ?1", self.table),
+                        params![to_sql_id(*id)],
+                    )?;
+                }
+            }
+        }
+
+        Ok(transaction.commit()?)
+    }
+
+    /// Every change is committed as it's made
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn replace_all(&self, values: &HashMap<serenity::UserId, V>) -> Result<(), Error> {
+        let mut connection = self.connection.lock().expect("Lock poisoned");
+        let transaction = connection.transaction()?;
+
+        transaction.execute_batch(&format!(
+            "DELETE FROM {}; DELETE FROM {};",
+            self.table, self.ckeys_table
+        ))?;
+        for (id, value) in values {
+            self.insert(&transaction, *id, value)?;
+        }
+
+        Ok(transaction.commit()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::{models::Ckey, services::Member};
+
+    fn storage() -> SqliteStorage<Member> {
+        let connection = Arc::new(Mutex::new(Connection::open_in_memory().unwrap()));
+        SqliteStorage::new(connection, "members", "member_ckeys").unwrap()
+    }
+
+    fn member(ckeys: &[&str]) -> Member {
+        let mut member = Member::default();
+        for ckey in ckeys {
+            member.add_ckey(&Ckey::from(*ckey), false);
+        }
+        member
+    }
+
+    #[test]
+    fn finds_users_by_any_ckey() {
+        let storage = storage();
+        let (alice, bob) = (serenity::UserId(1), serenity::UserId(2));
+        storage
+            .update(&[
+                (alice, Some(member(&["alice", "alt"]))),
+                (bob, Some(member(&["bob"]))),
+            ])
+            .unwrap();
+
+        assert_eq!(storage.get_user("alt").unwrap(), Some(alice));
+        assert_eq!(storage.get_user("bob").unwrap(), Some(bob));
+
+        storage
+            .update(&[(alice, Some(member(&["alice"]))), (bob, None)])
+            .unwrap();
+
+        assert_eq!(storage.get_user("alt").unwrap(), None);
+        assert_eq!(storage.get_user("bob").unwrap(), None);
+        assert_eq!(storage.load().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn replaces_everything() {
+        let storage = storage();
+        storage
+            .update(&[(serenity::UserId(1), Some(member(&["alice"])))])
+            .unwrap();
+
+        let values = HashMap::from([(serenity::UserId(2), member(&["bob", "alice"]))]);
+        storage.replace_all(&values).unwrap();
+
+        assert_eq!(storage.load().unwrap(), values);
+        assert_eq!(
+            storage.get_user("alice").unwrap(),
+            Some(serenity::UserId(2))
+        );
+    }
+
+    #[test]
+    fn ckey_lookups_use_the_index() {
+        let storage = storage();
+        let connection = storage.connection.lock().unwrap();
+
+        let plan: String = connection
+            .query_row(
+                "EXPLAIN QUERY PLAN SELECT user_id FROM member_ckeys WHERE ckey = 'alice'",
+                [],
+                |row| row.get(3),
+            )
+            .unwrap();
+
+        assert!(plan.contains("member_ckeys_ckey"), "{plan}");
+    }
+}
@@ -0,0 +1,788 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use poise::serenity_prelude as serenity;
+use serde::{Deserialize, Serialize};
+
+use crate::app::models::Ckey;
+
+use super::{
+    audit::Actor,
+    guild::Guild,
+    member::{self, MappedCkey, Member},
+    verification::{self, HistoryEvent, VerificationStatus},
+    whitelist,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    Json(serde_json::Error),
+    Csv(csv::Error),
+    Member(member::Error),
+    Verification(verification::Error),
+    Whitelist(whitelist::Error),
+}
+
+impl From<Error> for super::Error {
+    fn from(value: Error) -> Self {
+        Self::Transfer(value)
+    }
+}
+
+impl From<member::Error> for Error {
+    fn from(value: member::Error) -> Self {
+        Self::Member(value)
+    }
+}
+
+impl From<verification::Error> for Error {
+    fn from(value: verification::Error) -> Self {
+        Self::Verification(value)
+    }
+}
+
+impl From<whitelist::Error> for Error {
+    fn from(value: whitelist::Error) -> Self {
+        Self::Whitelist(value)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Error::Json(ref e) => write!(f, "json: {e}"),
+            Error::Csv(ref e) => write!(f, "csv: {e}"),
+            Error::Member(ref e) => write!(f, "member: {e}"),
+            Error::Verification(ref e) => write!(f, "verification: {e}"),
+            Error::Whitelist(ref e) => write!(f, "whitelist: {e}"),
+        }
+    }
+}
+
+#[derive(poise::ChoiceParameter, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    #[name = "JSON"]
+    Json,
+    #[name = "CSV"]
+    Csv,
+}
+
+impl Format {
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            Format::Json => "json",
+            Format::Csv => "csv",
+        }
+    }
+
+    /// Guesses the format from a file name
+    pub fn from_file_name(name: &str) -> Option<Self> {
+        let (_, extension) = name.rsplit_once('.')?;
+        extension.parse().ok()
+    }
+
+    pub fn serialize(&self, records: &[MemberRecord]) -> Result<Vec<u8>, Error> {
+        match *self {
+            Format::Json => serde_json::to_vec_pretty(records).map_err(Error::Json),
+            Format::Csv => {
+                let mut writer = csv::Writer::from_writer(Vec::new());
+                for record in records {
+                    writer.serialize(record).map_err(Error::Csv)?;
+                }
+
+                writer
+                    .into_inner()
+                    .map_err(|e| Error::Csv(e.into_error().into()))
+            }
+        }
+    }
+
+    pub fn deserialize(&self, data: &[u8]) -> Result<Vec<MemberRecord>, Error> {
+        match *self {
+            Format::Json => serde_json::from_slice(data).map_err(Error::Json),
+            Format::Csv => csv::Reader::from_reader(data)
+                .deserialize()
+                .collect::<Result<_, _>>()
+                .map_err(Error::Csv),
+        }
+    }
+}
+
+/// Ckeys in a single column separated by spaces, so CSV can hold them too
+mod space_separated {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(values: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&values.join(" "))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<String>, D::Error> {
+        let values = String::deserialize(deserializer)?;
+
+        Ok(values.split_whitespace().map(String::from).collect())
+    }
+}
+
+/// Everything known about a member, flattened for other tools
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MemberRecord {
+    pub user_id: serenity::UserId,
+    /// Primary ckey
+    pub ckey: Option<String>,
+    #[serde(default, with = "space_separated")]
+    pub other_ckeys: Vec<String>,
+    pub ckey_since: Option<serenity::Timestamp>,
+    /// Whether every one of the member's ckeys is whitelisted
+    pub whitelisted: bool,
+    pub status: Option<String>,
+    pub status_since: Option<serenity::Timestamp>,
+}
+
+impl MemberRecord {
+    /// Every member with a ckey mapping or verification data, sorted by ID
+    pub async fn export(guild: &Guild) -> Vec<Self> {
+        let members = guild.members.list().await;
+        let whitelist: HashSet<_> = guild.whitelist.list().await.into_iter().collect();
+
+        let mut user_ids: Vec<_> = members.keys().copied().collect();
+        user_ids.sort();
+
+        let mut records = Vec::with_capacity(user_ids.len());
+        for user_id in user_ids {
+            let member = &members[&user_id];
+
+            // Records from before members were kept together have no timestamps
+            let history = guild.verification.get_history(&user_id).await;
+            let last = |f: fn(&HistoryEvent) -> bool| {
+                history
+                    .iter()
+                    .rev()
+                    .find(|entry| f(&entry.event))
+                    .map(|entry| entry.timestamp)
+            };
+
+            records.push(Self {
+                user_id,
+                ckey: member.primary_ckey().map(|ckey| ckey.as_str().to_string()),
+                other_ckeys: member
+                    .ckeys
+                    .iter()
+                    .filter(|mapped| !mapped.primary)
+                    .map(|mapped| mapped.ckey.clone())
+                    .collect(),
+                ckey_since: match member.primary_ckey() {
+                    None => None,
+                    Some(_) => member
+                        .ckeys_since
+                        .or_else(|| last(|event| matches!(event, HistoryEvent::CkeySet { .. }))),
+                },
+                whitelisted: !member.ckeys.is_empty()
+                    && member
+                        .ckeys
+                        .iter()
+                        .all(|mapped| whitelist.contains(&mapped.ckey)),
+                status: member
+                    .status
+                    .as_ref()
+                    .map(|status| status.name().to_string()),
+                status_since: match member.status {
+                    None => None,
+                    Some(_) => member
+                        .status_since
+                        .or_else(|| last(HistoryEvent::changes_status)),
+                },
+            });
+        }
+
+        records
+    }
+}
+
+#[derive(poise::ChoiceParameter, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ImportMode {
+    /// Only fills in what's missing, current data wins conflicts
+    #[name = "Merge"]
+    Merge,
+    /// Makes the data match the file, members missing from it are cleared
+    #[name = "Replace"]
+    Replace,
+}
+
+/// Why part of an imported record wasn't applied as is
+#[derive(Clone, Debug)]
+pub enum Conflict {
+    DuplicateUser {
+        user_id: serenity::UserId,
+    },
+    DuplicateCkey {
+        user_id: serenity::UserId,
+        ckey: String,
+    },
+    CkeyDiffers {
+        user_id: serenity::UserId,
+        current: String,
+        imported: String,
+    },
+    CkeyTaken {
+        user_id: serenity::UserId,
+        ckey: String,
+        owner: serenity::UserId,
+    },
+    StatusDiffers {
+        user_id: serenity::UserId,
+        current: String,
+        imported: String,
+    },
+    StatusUnsupported {
+        user_id: serenity::UserId,
+        status: String,
+    },
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Conflict::DuplicateUser { user_id } => {
+                write!(
+                    f,
+                    "{user_id}: listed more than once, only the first is used"
+                )
+            }
+            Conflict::DuplicateCkey { user_id, ref ckey } => {
+                write!(
+                    f,
+                    "{user_id}: `{ckey}` is already used by an earlier record"
+                )
+            }
+            Conflict::CkeyDiffers {
+                user_id,
+                ref current,
+                ref imported,
+            } => write!(f, "{user_id}: ckey is `{current}`, file has `{imported}`"),
+            Conflict::CkeyTaken {
+                user_id,
+                ref ckey,
+                owner,
+            } => write!(f, "{user_id}: `{ckey}` is mapped to {owner}"),
+            Conflict::StatusDiffers {
+                user_id,
+                ref current,
+                ref imported,
+            } => write!(f, "{user_id}: status is {current}, file has {imported}"),
+            Conflict::StatusUnsupported {
+                user_id,
+                ref status,
+            } => write!(
+                f,
+                "{user_id}: {status} can't be imported, it's tied to Discord messages"
+            ),
+        }
+    }
+}
+
+/// A single change an import makes
+#[derive(Clone, Debug)]
+enum Change {
+    UnsetCkey(serenity::UserId),
+    RemoveCkey(serenity::UserId, Ckey),
+    AddCkey(serenity::UserId, Ckey, bool),
+    ClearStatus(serenity::UserId),
+    SetStatus(serenity::UserId, VerificationStatus),
+    Whitelist(Ckey),
+    Unwhitelist(serenity::UserId, Ckey),
+}
+
+/// What an import does, worked out before touching anything
+pub struct ImportPlan {
+    mode: ImportMode,
+    changes: Vec<Change>,
+    pub conflicts: Vec<Conflict>,
+}
+
+impl ImportPlan {
+    pub async fn new(guild: &Guild, records: Vec<MemberRecord>, mode: ImportMode) -> Self {
+        let members = guild.members.list().await;
+        let whitelist: HashSet<_> = guild.whitelist.list().await.into_iter().collect();
+
+        Self::plan(&members, &whitelist, records, mode)
+    }
+
+    /// Works out the changes against the given member records and whitelist
+    fn plan(
+        members: &HashMap<serenity::UserId, Member>,
+        whitelist: &HashSet<String>,
+        records: Vec<MemberRecord>,
+        mode: ImportMode,
+    ) -> Self {
+        let ckeys: HashMap<_, _> = members
+            .iter()
+            .filter(|(_, member)| !member.ckeys.is_empty())
+            .map(|(id, member)| (*id, member.ckeys.clone()))
+            .collect();
+        let owners: HashMap<_, _> = ckeys
+            .iter()
+            .flat_map(|(id, mapped)| mapped.iter().map(|mapped| (mapped.ckey.clone(), *id)))
+            .collect();
+        let statuses: HashMap<_, _> = members
+            .iter()
+            .filter_map(|(id, member)| Some((*id, member.status.clone()?)))
+            .collect();
+
+        let mut plan = Self {
+            mode,
+            changes: Vec::new(),
+            conflicts: Vec::new(),
+        };
+        let mut seen_users = HashSet::new();
+        let mut seen_ckeys = HashSet::new();
+
+        for record in records {
+            let user_id = record.user_id;
+            if !seen_users.insert(user_id) {
+                plan.conflicts.push(Conflict::DuplicateUser { user_id });
+                continue;
+            }
+
+            let current = ckeys.get(&user_id).map(Vec::as_slice).unwrap_or_default();
+            let imported: Vec<_> = record
+                .ckey
+                .iter()
+                .map(|ckey| (Ckey::from(ckey), true))
+                .chain(
+                    record
+                        .other_ckeys
+                        .iter()
+                        .map(|ckey| (Ckey::from(ckey), false)),
+                )
+                .collect();
+
+            for (ckey, primary) in &imported {
+                if seen_ckeys.insert(ckey.as_str().to_string()) {
+                    plan.plan_ckey(
+                        user_id,
+                        ckey.clone(),
+                        *primary,
+                        record.whitelisted,
+                        current,
+                        &owners,
+                        whitelist,
+                    );
+                } else {
+                    plan.conflicts.push(Conflict::DuplicateCkey {
+                        user_id,
+                        ckey: ckey.as_str().into(),
+                    });
+                }
+            }
+
+            if mode == ImportMode::Replace {
+                for mapped in current {
+                    if !imported
+                        .iter()
+                        .any(|(ckey, _)| ckey.as_str() == mapped.ckey)
+                    {
+                        plan.changes
+                            .push(Change::RemoveCkey(user_id, Ckey::from(&mapped.ckey)));
+                    }
+                }
+            }
+
+            plan.plan_status(user_id, record.status, &statuses);
+        }
+
+        if mode == ImportMode::Replace {
+            let mut missing: Vec<_> = ckeys
+                .keys()
+                .filter(|id| !seen_users.contains(id))
+                .map(|id| Change::UnsetCkey(*id))
+                .chain(
+                    statuses
+                        .keys()
+                        .filter(|id| !seen_users.contains(id))
+                        .map(|id| Change::ClearStatus(*id)),
+                )
+                .collect();
+            plan.changes.append(&mut missing);
+        }
+
+        // Ckeys are taken away first so nothing given by the file is removed after
+        plan.changes
+            .sort_by_key(|change| !matches!(change, Change::UnsetCkey(_) | Change::RemoveCkey(..)));
+
+        plan
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn plan_ckey(
+        &mut self,
+        user_id: serenity::UserId,
+        ckey: Ckey,
+        primary: bool,
+        whitelisted: bool,
+        current: &[MappedCkey],
+        owners: &HashMap<String, serenity::UserId>,
+        whitelist: &HashSet<String>,
+    ) {
+        // Giving a ckey to a member takes it away from its previous owner
+        if let Some(&owner) = owners.get(ckey.as_str()) {
+            if owner != user_id {
+                self.conflicts.push(Conflict::CkeyTaken {
+                    user_id,
+                    ckey: ckey.as_str().into(),
+                    owner,
+                });
+                if self.mode == ImportMode::Merge {
+                    return;
+                }
+            }
+        }
+
+        let existing = current.iter().find(|mapped| mapped.ckey == ckey.as_str());
+        let current_primary = current.iter().find(|mapped| mapped.primary);
+
+        match (existing, current_primary) {
+            (Some(existing), _) if existing.primary || !primary => (),
+            (_, Some(current_primary)) if primary => {
+                self.conflicts.push(Conflict::CkeyDiffers {
+                    user_id,
+                    current: current_primary.ckey.clone(),
+                    imported: ckey.as_str().into(),
+                });
+                match self.mode {
+                    // Current primary wins, the imported one is still added
+                    ImportMode::Merge if existing.is_none() => {
+                        self.changes
+                            .push(Change::AddCkey(user_id, ckey.clone(), false))
+                    }
+                    ImportMode::Merge => (),
+                    ImportMode::Replace => {
+                        self.changes
+                            .push(Change::AddCkey(user_id, ckey.clone(), true))
+                    }
+                }
+            }
+            _ => self
+                .changes
+                .push(Change::AddCkey(user_id, ckey.clone(), primary)),
+        }
+
+        if whitelisted && !whitelist.contains(ckey.as_str()) {
+            self.changes.push(Change::Whitelist(ckey));
+        } else if !whitelisted
+            && self.mode == ImportMode::Replace
+            && whitelist.contains(ckey.as_str())
+        {
+            self.changes.push(Change::Unwhitelist(user_id, ckey));
+        }
+    }
+
+    fn plan_status(
+        &mut self,
+        user_id: serenity::UserId,
+        imported: Option<String>,
+        statuses: &HashMap<serenity::UserId, VerificationStatus>,
+    ) {
+        let current = statuses.get(&user_id);
+
+        let Some(imported) = imported else {
+            if self.mode == ImportMode::Replace && current.is_some() {
+                self.changes.push(Change::ClearStatus(user_id));
+            }
+            return;
+        };
+
+        if current.is_some_and(|current| current.name() == imported) {
+            return;
+        }
+
+        let status = match imported.as_str() {
+            "Verified" => VerificationStatus::Verified,
+            "Rejected" => VerificationStatus::Rejected,
+            _ => {
+                self.conflicts.push(Conflict::StatusUnsupported {
+                    user_id,
+                    status: imported,
+                });
+                return;
+            }
+        };
+
+        if let Some(current) = current {
+            self.conflicts.push(Conflict::StatusDiffers {
+                user_id,
+                current: current.name().into(),
+                imported,
+            });
+            if self.mode == ImportMode::Merge {
+                return;
+            }
+        }
+
+        self.changes.push(Change::SetStatus(user_id, status));
+    }
+
+    /// Counts of the changes and conflicts
+    pub fn summary(&self) -> String {
+        let count = |f: fn(&Change) -> bool| self.changes.iter().filter(|c| f(c)).count();
+
+        let mut report = format!(
+            "{} changes, {} conflicts",
+            self.changes.len(),
+            self.conflicts.len()
+        );
+        for (n, what) in [
+            (
+                count(|c| matches!(c, Change::UnsetCkey(_))),
+                "members lose all ckeys",
+            ),
+            (
+                count(|c| matches!(c, Change::RemoveCkey(..))),
+                "ckeys taken away",
+            ),
+            (count(|c| matches!(c, Change::AddCkey(..))), "ckeys given"),
+            (
+                count(|c| matches!(c, Change::ClearStatus(_))),
+                "statuses cleared",
+            ),
+            (
+                count(|c| matches!(c, Change::SetStatus(..))),
+                "statuses set",
+            ),
+            (count(|c| matches!(c, Change::Whitelist(_))), "whitelisted"),
+            (
+                count(|c| matches!(c, Change::Unwhitelist(..))),
+                "unwhitelisted",
+            ),
+        ] {
+            if n > 0 {
+                report.push_str(&format!("\n- {n} {what}"));
+            }
+        }
+
+        report
+    }
+
+    /// Summary followed by every conflict, one per line
+    pub fn report(&self) -> String {
+        let mut report = self.summary();
+        for conflict in &self.conflicts {
+            report.push_str(&format!("\n{conflict}"));
+        }
+
+        report
+    }
+
+    /// Changes every member record in one go, so a failure leaves all of
+    /// them as they were. Whitelist entries that aren't tied to a record
+    /// follow afterwards
+    pub async fn apply(self, guild: &Guild, actor: Actor) -> Result<(), Error> {
+        let (cleared, entries) = guild
+            .members
+            .update_many(actor, |batch| {
+                let mut cleared = Vec::new();
+                let mut entries = Vec::new();
+
+                for change in self.changes {
+                    match change {
+                        Change::UnsetCkey(id) => batch.get(id).ckeys.clear(),
+                        Change::RemoveCkey(id, ckey) => {
+                            batch.get(id).remove_ckey(ckey.as_str());
+                        }
+                        Change::AddCkey(id, ckey, primary) => {
+                            batch.get(id).add_ckey(&ckey, primary);
+                        }
+                        Change::ClearStatus(id) => {
+                            if batch.get(id).status.take().is_some() {
+                                cleared.push(id);
+                            }
+                        }
+                        Change::SetStatus(id, status) => batch.get(id).status = Some(status),
+                        change @ (Change::Whitelist(_) | Change::Unwhitelist(..)) => {
+                            entries.push(change)
+                        }
+                    }
+                }
+
+                (cleared, entries)
+            })
+            .await?;
+
+        for id in cleared {
+            guild
+                .verification
+                .push_history(id, actor, HistoryEvent::Cleared)
+                .await?;
+        }
+
+        for change in entries {
+            match change {
+                Change::Whitelist(ckey) => {
+                    guild
+                        .whitelist
+                        .insert(&ckey, actor, whitelist::EntrySource::Import)
+                        .await?;
+                }
+                // Unless the member's record keeps it whitelisted anyway
+                Change::Unwhitelist(id, ckey) => {
+                    let kept = guild.members.get(&id).await.is_some_and(|member| {
+                        member.whitelisted() && member.has_ckey(ckey.as_str())
+                    });
+                    if !kept {
+                        guild.whitelist.remove(&ckey, actor).await?;
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(ckey: &str, status: Option<VerificationStatus>) -> Member {
+        Member {
+            ckeys: vec![MappedCkey {
+                ckey: ckey.into(),
+                primary: true,
+            }],
+            status,
+            ..Default::default()
+        }
+    }
+
+    fn record(user_id: u64, ckey: &str, status: Option<&str>, whitelisted: bool) -> MemberRecord {
+        MemberRecord {
+            user_id: serenity::UserId(user_id),
+            ckey: Some(ckey.into()),
+            other_ckeys: Vec::new(),
+            ckey_since: None,
+            whitelisted,
+            status: status.map(String::from),
+            status_since: None,
+        }
+    }
+
+    #[test]
+    fn merge_keeps_current_data() {
+        let members = HashMap::from([(
+            serenity::UserId(1),
+            member("alice", Some(VerificationStatus::Verified)),
+        )]);
+        let records = vec![
+            record(1, "alicealt", Some("Rejected"), false),
+            record(2, "alice", None, false),
+        ];
+
+        let plan = ImportPlan::plan(&members, &HashSet::new(), records, ImportMode::Merge);
+
+        assert!(matches!(
+            plan.changes[..],
+            [Change::AddCkey(serenity::UserId(1), ref ckey, false)] if ckey.as_str() == "alicealt"
+        ));
+        assert!(matches!(
+            plan.conflicts[..],
+            [
+                Conflict::CkeyDiffers { .. },
+                Conflict::StatusDiffers { .. },
+                Conflict::CkeyTaken { .. }
+            ]
+        ));
+    }
+
+    #[test]
+    fn replace_clears_missing_members_first() {
+        let members = HashMap::from([
+            (
+                serenity::UserId(1),
+                member("alice", Some(VerificationStatus::Verified)),
+            ),
+            (serenity::UserId(2), member("bob", None)),
+        ]);
+        let whitelist = HashSet::from(["alice".to_string()]);
+        let records = vec![record(1, "carol", Some("Verified"), false)];
+
+        let plan = ImportPlan::plan(&members, &whitelist, records, ImportMode::Replace);
+
+        assert!(matches!(
+            plan.changes[..],
+            [
+                Change::RemoveCkey(serenity::UserId(1), _),
+                Change::UnsetCkey(serenity::UserId(2)),
+                Change::AddCkey(serenity::UserId(1), _, true),
+            ]
+        ));
+    }
+
+    #[test]
+    fn replace_unwhitelists_records_that_are_not() {
+        let members = HashMap::from([(serenity::UserId(1), member("alice", None))]);
+        let whitelist = HashSet::from(["alice".to_string()]);
+
+        let plan = ImportPlan::plan(
+            &members,
+            &whitelist,
+            vec![record(1, "alice", None, false)],
+            ImportMode::Replace,
+        );
+        assert!(matches!(
+            plan.changes[..],
+            [Change::Unwhitelist(serenity::UserId(1), ref ckey)] if ckey.as_str() == "alice"
+        ));
+
+        let plan = ImportPlan::plan(
+            &members,
+            &whitelist,
+            vec![record(1, "alice", None, false)],
+            ImportMode::Merge,
+        );
+        assert!(plan.changes.is_empty());
+    }
+
+    #[test]
+    fn duplicates_only_use_the_first() {
+        let records = vec![
+            record(1, "alice", None, true),
+            record(1, "bob", None, true),
+            record(2, "alice", None, true),
+        ];
+
+        let plan = ImportPlan::plan(&HashMap::new(), &HashSet::new(), records, ImportMode::Merge);
+
+        assert!(matches!(
+            plan.changes[..],
+            [
+                Change::AddCkey(serenity::UserId(1), _, true),
+                Change::Whitelist(_)
+            ]
+        ));
+        assert!(matches!(
+            plan.conflicts[..],
+            [
+                Conflict::DuplicateUser { .. },
+                Conflict::DuplicateCkey { .. }
+            ]
+        ));
+    }
+
+    #[test]
+    fn round_trips_csv() {
+        let records = vec![MemberRecord {
+            other_ckeys: vec!["alt1".into(), "alt2".into()],
+            ..record(1, "alice", Some("Verified"), true)
+        }];
+
+        let data = Format::Csv.serialize(&records).unwrap();
+        let parsed = Format::Csv.deserialize(&data).unwrap();
+
+        assert_eq!(parsed[0].other_ckeys, ["alt1", "alt2"]);
+        assert_eq!(parsed[0].status.as_deref(), Some("Verified"));
+    }
+}
@@ -1,28 +1,61 @@
+mod component;
+mod history;
+mod retry;
+mod stats;
+mod transcript;
+
 use std::{
     collections::HashMap,
     fmt,
-    fs::File,
-    io::{self, BufReader, BufWriter, Write},
+    future::Future,
     path::{Path, PathBuf},
-    sync::{Arc, Weak},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
 };
 
 use poise::serenity_prelude as serenity;
-use poise::FrameworkContext;
-use ron::{error::SpannedError, ser::PrettyConfig};
-use serde::{Deserialize, Serialize};
-use tokio::sync::RwLock;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::{Mutex, Notify, OwnedMutexGuard, RwLock};
+
+use crate::{
+    app::{logging::LogEntry, models::Ckey, Error as AppError},
+    config::{
+        template::{Values, Variable},
+        EmbedTemplate, LogEvent, MessageKind, Question,
+    },
+    data_file::{self, Schema},
+};
 
-use crate::app::{models::Ckey, Data, Error as AppError};
+pub use history::{HistoryEntry, HistoryEvent};
+pub use stats::Stats;
+pub use transcript::Transcript;
 
-use super::{CkeyService, ConfigService, WhitelistService};
+use component::{Component, Notice};
+use retry::retry;
+
+use super::{
+    audit::Actor,
+    member::{self, Member},
+    write_behind::WriteBehind,
+    ConfigService, MemberService,
+};
 
 #[derive(Debug)]
 pub enum Error {
-    Read(SpannedError),
+    Read(data_file::Error),
     Write(ron::Error),
+    Member(member::Error),
     Dependency(&'static str),
-    GrantRole(serenity::Error),
+    NotConfigured,
+    CkeyTaken {
+        ckey: String,
+        owner: serenity::UserId,
+    },
+    GrantRole(Box<serenity::Error>),
+    RevokeRole(Box<serenity::Error>),
+    Respond(Box<serenity::Error>),
     SendGreeting(SendGreetingError),
 }
 
@@ -32,13 +65,27 @@ impl From<Error> for super::Error {
     }
 }
 
+impl From<member::Error> for Error {
+    fn from(value: member::Error) -> Self {
+        Self::Member(value)
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match *self {
             Error::Read(ref e) => write!(f, "read: {e}"),
             Error::Write(ref e) => write!(f, "write: {e}"),
+            Error::Member(ref e) => write!(f, "member: {e}"),
             Error::Dependency(ref e) => write!(f, "dependency not loaded: {e}"),
+            Error::NotConfigured => write!(f, "server is not configured"),
+            Error::CkeyTaken { ref ckey, owner } => write!(
+                f,
+                "`{ckey}` is mapped to <@{owner}>, take it away from them with `/ckey remove` first"
+            ),
             Error::GrantRole(ref e) => write!(f, "grant_role: {e}"),
+            Error::RevokeRole(ref e) => write!(f, "revoke_role: {e}"),
+            Error::Respond(ref e) => write!(f, "respond: {e}"),
             Error::SendGreeting(ref e) => write!(f, "send_greeting: {e}"),
         }
     }
@@ -49,7 +96,7 @@ pub enum SendGreetingError {
     AlreadyVerified,
     AlreadyPending,
     AlreadyRejected,
-    Discord(serenity::Error),
+    Discord(Box<serenity::Error>),
 }
 
 impl From<SendGreetingError> for Error {
@@ -69,162 +116,429 @@ impl fmt::Display for SendGreetingError {
     }
 }
 
+const HISTORY_SCHEMA: Schema = Schema {
+    migrations: &[data_file::unversioned],
+};
+const TRANSCRIPTS_SCHEMA: Schema = Schema {
+    migrations: &[data_file::unversioned],
+};
+
+async fn read_file<T>(path: PathBuf, schema: &'static Schema) -> Result<Option<T>, Error>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        data_file::read_versioned(&path, schema).map_err(Error::Read)
+    })
+    .await
+    .expect("Thread panicked")
+}
+
+async fn write_file<T>(path: PathBuf, schema: &'static Schema, value: T) -> Result<(), Error>
+where
+    T: Serialize + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        data_file::write_versioned(&path, schema, &value).map_err(Error::Write)
+    })
+    .await
+    .expect("Thread panicked")
+}
+
+fn render_embed(
+    kind: MessageKind,
+    template: &EmbedTemplate,
+    values: &Values,
+) -> serenity::CreateEmbed {
+    let mut embed = serenity::CreateEmbed::default();
+
+    if let Some(ref title) = template.title {
+        embed.title(kind.template(title).render(values));
+    }
+    if let Some(color) = template.color {
+        embed.colour(color);
+    }
+    if let Some(ref description) = template.description {
+        embed.description(kind.template(description).render(values));
+    }
+    if let Some(ref thumbnail) = template.thumbnail {
+        embed.thumbnail(kind.template(thumbnail).render(values));
+    }
+
+    embed
+}
+
+/// Tells a member their verification couldn't be completed, in place of the
+/// deferred response to the ckey form
+async fn apologize(sc: &serenity::Context, interaction: &serenity::ModalSubmitInteraction) {
+    let content = "Something went wrong while completing your verification. Staff have been \
+        notified and will sort it out.";
+
+    let response = interaction
+        .edit_original_interaction_response(sc, |b| b.content(content))
+        .await;
+    if response.is_ok() {
+        return;
+    }
+
+    if let Err(e) = interaction
+        .create_followup_message(sc, |b| b.ephemeral(true).content(content))
+        .await
+    {
+        log::warn!(
+            "Couldn't tell {} their verification failed: {e}",
+            interaction.user.id
+        );
+    }
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
 pub enum VerificationStatus {
     Greeted {
         greeting_id: serenity::MessageId,
+        #[serde(default)]
+        session: u64,
     },
     Pending {
         form_data: Vec<String>,
         form_idx: usize,
+        #[serde(default)]
+        session: u64,
     },
     Verified,
     Rejected,
 }
 
+impl VerificationStatus {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            VerificationStatus::Greeted { .. } => "Greeted",
+            VerificationStatus::Pending { .. } => "Pending",
+            VerificationStatus::Verified => "Verified",
+            VerificationStatus::Rejected => "Rejected",
+        }
+    }
+}
+
+/// A member's current state and when they entered it, if the history knows
+#[derive(Clone, Debug)]
+pub struct MemberStatus {
+    pub user_id: serenity::UserId,
+    pub status: VerificationStatus,
+    pub since: Option<serenity::Timestamp>,
+}
+
 pub struct VerificationService {
-    path: PathBuf,
-    ckey: Weak<CkeyService>,
+    guild_id: serenity::GuildId,
+    members: Weak<MemberService>,
     config: Weak<ConfigService>,
-    whitelist: Weak<WhitelistService>,
-    data: RwLock<HashMap<serenity::UserId, VerificationStatus>>,
+    history_path: PathBuf,
+    history: RwLock<HashMap<serenity::UserId, Vec<HistoryEntry>>>,
+    transcripts_path: PathBuf,
+    transcripts: RwLock<HashMap<serenity::UserId, Vec<Transcript>>>,
+    history_dirty: AtomicBool,
+    transcripts_dirty: AtomicBool,
+    changed: Notify,
+    /// Held by a flush from taking the dirty flags until it's written, so
+    /// another one waits for it rather than returning early
+    flushing: Mutex<()>,
+    /// One lock per member being handled, so their events are processed one
+    /// at a time and in order
+    user_locks: std::sync::Mutex<HashMap<serenity::UserId, Weak<Mutex<()>>>>,
 }
 
 impl VerificationService {
     pub fn new(
-        ckey: Weak<CkeyService>,
+        guild_id: serenity::GuildId,
+        members: Weak<MemberService>,
         config: Weak<ConfigService>,
-        whitelist: Weak<WhitelistService>,
         data_path: &Path,
     ) -> Self {
         Self {
-            path: data_path.join("verification.ron"),
-            ckey,
+            guild_id,
+            members,
             config,
-            whitelist,
-            data: RwLock::new(HashMap::new()),
+            history_path: data_path.join("history.ron"),
+            history: RwLock::new(HashMap::new()),
+            transcripts_path: data_path.join("transcripts.ron"),
+            transcripts: RwLock::new(HashMap::new()),
+            history_dirty: AtomicBool::new(false),
+            transcripts_dirty: AtomicBool::new(false),
+            changed: Notify::new(),
+            flushing: Mutex::new(()),
+            user_locks: std::sync::Mutex::new(HashMap::new()),
         }
     }
 
     pub async fn load(&self) -> Result<(), Error> {
-        let path = self.path.clone();
-        let data = tokio::task::spawn_blocking(move || {
-            let file = match File::open(path) {
-                Ok(file) => file,
-                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
-                Err(e) => return Err(Error::Read(e.into())),
-            };
-            let reader = BufReader::new(file);
-
-            Ok(Some(ron::de::from_reader(reader).map_err(Error::Read)?))
-        })
-        .await
-        .expect("Thread panicked")?;
+        let history = read_file(self.history_path.clone(), &HISTORY_SCHEMA).await?;
+        *self.history.write().await = history.unwrap_or_default();
 
-        *self.data.write().await = match data {
-            Some(data) => data,
-            None => HashMap::new(),
-        };
+        let transcripts = read_file(self.transcripts_path.clone(), &TRANSCRIPTS_SCHEMA).await?;
+        *self.transcripts.write().await = transcripts.unwrap_or_default();
 
         Ok(())
     }
 
-    pub async fn store(&self) -> Result<(), Error> {
-        let path = self.path.clone();
+    async fn store_history(&self) -> Result<(), Error> {
         let value = {
-            let guard = self.data.read().await;
+            let guard = self.history.read().await;
             guard.clone()
         };
 
-        tokio::task::spawn_blocking(move || {
-            let file = File::create(path).map_err(|e| Error::Write(e.into()))?;
-            let mut writer = BufWriter::new(file);
+        write_file(self.history_path.clone(), &HISTORY_SCHEMA, value).await
+    }
 
-            ron::ser::to_writer_pretty(&mut writer, &value, PrettyConfig::default())
-                .map_err(Error::Write)?;
+    async fn store_transcripts(&self) -> Result<(), Error> {
+        let value = {
+            let guard = self.transcripts.read().await;
+            guard.clone()
+        };
 
-            writer.flush().map_err(|e| Error::Write(e.into()))
-        })
-        .await
-        .expect("Thead panicked")
+        write_file(self.transcripts_path.clone(), &TRANSCRIPTS_SCHEMA, value).await
+    }
+
+    fn mark_dirty(&self, flag: &AtomicBool) {
+        flag.store(true, Ordering::Release);
+        self.changed.notify_one();
+    }
+
+    /// Appends an event to the member's timeline
+    pub async fn push_history(
+        &self,
+        id: serenity::UserId,
+        actor: Actor,
+        event: HistoryEvent,
+    ) -> Result<(), Error> {
+        {
+            let mut guard = self.history.write().await;
+            guard.entry(id).or_default().push(HistoryEntry {
+                timestamp: serenity::Timestamp::now(),
+                actor,
+                event,
+            });
+        }
+
+        self.mark_dirty(&self.history_dirty);
+
+        Ok(())
+    }
+
+    pub async fn get_history(&self, id: &serenity::UserId) -> Vec<HistoryEntry> {
+        let guard = self.history.read().await;
+        guard.get(id).cloned().unwrap_or_default()
+    }
+
+    pub async fn push_transcript(
+        &self,
+        id: serenity::UserId,
+        transcript: Transcript,
+    ) -> Result<(), Error> {
+        {
+            let mut guard = self.transcripts.write().await;
+            guard.entry(id).or_default().push(transcript);
+        }
+
+        self.mark_dirty(&self.transcripts_dirty);
+
+        Ok(())
+    }
+
+    /// Completed attempts, oldest first
+    pub async fn get_transcripts(&self, id: &serenity::UserId) -> Vec<Transcript> {
+        let guard = self.transcripts.read().await;
+        guard.get(id).cloned().unwrap_or_default()
+    }
+
+    /// Everyone with verification data
+    pub async fn list(&self) -> Result<Vec<MemberStatus>, Error> {
+        let members = self.members()?.list().await;
+        let history = self.history.read().await;
+
+        Ok(members
+            .into_iter()
+            .filter_map(|(user_id, member)| {
+                // Records from before members were kept together have no timestamps
+                let since = member.status_since.or_else(|| {
+                    history.get(&user_id).and_then(|entries| {
+                        entries
+                            .iter()
+                            .rev()
+                            .find(|entry| entry.event.changes_status())
+                            .map(|entry| entry.timestamp)
+                    })
+                });
+
+                Some(MemberStatus {
+                    user_id,
+                    status: member.status?,
+                    since,
+                })
+            })
+            .collect())
+    }
+
+    /// Statistics over the recorded data, `since` limits the history that's considered
+    pub async fn stats(&self, since: Option<serenity::Timestamp>) -> Result<Stats, Error> {
+        let statuses: HashMap<_, _> = self
+            .members()?
+            .list()
+            .await
+            .into_iter()
+            .filter_map(|(user_id, member)| Some((user_id, member.status?)))
+            .collect();
+        let history = self.history.read().await;
+
+        Ok(Stats::compute(&statuses, &history, since))
     }
 
     pub async fn set_status(
         &self,
         id: serenity::UserId,
         status: &VerificationStatus,
+        actor: Actor,
     ) -> Result<bool, Error> {
-        let result = {
-            let mut guard = self.data.write().await;
-            guard.insert(id, status.clone())
-        };
+        Ok(self
+            .members()?
+            .update(id, actor, |member| {
+                member.status.replace(status.clone()).as_ref() != Some(status)
+            })
+            .await?)
+    }
 
-        match result {
-            None => {
-                self.store().await?;
+    pub async fn get_status(&self, id: &serenity::UserId) -> Option<VerificationStatus> {
+        self.members().ok()?.get_status(id).await
+    }
 
-                Ok(true)
-            }
-            Some(old_status) => {
-                if old_status != *status {
-                    self.store().await?;
+    pub async fn remove(&self, id: &serenity::UserId, actor: Actor) -> Result<bool, Error> {
+        let result = self
+            .members()?
+            .update(*id, actor, |member| member.status.take().is_some())
+            .await?;
 
-                    Ok(true)
-                } else {
-                    Ok(false)
+        if result {
+            self.push_history(*id, actor, HistoryEvent::Cleared).await?;
+        }
+
+        Ok(result)
+    }
+
+    fn config(&self) -> Result<Arc<ConfigService>, Error> {
+        self.config.upgrade().ok_or(Error::Dependency("config"))
+    }
+
+    /// Waits for everything else being done for a member to finish. Locks
+    /// are handed out in the order they're asked for
+    async fn lock(&self, user_id: serenity::UserId) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.user_locks.lock().expect("Lock poisoned");
+            locks.retain(|_, lock| lock.strong_count() > 0);
+
+            match locks.get(&user_id).and_then(Weak::upgrade) {
+                Some(lock) => lock,
+                None => {
+                    let lock = Arc::new(Mutex::new(()));
+                    locks.insert(user_id, Arc::downgrade(&lock));
+                    lock
                 }
             }
-        }
+        };
+
+        lock.lock_owned().await
     }
 
-    pub async fn get_status(&self, id: &serenity::UserId) -> Option<VerificationStatus> {
-        let guard = self.data.read().await;
-        guard.get(id).cloned()
+    fn members(&self) -> Result<Arc<MemberService>, Error> {
+        self.members.upgrade().ok_or(Error::Dependency("members"))
     }
 
-    pub async fn remove(&self, id: &serenity::UserId) -> Result<bool, Error> {
-        let result = {
-            let mut guard = self.data.write().await;
-            guard.remove(id)
+    pub async fn log(&self, sc: &serenity::Context, entry: LogEntry) -> Result<(), AppError> {
+        let target = {
+            let config = self.config()?;
+            let guard = config
+                .guild(self.guild_id)
+                .await
+                .ok_or(Error::NotConfigured)?;
+            guard.log_target(entry.event())
         };
-        if result.is_some() {
-            self.store().await?;
 
-            Ok(true)
-        } else {
-            Ok(false)
+        if let Some((channel_id, mention_role_id)) = target {
+            entry.send(sc, channel_id, mention_role_id).await?;
         }
+
+        Ok(())
     }
 
-    fn config(&self) -> Result<Arc<ConfigService>, Error> {
-        self.config.upgrade().ok_or(Error::Dependency("config"))
+    async fn template_values(
+        &self,
+        sc: &serenity::Context,
+        user: &serenity::User,
+        ckey: Option<&Ckey>,
+    ) -> Result<Values, Error> {
+        let question_count = {
+            let config = self.config()?;
+            let guard = config
+                .guild(self.guild_id)
+                .await
+                .ok_or(Error::NotConfigured)?;
+            guard.questions.len()
+        };
+
+        let mut values = Values::new();
+        values
+            .set(Variable::User, user)
+            .set(Variable::UserName, &user.name)
+            .set(Variable::UserAvatar, user.face())
+            .set(
+                Variable::Guild,
+                self.guild_id
+                    .name(sc)
+                    .unwrap_or_else(|| self.guild_id.to_string()),
+            )
+            .set(Variable::QuestionCount, question_count);
+
+        if let Some(ckey) = ckey {
+            values.set(Variable::Ckey, ckey);
+        }
+
+        Ok(values)
     }
 
-    fn ckey(&self) -> Result<Arc<CkeyService>, Error> {
-        self.ckey.upgrade().ok_or(Error::Dependency("ckey"))
+    async fn verified_role_id(&self) -> Result<serenity::RoleId, Error> {
+        let config = self.config()?;
+        let guard = config
+            .guild(self.guild_id)
+            .await
+            .ok_or(Error::NotConfigured)?;
+
+        Ok(guard.verified_role_id)
     }
 
-    fn whitelist(&self) -> Result<Arc<WhitelistService>, Error> {
-        self.whitelist
-            .upgrade()
-            .ok_or(Error::Dependency("whitelist"))
+    /// Adds the verified role without recording it, retrying if Discord fails
+    async fn add_role(
+        &self,
+        sc: &serenity::Context,
+        member: &serenity::Member,
+    ) -> Result<(), Error> {
+        let role_id = self.verified_role_id().await?;
+
+        retry("grant the verified role", || {
+            sc.http
+                .add_member_role(self.guild_id.0, member.user.id.0, role_id.0, None)
+        })
+        .await
+        .map_err(|e| Error::GrantRole(Box::new(e)))
     }
 
     pub async fn grant_role(
         &self,
         sc: &serenity::Context,
-        member: &mut serenity::Member,
+        member: &serenity::Member,
+        actor: Actor,
     ) -> Result<(), Error> {
-        let verified_role_id = {
-            let config = self.config()?;
-            let guard = config.get().await;
-            guard.verified_role_id
-        };
+        self.add_role(sc, member).await?;
 
-        member
-            .add_role(sc, verified_role_id)
-            .await
-            .map_err(Error::GrantRole)?;
+        self.push_history(member.user.id, actor, HistoryEvent::RoleGranted)
+            .await?;
 
         Ok(())
     }
@@ -233,66 +547,150 @@ impl VerificationService {
         &self,
         sc: &serenity::Context,
         user: &serenity::User,
+        actor: Actor,
+    ) -> Result<serenity::MessageId, Error> {
+        let _guard = self.lock(user.id).await;
+
+        self.greet(sc, user, actor).await
+    }
+
+    async fn greet(
+        &self,
+        sc: &serenity::Context,
+        user: &serenity::User,
+        actor: Actor,
     ) -> Result<serenity::MessageId, Error> {
-        let (greeting_channel_id, greeting_message) = {
+        let (greeting_channel_id, greeting_template, greeting_embed) = {
             let config = self.config()?;
-            let guard = config.get().await;
-            (guard.greeting_channel_id, guard.messages.greeting.clone())
+            let guard = config
+                .guild(self.guild_id)
+                .await
+                .ok_or(Error::NotConfigured)?;
+            (
+                guard.greeting_channel_id,
+                guard.messages.template(MessageKind::Greeting),
+                guard.messages.embed(MessageKind::Greeting).cloned(),
+            )
         };
         let status = self.get_status(&user.id).await;
 
         match status {
-            Some(VerificationStatus::Greeted { greeting_id }) => Ok(greeting_id),
-            Some(VerificationStatus::Pending {
-                form_data: _,
-                form_idx: _,
-            }) => Err(SendGreetingError::AlreadyPending.into()),
+            Some(VerificationStatus::Greeted { greeting_id, .. }) => Ok(greeting_id),
+            Some(VerificationStatus::Pending { .. }) => {
+                Err(SendGreetingError::AlreadyPending.into())
+            }
             Some(VerificationStatus::Verified) => Err(SendGreetingError::AlreadyVerified.into()),
             Some(VerificationStatus::Rejected) => Err(SendGreetingError::AlreadyRejected.into()),
             None => {
+                let values = self.template_values(sc, user, None).await?;
+                let mut greeting_message = greeting_template.render(&values);
+                let greeting_embeds: Vec<_> = greeting_embed
+                    .iter()
+                    .map(|e| render_embed(MessageKind::Greeting, e, &values))
+                    .collect();
+
+                // The newcomer is always pinged, older templates didn't have to do it themselves
+                if !greeting_template.contains(Variable::User) {
+                    greeting_message = format!("{user}: {greeting_message}");
+                }
+
+                let session = component::new_session();
                 let message = greeting_channel_id
                     .send_message(sc, |b| {
                         b.allowed_mentions(|b| b.users([user.id]))
-                            .content(format!("{user}: {greeting_message}"))
+                            .content(greeting_message)
+                            .set_embeds(greeting_embeds)
                             .components(|b| {
                                 b.create_action_row(|b| {
                                     b.create_button(|b| {
-                                        b.custom_id("begin_verification")
-                                            .label("Begin")
-                                            .style(serenity::ButtonStyle::Primary)
+                                        b.custom_id(
+                                            Component::Begin {
+                                                user_id: user.id,
+                                                session,
+                                            }
+                                            .custom_id(),
+                                        )
+                                        .label("Begin")
+                                        .style(serenity::ButtonStyle::Primary)
                                     })
                                 })
                             })
                     })
                     .await
-                    .map_err(SendGreetingError::Discord)?;
+                    .map_err(|e| SendGreetingError::Discord(Box::new(e)))?;
 
                 self.set_status(
                     user.id,
                     &VerificationStatus::Greeted {
                         greeting_id: message.id,
+                        session,
                     },
+                    actor,
                 )
                 .await?;
+                self.push_history(user.id, actor, HistoryEvent::Greeted)
+                    .await?;
 
                 Ok(message.id)
             }
         }
     }
 
+    /// Replaces a member's greeting with a fresh one
+    pub async fn regreet(
+        &self,
+        sc: &serenity::Context,
+        user: &serenity::User,
+        actor: Actor,
+    ) -> Result<serenity::MessageId, Error> {
+        let _guard = self.lock(user.id).await;
+
+        let greeting_channel_id = {
+            let config = self.config()?;
+            let guard = config
+                .guild(self.guild_id)
+                .await
+                .ok_or(Error::NotConfigured)?;
+            guard.greeting_channel_id
+        };
+
+        if let Some(VerificationStatus::Greeted { greeting_id, .. }) =
+            self.get_status(&user.id).await
+        {
+            // The old greeting may have been deleted by hand already
+            if let Err(e) = greeting_channel_id.delete_message(sc, greeting_id).await {
+                log::warn!("Couldn't delete old greeting for {}: {e}", user.id);
+            }
+
+            self.remove(&user.id, actor).await?;
+        }
+
+        self.greet(sc, user, actor).await
+    }
+
     pub async fn render_form(
         &self,
         sc: &serenity::Context,
         interaction: &serenity::MessageComponentInteraction,
         form_idx: usize,
+        session: u64,
     ) -> Result<(), AppError> {
         let questions = {
             let config = self.config()?;
-            let guard = config.get().await;
+            let guard = config
+                .guild(self.guild_id)
+                .await
+                .ok_or(Error::NotConfigured)?;
             guard.questions.clone()
         };
 
-        let current_question = &questions[form_idx];
+        let Some(current_question) = questions.get(form_idx) else {
+            interaction
+                .create_interaction_response(sc, |b| Notice::QuestionsChanged.respond(b))
+                .await?;
+
+            return Ok(());
+        };
 
         let is_ephemeral = interaction
             .message
@@ -313,15 +711,22 @@ impl VerificationService {
                         .components(|b| {
                             b.create_action_row(|b| {
                                 b.create_select_menu(|b| {
-                                    b.custom_id("form_answer")
-                                        .placeholder("Answer")
-                                        .options(|b| {
-                                            for answer in &current_question.answers {
-                                                b.create_option(|b| b.label(answer).value(answer));
-                                            }
-
-                                            b
-                                        })
+                                    b.custom_id(
+                                        Component::Answer {
+                                            user_id: interaction.user.id,
+                                            session,
+                                            form_idx,
+                                        }
+                                        .custom_id(),
+                                    )
+                                    .placeholder("Answer")
+                                    .options(|b| {
+                                        for answer in &current_question.answers {
+                                            b.create_option(|b| b.label(answer).value(answer));
+                                        }
+
+                                        b
+                                    })
                                 })
                             })
                         })
@@ -336,24 +741,374 @@ impl VerificationService {
         Ok(())
     }
 
-    pub async fn reject(&self, user_id: &serenity::UserId) -> Result<(), AppError> {
-        self.set_status(*user_id, &VerificationStatus::Rejected)
+    pub async fn reject(&self, user_id: &serenity::UserId, actor: Actor) -> Result<(), AppError> {
+        self.set_status(*user_id, &VerificationStatus::Rejected, actor)
             .await?;
 
         Ok(())
     }
 
-    pub async fn verify(&self, user_id: &serenity::UserId, ckey: Ckey) -> Result<(), AppError> {
-        self.set_status(*user_id, &VerificationStatus::Verified)
+    /// Verifies a member as a single unit: their record, the verified role and
+    /// the confirmation if there's an interaction to answer, which has to be
+    /// deferred already. Discord calls are retried, and if one still fails
+    /// everything done before it is undone
+    pub async fn verify(
+        &self,
+        sc: &serenity::Context,
+        member: &serenity::Member,
+        ckey: &Ckey,
+        actor: Actor,
+        interaction: Option<&serenity::ModalSubmitInteraction>,
+    ) -> Result<(), Error> {
+        let user_id = member.user.id;
+        let members = self.members()?;
+
+        // Giving the member their ckey takes it away from anyone else, so both
+        // records are kept to put back
+        let mut before = vec![(user_id, members.get(&user_id).await.unwrap_or_default())];
+        if let Some(owner) = members.get_user(ckey).await.filter(|&id| id != user_id) {
+            before.push((owner, members.get(&owner).await.unwrap_or_default()));
+        }
+
+        let ckey_added = members
+            .update(user_id, actor, |record| {
+                record.status = Some(VerificationStatus::Verified);
+                record.add_ckey(ckey, true)
+            })
+            .await?;
+
+        if let Err(e) = self.add_role(sc, member).await {
+            self.rollback(before).await;
+            return Err(e);
+        }
+
+        if let Some(interaction) = interaction {
+            if let Err(e) = self.confirm(sc, interaction, &member.user, ckey).await {
+                let role_id = self.verified_role_id().await?;
+                if let Err(e) = retry("take back the verified role", || {
+                    sc.http
+                        .remove_member_role(self.guild_id.0, user_id.0, role_id.0, None)
+                })
+                .await
+                {
+                    log::error!("Couldn't take back the verified role from {user_id}: {e}");
+                }
+
+                self.rollback(before).await;
+                return Err(e);
+            }
+        }
+
+        if ckey_added {
+            self.push_history(
+                user_id,
+                actor,
+                HistoryEvent::CkeySet {
+                    ckey: ckey.as_str().into(),
+                },
+            )
+            .await?;
+        }
+        self.push_history(user_id, actor, HistoryEvent::RoleGranted)
             .await?;
+
+        Ok(())
+    }
+
+    /// Puts the verified message in the deferred response to the ckey form
+    async fn confirm(
+        &self,
+        sc: &serenity::Context,
+        interaction: &serenity::ModalSubmitInteraction,
+        user: &serenity::User,
+        ckey: &Ckey,
+    ) -> Result<(), Error> {
+        let (verified_template, verified_embed) = {
+            let config = self.config()?;
+            let guard = config
+                .guild(self.guild_id)
+                .await
+                .ok_or(Error::NotConfigured)?;
+            (
+                guard.messages.template(MessageKind::Verified),
+                guard.messages.embed(MessageKind::Verified).cloned(),
+            )
+        };
+
+        let values = self.template_values(sc, user, Some(ckey)).await?;
+        let verified_message = verified_template.render(&values);
+        let verified_embeds: Vec<_> = verified_embed
+            .iter()
+            .map(|e| render_embed(MessageKind::Verified, e, &values))
+            .collect();
+
+        retry("send the verified message", || {
+            interaction.edit_original_interaction_response(sc, |b| {
+                b.content(&verified_message)
+                    .set_embeds(verified_embeds.clone())
+            })
+        })
+        .await
+        .map(|_| ())
+        .map_err(|e| Error::Respond(Box::new(e)))
+    }
+
+    /// Tells staff a verification was undone, it's too late to fail over that
+    async fn report_failure(
+        &self,
+        sc: &serenity::Context,
+        user: &serenity::User,
+        ckey: &Ckey,
+        actor: Actor,
+        error: &Error,
+    ) {
+        log::error!("Couldn't verify {}: {error}", user.id);
+
+        if let Err(e) = self
+            .push_history(
+                user.id,
+                actor,
+                HistoryEvent::Aborted {
+                    error: error.to_string(),
+                },
+            )
+            .await
         {
-            let ckey_service = self.ckey()?;
-            ckey_service.insert(*user_id, &ckey).await?;
+            log::error!("Couldn't record failed verification of {}: {e}", user.id);
+        }
+
+        if let Err(e) = self
+            .log(
+                sc,
+                LogEntry::new(LogEvent::Aborted)
+                    .user(user)
+                    .ckey(Some(ckey))
+                    .field("Error", &error.to_string(), false),
+            )
+            .await
+        {
+            log::error!("Couldn't report failed verification of {}: {e}", user.id);
+        }
+    }
+
+    /// Puts records back the way they were before a verification or rejection
+    /// that couldn't be completed
+    async fn rollback(&self, records: Vec<(serenity::UserId, Member)>) {
+        let members = match self.members() {
+            Ok(members) => members,
+            Err(e) => {
+                log::error!("Couldn't put records back: {e}");
+                return;
+            }
+        };
+
+        for (user_id, before) in records {
+            if let Err(e) = members
+                .update(user_id, Actor::System, |record| *record = before)
+                .await
+            {
+                log::error!("Couldn't put back the record of {user_id}: {e}");
+            }
+        }
+    }
+
+    /// Replaces every ckey of a member with the given one
+    pub async fn set_ckey(
+        &self,
+        user_id: serenity::UserId,
+        ckey: &Ckey,
+        actor: Actor,
+    ) -> Result<bool, AppError> {
+        if !self.members()?.set_ckey(user_id, ckey, actor).await? {
+            return Ok(false);
         }
+        self.push_history(
+            user_id,
+            actor,
+            HistoryEvent::CkeySet {
+                ckey: ckey.as_str().into(),
+            },
+        )
+        .await?;
+
+        Ok(true)
+    }
+
+    pub async fn remove_ckey(
+        &self,
+        user_id: serenity::UserId,
+        ckey: &Ckey,
+        actor: Actor,
+    ) -> Result<bool, AppError> {
+        if !self.members()?.remove_ckey(user_id, ckey, actor).await? {
+            return Ok(false);
+        }
+        self.push_history(
+            user_id,
+            actor,
+            HistoryEvent::CkeyRemoved {
+                ckey: ckey.as_str().into(),
+            },
+        )
+        .await?;
+
+        Ok(true)
+    }
 
+    /// Takes every ckey away from a member, returns whether they had any
+    pub async fn remove_ckeys(
+        &self,
+        user_id: serenity::UserId,
+        actor: Actor,
+    ) -> Result<bool, AppError> {
+        let removed = self.members()?.remove_ckeys(user_id, actor).await?;
+        for mapped in &removed {
+            self.push_history(
+                user_id,
+                actor,
+                HistoryEvent::CkeyRemoved {
+                    ckey: mapped.ckey.clone(),
+                },
+            )
+            .await?;
+        }
+
+        Ok(!removed.is_empty())
+    }
+
+    pub async fn add_ckey(
+        &self,
+        user_id: serenity::UserId,
+        ckey: &Ckey,
+        primary: bool,
+        actor: Actor,
+    ) -> Result<bool, AppError> {
+        if !self
+            .members()?
+            .add_ckey(user_id, ckey, primary, actor)
+            .await?
         {
-            let whitelist_service = self.whitelist()?;
-            whitelist_service.insert(&ckey).await?;
+            return Ok(false);
+        }
+        self.push_history(
+            user_id,
+            actor,
+            HistoryEvent::CkeySet {
+                ckey: ckey.as_str().into(),
+            },
+        )
+        .await?;
+
+        Ok(true)
+    }
+
+    /// Verifies a member on staff's word, skipping the questions
+    pub async fn approve(
+        &self,
+        sc: &serenity::Context,
+        member: &serenity::Member,
+        ckey: Ckey,
+        actor: Actor,
+    ) -> Result<(), AppError> {
+        let _guard = self.lock(member.user.id).await;
+
+        // Staff decide who the ckey belongs to, rather than it changing hands
+        // as a side effect
+        let owner = self.members()?.get_user(&ckey).await;
+        if let Some(owner) = owner.filter(|&id| id != member.user.id) {
+            return Err(Error::CkeyTaken {
+                ckey: ckey.as_str().into(),
+                owner,
+            }
+            .into());
+        }
+
+        if let Err(e) = self.verify(sc, member, &ckey, actor, None).await {
+            self.report_failure(sc, &member.user, &ckey, actor, &e)
+                .await;
+            return Err(e.into());
+        }
+        self.push_history(member.user.id, actor, HistoryEvent::Approved)
+            .await?;
+
+        self.log(
+            sc,
+            LogEntry::new(LogEvent::Approved)
+                .user(&member.user)
+                .ckey(Some(&ckey))
+                .field("By", &actor.to_string(), true),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Rejects a member on staff's word, whatever state they're in
+    pub async fn reject_member(
+        &self,
+        sc: &serenity::Context,
+        user: &serenity::User,
+        reason: Option<String>,
+        actor: Actor,
+    ) -> Result<(), AppError> {
+        let _guard = self.lock(user.id).await;
+
+        let role_id = self.verified_role_id().await?;
+        self.reject_revoking(user.id, actor, || async {
+            let result = retry("take away the verified role", || {
+                sc.http
+                    .remove_member_role(self.guild_id.0, user.id.0, role_id.0, None)
+            })
+            .await;
+
+            match result {
+                // Not in the server, so there's no role to take away
+                Err(ref e) if retry::is_not_found(e) => Ok(()),
+                result => result,
+            }
+        })
+        .await?;
+        self.push_history(
+            user.id,
+            actor,
+            HistoryEvent::Rejected {
+                reason: reason.clone(),
+            },
+        )
+        .await?;
+
+        let mut entry =
+            LogEntry::new(LogEvent::Rejected)
+                .user(user)
+                .field("By", &actor.to_string(), true);
+        if let Some(ref reason) = reason {
+            entry = entry.field("Reason", reason, false);
+        }
+        self.log(sc, entry).await?;
+
+        Ok(())
+    }
+
+    /// Marks a member rejected and takes the verified role away from them with
+    /// `revoke`, putting their record back if that fails so the two agree
+    async fn reject_revoking<F, Fut>(
+        &self,
+        user_id: serenity::UserId,
+        actor: Actor,
+        revoke: F,
+    ) -> Result<(), Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(), serenity::Error>>,
+    {
+        let before = self.members()?.get(&user_id).await.unwrap_or_default();
+
+        self.set_status(user_id, &VerificationStatus::Rejected, actor)
+            .await?;
+
+        if let Err(e) = revoke().await {
+            self.rollback(vec![(user_id, before)]).await;
+            return Err(Error::RevokeRole(Box::new(e)));
         }
 
         Ok(())
@@ -363,17 +1118,21 @@ impl VerificationService {
         &self,
         sc: &serenity::Context,
         interaction: &serenity::MessageComponentInteraction,
-        form_data: &Vec<String>,
+        form_data: &[String],
+        session: u64,
     ) -> Result<(), AppError> {
-        let (questions, rejected_message, ckey_prompt, log_channel_id) = {
+        let (questions, rejected_template, rejected_embed, ckey_prompt) = {
             let config = self.config()?;
-            let guard = config.get().await;
+            let guard = config
+                .guild(self.guild_id)
+                .await
+                .ok_or(Error::NotConfigured)?;
 
             (
                 guard.questions.clone(),
-                guard.messages.rejected.clone(),
+                guard.messages.template(MessageKind::Rejected),
+                guard.messages.embed(MessageKind::Rejected).cloned(),
                 guard.ckey_prompt.clone(),
-                guard.log_channel_id,
             )
         };
 
@@ -383,8 +1142,23 @@ impl VerificationService {
                 .enumerate()
                 .all(|(i, q)| form_data[i] == q.answers[q.correct_answer]);
 
+        self.push_transcript(
+            interaction.user.id,
+            Transcript::new(&questions, form_data, valid),
+        )
+        .await?;
+
         if !valid {
-            self.reject(&interaction.user.id).await?;
+            let actor = Actor::User(interaction.user.id);
+            self.reject(&interaction.user.id, actor).await?;
+            self.push_history(interaction.user.id, actor, HistoryEvent::Failed)
+                .await?;
+
+            let values = self.template_values(sc, &interaction.user, None).await?;
+            let rejected_message = rejected_template.render(&values);
+            let rejected_embeds = rejected_embed
+                .iter()
+                .map(|e| render_embed(MessageKind::Rejected, e, &values));
 
             interaction
                 .create_interaction_response(sc, |b| {
@@ -392,19 +1166,19 @@ impl VerificationService {
                         .interaction_response_data(|b| {
                             b.ephemeral(true)
                                 .content(rejected_message)
+                                .set_embeds(rejected_embeds)
                                 .set_components(serenity::CreateComponents::default())
                         })
                 })
                 .await?;
 
-            log_channel_id
-                .send_message(sc, |b| {
-                    b.content(format!(
-                        "{} sent invalid form answers:\n```json\n{:#?}\n```",
-                        &interaction.user, form_data
-                    ))
-                })
-                .await?;
+            self.log(
+                sc,
+                LogEntry::new(LogEvent::Failed)
+                    .user(&interaction.user)
+                    .answers(&questions, form_data),
+            )
+            .await?;
 
             return Ok(());
         }
@@ -413,103 +1187,109 @@ impl VerificationService {
             .create_interaction_response(sc, |b| {
                 b.kind(serenity::InteractionResponseType::Modal)
                     .interaction_response_data(|b| {
-                        b.custom_id("ckey_modal")
-                            .title("Complete verification")
-                            .components(|b| {
-                                b.create_action_row(|b| {
-                                    b.create_input_text(|b| {
-                                        b.custom_id("ckey")
-                                            .label(ckey_prompt)
-                                            .required(true)
-                                            .style(serenity::InputTextStyle::Short)
-                                    })
+                        b.custom_id(
+                            Component::CkeyForm {
+                                user_id: interaction.user.id,
+                                session,
+                            }
+                            .custom_id(),
+                        )
+                        .title("Complete verification")
+                        .components(|b| {
+                            b.create_action_row(|b| {
+                                b.create_input_text(|b| {
+                                    b.custom_id("ckey")
+                                        .label(ckey_prompt)
+                                        .required(true)
+                                        .style(serenity::InputTextStyle::Short)
                                 })
                             })
+                        })
                     })
             })
             .await?;
 
-        log_channel_id
-            .send_message(sc, |b| {
-                b.content(format!(
-                    "{} sent valid form answers:\n```json\n{:#?}\n```",
-                    &interaction.user, form_data
-                ))
-            })
-            .await?;
+        self.push_history(
+            interaction.user.id,
+            Actor::User(interaction.user.id),
+            HistoryEvent::Passed,
+        )
+        .await?;
+
+        self.log(
+            sc,
+            LogEntry::new(LogEvent::Passed)
+                .user(&interaction.user)
+                .answers(&questions, form_data),
+        )
+        .await?;
 
         Ok(())
     }
 
-    pub async fn on_join<'a>(
+    pub async fn on_join(
         &self,
-        sc: &'a serenity::Context,
-        ctx: FrameworkContext<'a, Data, AppError>,
+        sc: &serenity::Context,
         new_member: &serenity::Member,
     ) -> Result<(), AppError> {
-        let (active_guild_id, log_channel_id) = {
-            let config = ctx.user_data.config.get().await;
-            (config.active_guild_id, config.log_channel_id)
-        };
-
-        if active_guild_id != new_member.guild_id {
-            return Ok(());
-        }
+        let _guard = self.lock(new_member.user.id).await;
 
-        let greeting_result = self.send_greeting(sc, &new_member.user).await;
+        let greeting_result = self.greet(sc, &new_member.user, Actor::System).await;
 
         match greeting_result {
             Ok(_) => Ok(()),
             Err(Error::SendGreeting(SendGreetingError::AlreadyVerified)) => {
-                let ckey = {
-                    let ckey = self.ckey()?;
-                    ckey.get_ckey(&new_member.user.id).await
-                };
+                let ckey = self.members()?.get_ckey(&new_member.user.id).await;
 
                 match ckey {
                     Some(ckey) => {
-                        self.grant_role(sc, &mut new_member.clone()).await?;
-
-                        log_channel_id.send_message(sc, |b| {
-                            b.content(format!("{new_member} (ID: {}, ckey: {ckey}) joined with existing verification, role granted", new_member.user.id))
-                        }).await?
+                        self.grant_role(sc, new_member, Actor::System).await?;
+
+                        self.log(
+                            sc,
+                            LogEntry::new(LogEvent::Rejoined)
+                                .user(&new_member.user)
+                                .ckey(Some(&ckey)),
+                        )
+                        .await?
                     }
                     None => {
-                        self.remove(&new_member.user.id).await?;
-                        self.send_greeting(sc, &new_member.user).await?;
-
-                        log_channel_id.send_message(sc, |b| {
-                            b.content(format!("{new_member} (ID: {}) joined with existing verification, but no ckey", new_member.user.id))
-                        }).await?
+                        self.remove(&new_member.user.id, Actor::System).await?;
+                        self.greet(sc, &new_member.user, Actor::System).await?;
+
+                        self.log(
+                            sc,
+                            LogEntry::new(LogEvent::RejoinedUnverified)
+                                .user(&new_member.user)
+                                .ckey(None)
+                                .field("Reason", "Verified without a ckey mapping", false),
+                        )
+                        .await?
                     }
                 };
 
                 Ok(())
             }
             Err(Error::SendGreeting(SendGreetingError::AlreadyPending)) => {
-                self.remove(&new_member.user.id).await?;
-                self.send_greeting(sc, &new_member.user).await?;
-
-                log_channel_id
-                    .send_message(sc, |b| {
-                        b.content(format!(
-                            "{new_member} (ID: {}) joined with unfinished verification",
-                            new_member.user.id
-                        ))
-                    })
-                    .await?;
+                self.remove(&new_member.user.id, Actor::System).await?;
+                self.greet(sc, &new_member.user, Actor::System).await?;
+
+                self.log(
+                    sc,
+                    LogEntry::new(LogEvent::RejoinedUnverified)
+                        .user(&new_member.user)
+                        .field("Reason", "Unfinished verification", false),
+                )
+                .await?;
 
                 Ok(())
             }
             Err(Error::SendGreeting(SendGreetingError::AlreadyRejected)) => {
-                log_channel_id
-                    .send_message(sc, |b| {
-                        b.content(format!(
-                            "{new_member} (ID: {}) joined with rejected verification",
-                            new_member.user.id
-                        ))
-                    })
-                    .await?;
+                self.log(
+                    sc,
+                    LogEntry::new(LogEvent::RejoinedRejected).user(&new_member.user),
+                )
+                .await?;
 
                 Ok(())
             }
@@ -517,225 +1297,549 @@ impl VerificationService {
         }
     }
 
-    pub async fn on_ban<'a>(
+    pub async fn on_ban(
         &self,
-        sc: &'a serenity::Context,
-        ctx: FrameworkContext<'a, Data, AppError>,
-        guild_id: serenity::GuildId,
-        banned_user: &'a serenity::User,
+        sc: &serenity::Context,
+        banned_user: &serenity::User,
     ) -> Result<(), AppError> {
-        let (active_guild_id, log_channel_id) = {
-            let config = ctx.user_data.config.get().await;
-            (config.active_guild_id, config.log_channel_id)
-        };
+        let _guard = self.lock(banned_user.id).await;
 
-        if active_guild_id != guild_id {
-            return Ok(());
-        }
-
-        self.remove(&banned_user.id).await?;
+        // Unwhitelisted before the status is cleared so the result covers both
+        // derived entries and ones added by hand
+        let members = self.members()?;
+        let ckey = members.get_ckey(&banned_user.id).await;
+        let result = members.unwhitelist(&banned_user.id, Actor::System).await?;
 
-        let ckey = ctx.user_data.ckey.get_ckey(&banned_user.id).await;
+        self.remove(&banned_user.id, Actor::System).await?;
 
         match ckey {
             Some(ckey) => {
-                let result = ctx.user_data.whitelist.remove(&ckey).await?;
-
-                log_channel_id
-                    .send_message(sc, |b| {
-                        b.content(format!(
-                            "`{}` (ID: `{}`, ckey: `{ckey}`) banned{}",
-                            banned_user.name,
-                            banned_user.id,
-                            if result {
-                                " and removed from whitelist"
-                            } else {
-                                ""
-                            }
-                        ))
-                    })
-                    .await?;
+                self.log(
+                    sc,
+                    LogEntry::new(LogEvent::Banned)
+                        .user(banned_user)
+                        .ckey(Some(&ckey))
+                        .field(
+                            "Whitelist",
+                            if result { "Removed" } else { "Not whitelisted" },
+                            true,
+                        ),
+                )
+                .await?;
             }
             None => {
-                log_channel_id
-                    .send_message(sc, |b| {
-                        b.content(format!(
-                            "`{}` (ID: `{}`, no ckey) banned",
-                            banned_user.name, banned_user.id
-                        ))
-                    })
-                    .await?;
+                self.log(
+                    sc,
+                    LogEntry::new(LogEvent::Banned).user(banned_user).ckey(None),
+                )
+                .await?;
             }
         };
 
         Ok(())
     }
 
-    pub async fn on_interaction<'a>(
+    pub async fn on_interaction(
         &self,
-        sc: &'a serenity::Context,
-        ctx: FrameworkContext<'a, Data, AppError>,
-        interaction: &'a serenity::Interaction,
+        sc: &serenity::Context,
+        interaction: &serenity::Interaction,
     ) -> Result<(), AppError> {
-        let (active_guild_id, log_channel_id, questions_len, verified_message) = {
-            let config = ctx.user_data.config.get().await;
-            (
-                config.active_guild_id,
-                config.log_channel_id,
-                config.questions.len(),
-                config.messages.verified.clone(),
-            )
+        // A double click or quick answers arrive as separate events, handling
+        // them at the same time would let one overwrite the other's progress
+        let user_id = match *interaction {
+            serenity::Interaction::MessageComponent(ref interaction) => interaction.user.id,
+            serenity::Interaction::ModalSubmit(ref interaction) => interaction.user.id,
+            _ => return Ok(()),
         };
+        let _guard = self.lock(user_id).await;
 
         match *interaction {
             serenity::Interaction::MessageComponent(ref interaction) => {
-                if interaction.guild_id != Some(active_guild_id) {
-                    return Ok(());
-                }
+                self.on_component(sc, interaction).await
+            }
+            serenity::Interaction::ModalSubmit(ref interaction) => {
+                self.on_ckey_form(sc, interaction).await
+            }
+            _ => Ok(()),
+        }
+    }
 
-                match interaction.data.custom_id.as_str() {
-                    "begin_verification" => {
-                        let user = &interaction.user;
+    /// What to tell a member whose interaction doesn't fit their current state
+    fn notice(user_id: serenity::UserId, status: Option<&VerificationStatus>) -> Notice {
+        match status {
+            Some(VerificationStatus::Greeted { .. }) => Notice::Replaced,
+            Some(&VerificationStatus::Pending { session, .. }) => {
+                Notice::Expired { user_id, session }
+            }
+            Some(VerificationStatus::Verified) => Notice::AlreadyVerified,
+            Some(VerificationStatus::Rejected) => Notice::Rejected,
+            None => Notice::NotStarted,
+        }
+    }
 
-                        let Some(VerificationStatus::Greeted { greeting_id }) =
-                            self.get_status(&user.id).await
-                        else {
-                            return Ok(());
-                        };
+    async fn on_component(
+        &self,
+        sc: &serenity::Context,
+        interaction: &serenity::MessageComponentInteraction,
+    ) -> Result<(), AppError> {
+        let Some(component) = Component::parse(&interaction.data.custom_id) else {
+            return Ok(());
+        };
+        let user = &interaction.user;
 
-                        if interaction.message.id != greeting_id {
-                            return Ok(());
-                        }
+        if component.user_id().is_some_and(|id| id != user.id) {
+            interaction
+                .create_interaction_response(sc, |b| Notice::NotForYou.respond(b))
+                .await?;
 
-                        if questions_len == 0 {
-                            self.validate_form(sc, interaction, &Vec::new()).await?;
+            return Ok(());
+        }
 
-                            return Ok(());
-                        }
+        let status = self.get_status(&user.id).await;
 
-                        self.render_form(sc, interaction, 0).await?;
+        match (component, &status) {
+            (
+                Component::Begin { session, .. },
+                &Some(VerificationStatus::Greeted {
+                    session: current, ..
+                }),
+            ) if session == current => self.begin(sc, interaction, current).await,
+            // Greetings from before custom IDs named the member can only be
+            // matched by message
+            (
+                Component::LegacyBegin,
+                &Some(VerificationStatus::Greeted {
+                    greeting_id,
+                    session,
+                }),
+            ) if interaction.message.id == greeting_id => {
+                self.begin(sc, interaction, session).await
+            }
+            (Component::LegacyBegin, Some(VerificationStatus::Greeted { .. })) => {
+                interaction
+                    .create_interaction_response(sc, |b| Notice::NotForYou.respond(b))
+                    .await?;
 
-                        let new_status = VerificationStatus::Pending {
-                            form_data: Vec::new(),
-                            form_idx: 0,
-                        };
+                Ok(())
+            }
+            // A second click on the greeting while the first one is handled
+            (
+                Component::Begin { session, .. } | Component::Resume { session, .. },
+                &Some(VerificationStatus::Pending {
+                    form_idx,
+                    session: current,
+                    ..
+                }),
+            ) if session == current => self.render_form(sc, interaction, form_idx, current).await,
+            (
+                Component::Answer {
+                    session, form_idx, ..
+                },
+                Some(VerificationStatus::Pending {
+                    form_data,
+                    form_idx: current_idx,
+                    session: current,
+                }),
+            ) if session == *current && form_idx == *current_idx => {
+                self.answer(sc, interaction, form_data.clone(), form_idx, session)
+                    .await
+            }
+            (_, status) => {
+                let notice = Self::notice(user.id, status.as_ref());
+                interaction
+                    .create_interaction_response(sc, |b| notice.respond(b))
+                    .await?;
 
-                        self.set_status(user.id, &new_status).await?;
+                Ok(())
+            }
+        }
+    }
 
-                        log_channel_id
-                            .send_message(sc, |b| b.content(format!("{user} started verification")))
-                            .await?;
+    async fn questions(&self) -> Result<Vec<Question>, Error> {
+        let config = self.config()?;
+        let guard = config
+            .guild(self.guild_id)
+            .await
+            .ok_or(Error::NotConfigured)?;
 
-                        Ok(())
-                    }
-                    "form_answer" => {
-                        let user = &interaction.user;
+        Ok(guard.questions.clone())
+    }
 
-                        let Some(VerificationStatus::Pending {
-                            mut form_data,
-                            form_idx,
-                        }) = self.get_status(&user.id).await
-                        else {
-                            return Ok(());
-                        };
+    async fn begin(
+        &self,
+        sc: &serenity::Context,
+        interaction: &serenity::MessageComponentInteraction,
+        session: u64,
+    ) -> Result<(), AppError> {
+        let user = &interaction.user;
 
-                        if interaction.data.values.len() != 1 {
-                            return Ok(());
-                        }
+        if self.questions().await?.is_empty() {
+            self.validate_form(sc, interaction, &[], session).await?;
 
-                        form_data.push(interaction.data.values[0].clone());
+            return Ok(());
+        }
 
-                        let form_idx = form_idx + 1;
+        self.render_form(sc, interaction, 0, session).await?;
 
-                        if form_idx >= questions_len {
-                            self.validate_form(sc, interaction, &form_data).await?;
+        let new_status = VerificationStatus::Pending {
+            form_data: Vec::new(),
+            form_idx: 0,
+            session,
+        };
 
-                            return Ok(());
-                        }
+        self.set_status(user.id, &new_status, Actor::User(user.id))
+            .await?;
 
-                        self.render_form(sc, interaction, form_idx).await?;
+        self.push_history(user.id, Actor::User(user.id), HistoryEvent::Started)
+            .await?;
 
-                        let new_status = VerificationStatus::Pending {
-                            form_data,
-                            form_idx,
-                        };
+        self.log(sc, LogEntry::new(LogEvent::Started).user(user))
+            .await?;
 
-                        self.set_status(user.id, &new_status).await?;
+        Ok(())
+    }
 
-                        Ok(())
-                    }
-                    _ => Ok(()),
+    async fn answer(
+        &self,
+        sc: &serenity::Context,
+        interaction: &serenity::MessageComponentInteraction,
+        mut form_data: Vec<String>,
+        form_idx: usize,
+        session: u64,
+    ) -> Result<(), AppError> {
+        let user = &interaction.user;
+        let questions = self.questions().await?;
+
+        let (Some(question), [answer]) = (questions.get(form_idx), &interaction.data.values[..])
+        else {
+            interaction
+                .create_interaction_response(sc, |b| Notice::QuestionsChanged.respond(b))
+                .await?;
+
+            return Ok(());
+        };
+
+        self.push_history(
+            user.id,
+            Actor::User(user.id),
+            HistoryEvent::Answered {
+                question_id: question.id.clone(),
+                correct: *answer == question.answers[question.correct_answer],
+                answer: answer.clone(),
+            },
+        )
+        .await?;
+
+        form_data.push(answer.clone());
+
+        let form_idx = form_idx + 1;
+
+        if form_idx >= questions.len() {
+            self.validate_form(sc, interaction, &form_data, session)
+                .await?;
+
+            return Ok(());
+        }
+
+        self.render_form(sc, interaction, form_idx, session).await?;
+
+        let new_status = VerificationStatus::Pending {
+            form_data,
+            form_idx,
+            session,
+        };
+
+        self.set_status(user.id, &new_status, Actor::User(user.id))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn on_ckey_form(
+        &self,
+        sc: &serenity::Context,
+        interaction: &serenity::ModalSubmitInteraction,
+    ) -> Result<(), AppError> {
+        let Some(component) = Component::parse(&interaction.data.custom_id) else {
+            return Ok(());
+        };
+        let Some(ref member) = interaction.member else {
+            return Ok(());
+        };
+
+        if component.user_id().is_some_and(|id| id != member.user.id) {
+            interaction
+                .create_interaction_response(sc, |b| Notice::NotForYou.respond(b))
+                .await?;
+
+            return Ok(());
+        }
+
+        let status = self.get_status(&member.user.id).await;
+        let current = match status {
+            Some(
+                VerificationStatus::Greeted { session, .. }
+                | VerificationStatus::Pending { session, .. },
+            ) => Some(session),
+            _ => None,
+        };
+
+        let value = match (component, &interaction.data.components[..]) {
+            (Component::CkeyForm { session, .. }, [row]) if current == Some(session) => {
+                match row.components[..] {
+                    [serenity::ActionRowComponent::InputText(serenity::InputText {
+                        ref custom_id,
+                        ref value,
+                        ..
+                    })] if custom_id == "ckey" => Some(value),
+                    _ => None,
                 }
             }
-            serenity::Interaction::ModalSubmit(ref interaction) => {
-                if interaction.guild_id != Some(active_guild_id) {
-                    return Ok(());
-                }
+            _ => None,
+        };
 
-                match interaction.data.custom_id.as_str() {
-                    "ckey_modal" => {
-                        let Some(ref member) = interaction.member else {
-                            return Ok(());
-                        };
-
-                        let status = self.get_status(&member.user.id).await;
-
-                        match status {
-                            Some(
-                                VerificationStatus::Greeted { greeting_id: _ }
-                                | VerificationStatus::Pending {
-                                    form_data: _,
-                                    form_idx: _,
-                                },
-                            ) => (),
-                            _ => return Ok(()),
-                        };
-
-                        if interaction.data.components.len() != 1
-                            && interaction.data.components[0].components.len() != 1
-                        {
-                            return Ok(());
-                        }
-
-                        let serenity::ActionRowComponent::InputText(serenity::InputText {
-                            kind: _,
-                            custom_id,
-                            value,
-                        }) = &interaction.data.components[0].components[0]
-                        else {
-                            return Ok(());
-                        };
-
-                        if custom_id != "ckey" {
-                            return Ok(());
-                        }
-
-                        let ckey = Ckey::from(value);
-
-                        self.verify(&member.user.id, ckey.clone()).await?;
-                        self.grant_role(sc, &mut member.clone()).await?;
-
-                        interaction
-                            .create_interaction_response(sc, |b| {
-                                b.kind(serenity::InteractionResponseType::ChannelMessageWithSource)
-                                    .interaction_response_data(|b| {
-                                        b.ephemeral(true).content(verified_message)
-                                    })
-                            })
-                            .await?;
+        let Some(value) = value else {
+            let notice = Self::notice(member.user.id, status.as_ref());
+            interaction
+                .create_interaction_response(sc, |b| notice.respond(b))
+                .await?;
 
-                        log_channel_id
-                            .send_message(sc, |b| {
-                                b.content(format!("{member} is now verified for ckey `{ckey}`"))
-                            })
-                            .await?;
+            return Ok(());
+        };
 
-                        Ok(())
-                    }
-                    _ => Ok(()),
-                }
+        let ckey = Ckey::from(value);
+
+        // Left for staff to sort out, the member stays where they are
+        let owner = self.members()?.get_user(&ckey).await;
+        if let Some(owner) = owner.filter(|&id| id != member.user.id) {
+            interaction
+                .create_interaction_response(sc, |b| Notice::CkeyTaken.respond(b))
+                .await?;
+            self.log(
+                sc,
+                LogEntry::new(LogEvent::CkeyConflict)
+                    .user(&member.user)
+                    .ckey(Some(&ckey))
+                    .field("Mapped to", &format!("<@{owner}> `{owner}`"), true),
+            )
+            .await?;
+
+            return Ok(());
+        }
+
+        // Granting the role with retries can take longer than Discord waits for
+        // a response. Answering only once, a retried response could fail just
+        // because an earlier attempt got through
+        interaction.defer_ephemeral(sc).await?;
+
+        let actor = Actor::User(member.user.id);
+        if let Err(e) = self
+            .verify(sc, member, &ckey, actor, Some(interaction))
+            .await
+        {
+            self.report_failure(sc, &member.user, &ckey, actor, &e)
+                .await;
+            apologize(sc, interaction).await;
+
+            return Err(e.into());
+        }
+
+        self.log(
+            sc,
+            LogEntry::new(LogEvent::Verified)
+                .user(&member.user)
+                .ckey(Some(&ckey)),
+        )
+        .await?;
+
+        Ok(())
+    }
+}
+
+impl WriteBehind for VerificationService {
+    fn changed(&self) -> &Notify {
+        &self.changed
+    }
+
+    async fn flush(&self) -> Result<(), super::Error> {
+        let _flushing = self.flushing.lock().await;
+
+        if self.history_dirty.swap(false, Ordering::AcqRel) {
+            if let Err(e) = self.store_history().await {
+                self.history_dirty.store(true, Ordering::Release);
+                return Err(e.into());
             }
-            _ => Ok(()),
         }
+
+        if self.transcripts_dirty.swap(false, Ordering::AcqRel) {
+            if let Err(e) = self.store_transcripts().await {
+                self.transcripts_dirty.store(true, Ordering::Release);
+                return Err(e.into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        app::services::{audit::AuditService, storage, WhitelistService},
+        config::StorageBackend,
+    };
+
+    /// Member and verification services of one guild, on a directory of
+    /// their own
+    struct Fixture {
+        dir: PathBuf,
+        whitelist: Arc<WhitelistService>,
+        members: Arc<MemberService>,
+        verification: VerificationService,
+    }
+
+    impl Fixture {
+        async fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir()
+                .join(format!("nightstation-verify-{}-{name}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("whitelist.txt"), "").unwrap();
+
+            let guild_id = serenity::GuildId(1);
+            let audit = Arc::new(AuditService::new(&dir));
+            let whitelist = Arc::new(WhitelistService::new(
+                &dir.join("whitelist.txt"),
+                audit.clone(),
+            ));
+            whitelist.load().await.unwrap();
+            let members = Arc::new(MemberService::new(
+                guild_id,
+                audit,
+                Arc::downgrade(&whitelist),
+                storage::open(StorageBackend::Ron, &dir).unwrap(),
+            ));
+            members.load().await.unwrap();
+            let verification =
+                VerificationService::new(guild_id, Arc::downgrade(&members), Weak::new(), &dir);
+
+            Self {
+                dir,
+                whitelist,
+                members,
+                verification,
+            }
+        }
+
+        async fn verified(&self, user_id: serenity::UserId, ckey: &str) {
+            self.members
+                .update(user_id, Actor::System, |member| {
+                    member.status = Some(VerificationStatus::Verified);
+                    member.add_ckey(&Ckey::from(ckey), true);
+                })
+                .await
+                .unwrap();
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejecting_verified_member_takes_role_away() {
+        let fixture = Fixture::new("reject").await;
+        let user_id = serenity::UserId(2);
+        fixture.verified(user_id, "alice").await;
+
+        let mut revoked = false;
+        fixture
+            .verification
+            .reject_revoking(user_id, Actor::System, || async {
+                revoked = true;
+                Ok(())
+            })
+            .await
+            .unwrap();
+
+        assert!(revoked);
+        assert_eq!(
+            fixture.verification.get_status(&user_id).await,
+            Some(VerificationStatus::Rejected)
+        );
+        assert!(fixture.whitelist.list().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn failing_to_take_role_away_keeps_member_verified() {
+        let fixture = Fixture::new("reject-failed").await;
+        let user_id = serenity::UserId(2);
+        fixture.verified(user_id, "alice").await;
+
+        let result = fixture
+            .verification
+            .reject_revoking(user_id, Actor::System, || async {
+                Err(serenity::Error::Other("unavailable"))
+            })
+            .await;
+
+        assert!(matches!(result, Err(Error::RevokeRole(_))));
+        assert_eq!(
+            fixture.verification.get_status(&user_id).await,
+            Some(VerificationStatus::Verified)
+        );
+        assert_eq!(fixture.whitelist.list().await, ["alice"]);
+    }
+
+    #[tokio::test]
+    async fn removing_ckeys_is_recorded() {
+        let fixture = Fixture::new("remove-ckeys").await;
+        let user_id = serenity::UserId(2);
+        fixture.verified(user_id, "alice").await;
+        fixture
+            .verification
+            .add_ckey(user_id, &Ckey::from("bob"), false, Actor::System)
+            .await
+            .unwrap();
+
+        let removed = fixture
+            .verification
+            .remove_ckey(user_id, &Ckey::from("bob"), Actor::System)
+            .await
+            .unwrap();
+        assert!(removed);
+        assert!(fixture
+            .verification
+            .remove_ckeys(user_id, Actor::System)
+            .await
+            .unwrap());
+        assert!(!fixture
+            .verification
+            .remove_ckeys(user_id, Actor::System)
+            .await
+            .unwrap());
+
+        let events: Vec<_> = fixture
+            .verification
+            .get_history(&user_id)
+            .await
+            .into_iter()
+            .map(|entry| entry.event)
+            .collect();
+        assert_eq!(
+            events,
+            [
+                HistoryEvent::CkeySet { ckey: "bob".into() },
+                HistoryEvent::CkeyRemoved { ckey: "bob".into() },
+                HistoryEvent::CkeyRemoved {
+                    ckey: "alice".into()
+                },
+            ]
+        );
     }
 }
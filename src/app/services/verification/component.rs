@@ -0,0 +1,229 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+use poise::serenity_prelude as serenity;
+
+/// Random ID for a new verification session, it only has to differ from the
+/// member's earlier ones
+pub fn new_session() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// A button, menu or form the bot sent during verification. Its custom ID
+/// carries the member it's for and the session it belongs to, which starts
+/// when they're greeted, so stale ones can be recognized
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Component {
+    Begin {
+        user_id: serenity::UserId,
+        session: u64,
+    },
+    Answer {
+        user_id: serenity::UserId,
+        session: u64,
+        form_idx: usize,
+    },
+    Resume {
+        user_id: serenity::UserId,
+        session: u64,
+    },
+    CkeyForm {
+        user_id: serenity::UserId,
+        session: u64,
+    },
+    /// Greeting button from before custom IDs named the member
+    LegacyBegin,
+    /// Question or form from before custom IDs named the member
+    Legacy,
+}
+
+impl Component {
+    pub fn parse(custom_id: &str) -> Option<Self> {
+        let mut parts = custom_id.split(':');
+        let kind = parts.next()?;
+
+        match kind {
+            "begin_verification" if custom_id == kind => return Some(Self::LegacyBegin),
+            "form_answer" | "ckey_modal" if custom_id == kind => return Some(Self::Legacy),
+            _ => (),
+        }
+
+        let user_id = serenity::UserId(parts.next()?.parse().ok()?);
+        let session = parts.next()?.parse().ok()?;
+
+        let component = match kind {
+            "begin_verification" => Self::Begin { user_id, session },
+            "form_answer" => Self::Answer {
+                user_id,
+                session,
+                form_idx: parts.next()?.parse().ok()?,
+            },
+            "resume_verification" => Self::Resume { user_id, session },
+            "ckey_modal" => Self::CkeyForm { user_id, session },
+            _ => return None,
+        };
+
+        parts.next().is_none().then_some(component)
+    }
+
+    pub fn custom_id(&self) -> String {
+        match *self {
+            Self::Begin { user_id, session } => format!("begin_verification:{user_id}:{session}"),
+            Self::Answer {
+                user_id,
+                session,
+                form_idx,
+            } => format!("form_answer:{user_id}:{session}:{form_idx}"),
+            Self::Resume { user_id, session } => {
+                format!("resume_verification:{user_id}:{session}")
+            }
+            Self::CkeyForm { user_id, session } => format!("ckey_modal:{user_id}:{session}"),
+            Self::LegacyBegin => "begin_verification".into(),
+            Self::Legacy => "form_answer".into(),
+        }
+    }
+
+    /// Member the component was sent to, unknown for legacy ones
+    pub fn user_id(&self) -> Option<serenity::UserId> {
+        match *self {
+            Self::Begin { user_id, .. }
+            | Self::Answer { user_id, .. }
+            | Self::Resume { user_id, .. }
+            | Self::CkeyForm { user_id, .. } => Some(user_id),
+            Self::LegacyBegin | Self::Legacy => None,
+        }
+    }
+}
+
+/// Why an interaction wasn't acted on, told to whoever sent it instead of
+/// leaving Discord to say it failed
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Notice {
+    NotForYou,
+    Replaced,
+    Expired {
+        user_id: serenity::UserId,
+        session: u64,
+    },
+    QuestionsChanged,
+    AlreadyVerified,
+    Rejected,
+    NotStarted,
+    CkeyTaken,
+}
+
+impl Notice {
+    fn content(&self) -> &'static str {
+        match *self {
+            Self::NotForYou => {
+                "This greeting isn't for you, wait for your own or ask staff for one"
+            }
+            Self::Replaced => "This greeting was replaced by a newer one, use that one instead",
+            Self::Expired { .. } => {
+                "Your session expired, press Resume to continue where you left off"
+            }
+            Self::QuestionsChanged => {
+                "The questions changed since you started, ask staff for a new greeting"
+            }
+            Self::AlreadyVerified => "You're already verified",
+            Self::Rejected => {
+                "Your verification was rejected, ask staff if you think that's a mistake"
+            }
+            Self::NotStarted => {
+                "You don't have a verification in progress, ask staff for a new greeting"
+            }
+            Self::CkeyTaken => {
+                "That ckey belongs to another Discord account, staff were told and will \
+                get back to you. If it was a typo, enter your ckey again"
+            }
+        }
+    }
+
+    /// Ephemeral response with the explanation, and a way back in if there is one
+    pub fn respond<'a, 'b>(
+        &self,
+        b: &'a mut serenity::CreateInteractionResponse<'b>,
+    ) -> &'a mut serenity::CreateInteractionResponse<'b> {
+        b.kind(serenity::InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|b| {
+                b.ephemeral(true).content(self.content());
+
+                if let Self::Expired { user_id, session } = *self {
+                    b.components(|b| {
+                        b.create_action_row(|b| {
+                            b.create_button(|b| {
+                                b.custom_id(Component::Resume { user_id, session }.custom_id())
+                                    .label("Resume")
+                                    .style(serenity::ButtonStyle::Primary)
+                            })
+                        })
+                    });
+                }
+
+                b
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn custom_ids_round_trip() {
+        let user_id = serenity::UserId(42);
+        let components = [
+            Component::Begin {
+                user_id,
+                session: 7,
+            },
+            Component::Answer {
+                user_id,
+                session: 7,
+                form_idx: 2,
+            },
+            Component::Resume {
+                user_id,
+                session: 7,
+            },
+            Component::CkeyForm {
+                user_id,
+                session: u64::MAX,
+            },
+        ];
+
+        for component in components {
+            assert_eq!(Component::parse(&component.custom_id()), Some(component));
+            assert_eq!(component.user_id(), Some(user_id));
+        }
+    }
+
+    #[test]
+    fn recognizes_legacy_ids() {
+        assert_eq!(
+            Component::parse("begin_verification"),
+            Some(Component::LegacyBegin)
+        );
+        assert_eq!(Component::parse("form_answer"), Some(Component::Legacy));
+        assert_eq!(Component::parse("ckey_modal"), Some(Component::Legacy));
+        assert_eq!(Component::Legacy.user_id(), None);
+    }
+
+    #[test]
+    fn rejects_malformed_ids() {
+        for custom_id in [
+            "",
+            "resume_verification",
+            "unknown:1:2",
+            "begin_verification:1",
+            "begin_verification:x:2",
+            "begin_verification:1:2:3",
+            "form_answer:1:2",
+            "form_answer:1:2:x",
+        ] {
+            assert_eq!(Component::parse(custom_id), None, "{custom_id}");
+        }
+    }
+}
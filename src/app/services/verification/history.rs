@@ -0,0 +1,107 @@
+use std::fmt;
+
+use poise::serenity_prelude as serenity;
+use serde::{Deserialize, Serialize};
+
+use crate::app::services::Actor;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum HistoryEvent {
+    Greeted,
+    Started,
+    Answered {
+        question_id: String,
+        answer: String,
+        correct: bool,
+    },
+    Passed,
+    Failed,
+    CkeySet {
+        ckey: String,
+    },
+    CkeyRemoved {
+        ckey: String,
+    },
+    RoleGranted,
+    Cleared,
+    Approved,
+    Rejected {
+        reason: Option<String>,
+    },
+    Aborted {
+        error: String,
+    },
+}
+
+impl HistoryEvent {
+    /// Whether the event moves the member to a new verification state
+    pub fn changes_status(&self) -> bool {
+        matches!(
+            *self,
+            HistoryEvent::Greeted
+                | HistoryEvent::Started
+                | HistoryEvent::Failed
+                | HistoryEvent::CkeySet { .. }
+                | HistoryEvent::CkeyRemoved { .. }
+                | HistoryEvent::Cleared
+                | HistoryEvent::Approved
+                | HistoryEvent::Rejected { .. }
+        )
+    }
+}
+
+impl fmt::Display for HistoryEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            HistoryEvent::Greeted => write!(f, "Greeted"),
+            HistoryEvent::Started => write!(f, "Started verification"),
+            HistoryEvent::Answered {
+                ref question_id,
+                ref answer,
+                correct,
+            } => write!(
+                f,
+                "{} Answered `{question_id}` with `{answer}`",
+                if correct { "✅" } else { "❌" }
+            ),
+            HistoryEvent::Passed => write!(f, "Passed the questions"),
+            HistoryEvent::Failed => write!(f, "Failed the questions"),
+            HistoryEvent::CkeySet { ref ckey } => write!(f, "Ckey set to `{ckey}`"),
+            HistoryEvent::CkeyRemoved { ref ckey } => write!(f, "Ckey `{ckey}` removed"),
+            HistoryEvent::RoleGranted => write!(f, "Verified role granted"),
+            HistoryEvent::Cleared => write!(f, "Verification cleared"),
+            HistoryEvent::Approved => write!(f, "Approved"),
+            HistoryEvent::Rejected {
+                reason: Some(ref reason),
+            } => {
+                write!(f, "Rejected: {reason}")
+            }
+            HistoryEvent::Rejected { reason: None } => write!(f, "Rejected"),
+            HistoryEvent::Aborted { ref error } => {
+                write!(
+                    f,
+                    "Verification couldn't be completed and was undone: {error}"
+                )
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryEntry {
+    pub timestamp: serenity::Timestamp,
+    pub actor: Actor,
+    pub event: HistoryEvent,
+}
+
+impl HistoryEntry {
+    /// Timeline line, naming the actor only when it's staff acting on the member
+    pub fn describe(&self, user_id: serenity::UserId) -> String {
+        let time = format!("<t:{}:f>", self.timestamp.unix_timestamp());
+
+        match self.actor {
+            Actor::User(id) if id != user_id => format!("{time} {} by <@{id}>", self.event),
+            _ => format!("{time} {}", self.event),
+        }
+    }
+}
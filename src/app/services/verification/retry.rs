@@ -0,0 +1,61 @@
+use std::{future::Future, time::Duration};
+
+use poise::serenity_prelude as serenity;
+
+/// Attempts made at a Discord call before giving up
+const ATTEMPTS: u32 = 3;
+/// Wait before the first retry, doubled after every failed attempt
+const BACKOFF: Duration = Duration::from_millis(500);
+
+/// Failures that may go away on their own, unlike missing permissions or a
+/// deleted member
+fn is_transient(e: &serenity::Error) -> bool {
+    match *e {
+        serenity::Error::Http(ref e) => match **e {
+            serenity::HttpError::UnsuccessfulRequest(ref response) => {
+                response.status_code.is_server_error()
+                    || response.status_code == serenity::StatusCode::TOO_MANY_REQUESTS
+            }
+            serenity::HttpError::Request(_) => true,
+            _ => false,
+        },
+        serenity::Error::Io(_) => true,
+        _ => false,
+    }
+}
+
+/// Whether Discord doesn't know what the call refers to, like a member that
+/// left the server
+pub fn is_not_found(e: &serenity::Error) -> bool {
+    match *e {
+        serenity::Error::Http(ref e) => matches!(
+            **e,
+            serenity::HttpError::UnsuccessfulRequest(ref response)
+                if response.status_code == serenity::StatusCode::NOT_FOUND
+        ),
+        _ => false,
+    }
+}
+
+/// Runs a Discord call, retrying transient failures with exponential backoff
+pub async fn retry<T, F, Fut>(what: &str, mut f: F) -> Result<T, serenity::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, serenity::Error>>,
+{
+    let mut delay = BACKOFF;
+    let mut attempt = 1;
+
+    loop {
+        match f().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < ATTEMPTS && is_transient(&e) => {
+                log::warn!("Couldn't {what}, attempt {attempt} of {ATTEMPTS}: {e}");
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::Duration,
+};
+
+use poise::serenity_prelude as serenity;
+
+use super::{HistoryEntry, HistoryEvent, VerificationStatus};
+
+#[derive(Default, Debug)]
+pub struct QuestionStats {
+    pub answered: usize,
+    pub failed: usize,
+}
+
+impl QuestionStats {
+    pub fn failure_rate(&self) -> Option<f64> {
+        rate(self.failed, self.answered)
+    }
+}
+
+/// Verification numbers, everything except the state counts only covers the window
+#[derive(Default, Debug)]
+pub struct Stats {
+    pub greeted: usize,
+    pub pending: usize,
+    pub verified: usize,
+    pub rejected: usize,
+    pub window_greeted: usize,
+    pub window_started: usize,
+    pub window_passed: usize,
+    pub window_failed: usize,
+    /// Members greeted in the window that passed or failed, each counted once
+    pub window_greeted_completed: usize,
+    pub median_completion: Option<Duration>,
+    pub questions: BTreeMap<String, QuestionStats>,
+}
+
+fn rate(part: usize, total: usize) -> Option<f64> {
+    (total > 0).then(|| part as f64 / total as f64)
+}
+
+impl Stats {
+    pub fn compute(
+        statuses: &HashMap<serenity::UserId, VerificationStatus>,
+        history: &HashMap<serenity::UserId, Vec<HistoryEntry>>,
+        since: Option<serenity::Timestamp>,
+    ) -> Self {
+        let mut stats = Stats::default();
+
+        for status in statuses.values() {
+            match *status {
+                VerificationStatus::Greeted { .. } => stats.greeted += 1,
+                VerificationStatus::Pending { .. } => stats.pending += 1,
+                VerificationStatus::Verified => stats.verified += 1,
+                VerificationStatus::Rejected => stats.rejected += 1,
+            }
+        }
+
+        let mut greeted = HashSet::new();
+        let mut started = HashSet::new();
+        let mut completed = HashSet::new();
+        let mut completion_times = Vec::new();
+
+        for (user_id, entries) in history {
+            let mut last_greeted = None;
+
+            for entry in entries {
+                let in_window = since.is_none_or(|since| entry.timestamp >= since);
+
+                match entry.event {
+                    HistoryEvent::Greeted => {
+                        last_greeted = Some(entry.timestamp);
+                        if in_window {
+                            greeted.insert(*user_id);
+                        }
+                    }
+                    HistoryEvent::Started if in_window => {
+                        started.insert(*user_id);
+                    }
+                    HistoryEvent::Passed | HistoryEvent::Failed if in_window => {
+                        if entry.event == HistoryEvent::Passed {
+                            stats.window_passed += 1;
+                        } else {
+                            stats.window_failed += 1;
+                        }
+                        completed.insert(*user_id);
+
+                        if let Some(greeted_at) = last_greeted.take() {
+                            let secs =
+                                entry.timestamp.unix_timestamp() - greeted_at.unix_timestamp();
+                            completion_times.push(Duration::from_secs(secs.max(0) as u64));
+                        }
+                    }
+                    HistoryEvent::Answered {
+                        ref question_id,
+                        correct,
+                        ..
+                    } if in_window => {
+                        let question = stats.questions.entry(question_id.clone()).or_default();
+                        question.answered += 1;
+                        if !correct {
+                            question.failed += 1;
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        stats.window_greeted = greeted.len();
+        stats.window_started = started.len();
+        stats.window_greeted_completed = completed.intersection(&greeted).count();
+
+        completion_times.sort();
+        stats.median_completion = match completion_times.len() {
+            0 => None,
+            n if n % 2 == 1 => Some(completion_times[n / 2]),
+            n => Some((completion_times[n / 2 - 1] + completion_times[n / 2]) / 2),
+        };
+
+        stats
+    }
+
+    pub fn window_completed(&self) -> usize {
+        self.window_passed + self.window_failed
+    }
+
+    /// Share of greeted members that got through the questions, pass or fail.
+    /// Members retrying or greeted before the window don't count
+    pub fn completion_rate(&self) -> Option<f64> {
+        rate(self.window_greeted_completed, self.window_greeted)
+    }
+
+    pub fn pass_rate(&self) -> Option<f64> {
+        rate(self.window_passed, self.window_completed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::services::Actor;
+
+    fn entry(timestamp: i64, event: HistoryEvent) -> HistoryEntry {
+        HistoryEntry {
+            timestamp: serenity::Timestamp::from_unix_timestamp(timestamp).unwrap(),
+            actor: Actor::System,
+            event,
+        }
+    }
+
+    #[test]
+    fn completion_rate_counts_members_once() {
+        let history = HashMap::from([
+            (
+                serenity::UserId(1),
+                vec![
+                    entry(100, HistoryEvent::Greeted),
+                    entry(110, HistoryEvent::Started),
+                    entry(120, HistoryEvent::Failed),
+                    entry(130, HistoryEvent::Started),
+                    entry(140, HistoryEvent::Failed),
+                    entry(150, HistoryEvent::Started),
+                    entry(160, HistoryEvent::Passed),
+                ],
+            ),
+            (serenity::UserId(2), vec![entry(100, HistoryEvent::Greeted)]),
+        ]);
+
+        let stats = Stats::compute(&HashMap::new(), &history, None);
+
+        assert_eq!(stats.window_greeted, 2);
+        assert_eq!(stats.window_completed(), 3);
+        assert_eq!(stats.completion_rate(), Some(0.5));
+        assert_eq!(stats.pass_rate(), Some(1.0 / 3.0));
+    }
+
+    #[test]
+    fn completion_rate_ignores_members_greeted_before_window() {
+        let history = HashMap::from([
+            (
+                serenity::UserId(1),
+                vec![
+                    entry(100, HistoryEvent::Greeted),
+                    entry(300, HistoryEvent::Passed),
+                ],
+            ),
+            (serenity::UserId(2), vec![entry(250, HistoryEvent::Greeted)]),
+        ]);
+
+        let since = serenity::Timestamp::from_unix_timestamp(200).unwrap();
+        let stats = Stats::compute(&HashMap::new(), &history, Some(since));
+
+        assert_eq!(stats.window_greeted, 1);
+        assert_eq!(stats.window_passed, 1);
+        assert_eq!(stats.completion_rate(), Some(0.0));
+        assert_eq!(stats.median_completion, Some(Duration::from_secs(200)));
+    }
+
+    #[test]
+    fn counts_current_states() {
+        let statuses = HashMap::from([
+            (serenity::UserId(1), VerificationStatus::Verified),
+            (serenity::UserId(2), VerificationStatus::Verified),
+            (serenity::UserId(3), VerificationStatus::Rejected),
+        ]);
+
+        let stats = Stats::compute(&statuses, &HashMap::new(), None);
+
+        assert_eq!((stats.verified, stats.rejected, stats.pending), (2, 1, 0));
+        assert_eq!(stats.completion_rate(), None);
+    }
+}
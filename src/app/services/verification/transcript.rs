@@ -0,0 +1,48 @@
+use poise::serenity_prelude as serenity;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Question;
+
+/// One answer, with the question as it was worded when it was answered
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TranscriptAnswer {
+    pub question_id: String,
+    pub prompt: String,
+    pub answer: String,
+    pub correct_answer: String,
+}
+
+impl TranscriptAnswer {
+    pub fn is_correct(&self) -> bool {
+        self.answer == self.correct_answer
+    }
+}
+
+/// Answers from one completed attempt at the questions
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Transcript {
+    pub timestamp: serenity::Timestamp,
+    pub passed: bool,
+    pub answers: Vec<TranscriptAnswer>,
+}
+
+impl Transcript {
+    pub fn new(questions: &[Question], form_data: &[String], passed: bool) -> Self {
+        let answers = questions
+            .iter()
+            .zip(form_data)
+            .map(|(question, answer)| TranscriptAnswer {
+                question_id: question.id.clone(),
+                prompt: question.prompt.clone(),
+                answer: answer.clone(),
+                correct_answer: question.answers[question.correct_answer].clone(),
+            })
+            .collect();
+
+        Self {
+            timestamp: serenity::Timestamp::now(),
+            passed,
+            answers,
+        }
+    }
+}
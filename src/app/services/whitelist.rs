@@ -1,19 +1,55 @@
+mod entry;
+mod file;
+
 use std::{
-    collections::HashSet,
-    fmt,
-    fs::File,
-    io::{self, BufRead, BufReader, BufWriter, Write},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt, io,
     path::{Path, PathBuf},
+    str,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+    time::Duration,
+};
+
+use poise::serenity_prelude as serenity;
+use tokio::sync::{Mutex, Notify, RwLock};
+
+use crate::{
+    app::models::Ckey,
+    data_file::{self, Schema},
 };
 
-use tokio::sync::RwLock;
+use super::{
+    audit::{self, Actor, AuditAction, AuditService},
+    write_behind::WriteBehind,
+};
+
+pub use entry::{EntrySource, WhitelistEntry};
+
+use file::WhitelistFile;
 
-use crate::app::models::Ckey;
+/// How often the file is checked for changes made outside the bot
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+/// Wait between reads of a file that changed, until it stops changing
+const SETTLE: Duration = Duration::from_millis(500);
+/// Reads of a changing file after which it's taken as it is
+const SETTLE_ATTEMPTS: u32 = 5;
+/// How often expired entries are looked for
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+const ENTRIES_SCHEMA: Schema = Schema {
+    migrations: &[data_file::unversioned],
+};
 
 #[derive(Debug)]
 pub enum Error {
     Read(io::Error),
     Write(io::Error),
+    ReadEntries(data_file::Error),
+    WriteEntries(ron::Error),
+    Audit(audit::Error),
 }
 
 impl From<Error> for super::Error {
@@ -27,88 +63,478 @@ impl fmt::Display for Error {
         match *self {
             Error::Read(ref e) => write!(f, "read: {}", e),
             Error::Write(ref e) => write!(f, "write: {}", e),
+            Error::ReadEntries(ref e) => write!(f, "read entry details: {}", e),
+            Error::WriteEntries(ref e) => write!(f, "write entry details: {}", e),
+            Error::Audit(ref e) => write!(f, "audit: {}", e),
         }
     }
 }
 
 pub struct WhitelistService {
     whitelist_path: PathBuf,
+    /// Details of the entries, kept next to the whitelist since it may be
+    /// shared between guilds
+    entries_path: PathBuf,
+    audit: Arc<AuditService>,
     whitelist: RwLock<HashSet<String>>,
+    entries: RwLock<HashMap<String, WhitelistEntry>>,
+    /// Layout of the file as last read, to keep what was written by hand
+    file: RwLock<WhitelistFile>,
+    /// File contents as the bot last read or wrote them, anything else was
+    /// changed from outside. Held while reading or writing the file
+    disk: Mutex<Option<Vec<u8>>>,
+    /// Guilds whose verified members own each ckey and which member that is,
+    /// entries nobody claims were added by hand
+    claims: RwLock<HashMap<String, HashMap<serenity::GuildId, serenity::UserId>>>,
+    /// Guilds whose members are named in comments next to their entries
+    commented: RwLock<HashSet<serenity::GuildId>>,
+    dirty: AtomicBool,
+    entries_dirty: AtomicBool,
+    changed: Notify,
 }
 
 impl WhitelistService {
-    pub fn new(whitelist_path: &Path) -> Self {
+    pub fn new(whitelist_path: &Path, audit: Arc<AuditService>) -> Self {
+        let mut entries_path = whitelist_path.as_os_str().to_owned();
+        entries_path.push(".entries.ron");
+
         Self {
             whitelist_path: whitelist_path.into(),
+            entries_path: entries_path.into(),
+            audit,
             whitelist: RwLock::new(HashSet::new()),
+            entries: RwLock::new(HashMap::new()),
+            file: RwLock::new(WhitelistFile::default()),
+            disk: Mutex::new(None),
+            claims: RwLock::new(HashMap::new()),
+            commented: RwLock::new(HashSet::new()),
+            dirty: AtomicBool::new(false),
+            entries_dirty: AtomicBool::new(false),
+            changed: Notify::new(),
         }
     }
 
     pub async fn load(&self) -> Result<(), Error> {
-        let path = self.whitelist_path.clone();
-        let whitelist = tokio::task::spawn_blocking(move || {
-            let file = File::open(path).map_err(Error::Read)?;
-            let reader = BufReader::new(file);
-
-            let mut whitelist = HashSet::new();
+        let mut disk = self.disk.lock().await;
 
-            for line in reader.lines() {
-                let line = line.map_err(Error::Read)?;
-                if line.starts_with('#') || line.trim().is_empty() {
-                    continue;
-                }
-                whitelist.insert(line.trim().into());
-            }
+        let path = self.whitelist_path.clone();
+        let contents = tokio::task::spawn_blocking(move || {
+            data_file::read(&path, |contents| {
+                let text = str::from_utf8(contents)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                let file = WhitelistFile::parse(text)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
 
-            Ok(whitelist)
+                Ok::<_, io::Error>((text.to_string(), file))
+            })
+            .map_err(Error::Read)?
+            .ok_or_else(|| Error::Read(io::ErrorKind::NotFound.into()))
         })
         .await
         .expect("Thread panicked")?;
 
+        let (contents, file) = contents;
+        let whitelist = file.entries();
+
+        let path = self.entries_path.clone();
+        let mut entries: HashMap<String, WhitelistEntry> =
+            tokio::task::spawn_blocking(move || data_file::read_versioned(&path, &ENTRIES_SCHEMA))
+                .await
+                .expect("Thread panicked")
+                .map_err(Error::ReadEntries)?
+                .unwrap_or_default();
+        // Removed from the file while the bot wasn't looking
+        let before = entries.len();
+        entries.retain(|ckey, _| whitelist.contains(ckey));
+        if entries.len() != before {
+            self.mark_entries_dirty();
+        }
+
         *self.whitelist.write().await = whitelist;
+        *self.entries.write().await = entries;
+        *self.file.write().await = file;
+        *disk = Some(contents.into_bytes());
 
         Ok(())
     }
 
-    pub async fn store(&self) -> Result<(), Error> {
+    /// Reads the file once it stops changing, other tools may write it in
+    /// several steps. `None` if it doesn't exist
+    fn read_settled(path: &Path) -> io::Result<Option<Vec<u8>>> {
+        let read = || match std::fs::read(path) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        };
+
+        let mut contents = read()?;
+        for _ in 1..SETTLE_ATTEMPTS {
+            std::thread::sleep(SETTLE);
+
+            let again = read()?;
+            if again == contents {
+                break;
+            }
+            contents = again;
+        }
+
+        Ok(contents)
+    }
+
+    /// Takes in changes made to the file from outside since the bot last read
+    /// or wrote it, keeping changes of its own that aren't written yet.
+    /// Returns whether there were any
+    async fn merge(&self, disk: &mut Option<Vec<u8>>) -> Result<bool, Error> {
         let path = self.whitelist_path.clone();
-        let whitelist = {
+        let unchanged = disk.clone();
+        let contents = tokio::task::spawn_blocking(move || {
+            match std::fs::read(&path) {
+                Ok(contents) if Some(&contents) == unchanged.as_ref() => return Ok(None),
+                // Recreated on the next write
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                _ => (),
+            }
+
+            Self::read_settled(&path)
+        })
+        .await
+        .expect("Thread panicked")
+        .map_err(Error::Read)?;
+
+        let Some(contents) = contents.filter(|contents| Some(contents) != disk.as_ref()) else {
+            return Ok(false);
+        };
+
+        let Ok(text) = str::from_utf8(&contents) else {
+            log::warn!(
+                "{} was changed outside the bot and isn't valid UTF-8 anymore, \
+                ignoring the change until the bot writes it again",
+                self.whitelist_path.display()
+            );
+            *disk = Some(contents);
+
+            return Ok(false);
+        };
+
+        let mut file = match WhitelistFile::parse(text) {
+            Ok(file) => file,
+            Err(e) => {
+                log::warn!(
+                    "{} was changed outside the bot and can't be read anymore ({e}), \
+                    ignoring the change until the bot writes it again",
+                    self.whitelist_path.display()
+                );
+                *disk = Some(contents);
+
+                return Ok(false);
+            }
+        };
+        let before = match disk
+            .as_deref()
+            .map(|disk| WhitelistFile::parse(&String::from_utf8_lossy(disk)))
+        {
+            Some(Ok(previous)) => previous.entries(),
+            // Last seen unreadable, compared to what the bot has instead
+            Some(Err(_)) => self.whitelist.read().await.clone(),
+            None => HashSet::new(),
+        };
+        let after = file.entries();
+
+        let mut added: Vec<_> = after.difference(&before).cloned().collect();
+        let mut removed: Vec<_> = before.difference(&after).cloned().collect();
+        added.sort();
+        removed.sort();
+
+        let pending = {
+            let mut whitelist = self.whitelist.write().await;
+            let mut entries = self.entries.write().await;
+            for ckey in &added {
+                whitelist.insert(ckey.clone());
+                entries.insert(
+                    ckey.clone(),
+                    WhitelistEntry::new(Actor::File, EntrySource::Manual),
+                );
+            }
+            for ckey in &removed {
+                whitelist.remove(ckey);
+                entries.remove(ckey);
+            }
+
+            // Entries the bot removed but hasn't written yet stay removed
+            for ckey in after.difference(&whitelist) {
+                file.remove_manual(ckey);
+            }
+
+            *whitelist != after
+        };
+
+        *self.file.write().await = file;
+        *disk = Some(contents);
+
+        let changed = !added.is_empty() || !removed.is_empty();
+        if changed {
+            log::info!(
+                "Whitelist changed outside the bot, added: [{}], removed: [{}]",
+                added.join(", "),
+                removed.join(", ")
+            );
+        }
+        for ckey in added {
+            self.record(Actor::File, AuditAction::WhitelistAdd { ckey })
+                .await?;
+        }
+        for ckey in removed {
+            self.record(Actor::File, AuditAction::WhitelistRemove { ckey })
+                .await?;
+        }
+
+        if changed {
+            self.mark_entries_dirty();
+        }
+        if pending {
+            self.mark_dirty();
+        }
+
+        Ok(true)
+    }
+
+    /// Takes in changes made to the file from outside, returns whether there
+    /// were any
+    pub async fn sync(&self) -> Result<bool, Error> {
+        let mut disk = self.disk.lock().await;
+        self.merge(&mut disk).await
+    }
+
+    /// Takes in changes made to the file from outside like `sync`, then
+    /// writes out the bot's own pending changes right away. Returns whether
+    /// there were outside changes
+    pub async fn reload(&self) -> Result<bool, Error> {
+        let mut disk = self.disk.lock().await;
+        let changed = self.merge(&mut disk).await?;
+
+        if self.dirty.swap(false, Ordering::AcqRel) {
+            if let Err(e) = self.write(&mut disk).await {
+                self.dirty.store(true, Ordering::Release);
+                return Err(e);
+            }
+        }
+
+        Ok(changed)
+    }
+
+    async fn store(&self, disk: &mut Option<Vec<u8>>) -> Result<(), Error> {
+        // Otherwise they'd be overwritten
+        self.merge(disk).await?;
+
+        self.write(disk).await
+    }
+
+    /// Writes the file and entry details, outside changes have to be merged
+    /// into `disk` first
+    async fn write(&self, disk: &mut Option<Vec<u8>>) -> Result<(), Error> {
+        let path = self.whitelist_path.clone();
+        let file = self.file.read().await.clone();
+        let comments = self.comments().await;
+        let managed: BTreeSet<_> = {
             let guard = self.whitelist.read().await;
-            guard.clone()
+            let manual: HashSet<_> = file.manual().collect();
+            guard
+                .iter()
+                .filter(|ckey| !manual.contains(ckey.as_str()))
+                .cloned()
+                .collect()
         };
 
-        tokio::task::spawn_blocking(move || {
-            let file = File::create(path).map_err(Error::Write)?;
-            let mut writer = BufWriter::new(file);
+        let contents = file.render(
+            managed
+                .iter()
+                .map(|ckey| (ckey.as_str(), comments.get(ckey).cloned())),
+        );
 
-            writeln!(
-                writer,
-                "# This file is autogenerated\n# Any modifications will be lost"
-            )
-            .map_err(Error::Write)?;
+        // Comments are refreshed generously, don't rotate backups for nothing
+        let contents = contents.into_bytes();
+        if disk.as_ref() != Some(&contents) {
+            let written = contents.clone();
+            tokio::task::spawn_blocking(move || {
+                data_file::write(&path, &written).map_err(Error::Write)
+            })
+            .await
+            .expect("Thread panicked")?;
+
+            *disk = Some(contents);
+        }
 
-            for ckey in &whitelist {
-                writeln!(writer, "{ckey}").map_err(Error::Write)?;
+        if self.entries_dirty.swap(false, Ordering::AcqRel) {
+            if let Err(e) = self.store_entries().await {
+                self.entries_dirty.store(true, Ordering::Release);
+                return Err(e);
             }
+        }
+
+        Ok(())
+    }
+
+    async fn store_entries(&self) -> Result<(), Error> {
+        let path = self.entries_path.clone();
+        let entries: BTreeMap<_, _> = {
+            let guard = self.entries.read().await;
+            guard
+                .iter()
+                .map(|(ckey, entry)| (ckey.clone(), entry.clone()))
+                .collect()
+        };
 
-            writer.flush().map_err(Error::Write)
+        tokio::task::spawn_blocking(move || {
+            data_file::write_versioned(&path, &ENTRIES_SCHEMA, &entries)
+                .map_err(Error::WriteEntries)
         })
         .await
         .expect("Thread panicked")
     }
 
+    /// Comments naming the members that own each entry, in guilds that want them
+    async fn comments(&self) -> HashMap<String, String> {
+        let commented = self.commented.read().await;
+        let claims = self.claims.read().await;
+
+        claims
+            .iter()
+            .filter_map(|(ckey, guilds)| {
+                let users: BTreeSet<_> = guilds
+                    .iter()
+                    .filter(|(guild_id, _)| commented.contains(guild_id))
+                    .map(|(_, user_id)| user_id.to_string())
+                    .collect();
+
+                (!users.is_empty()).then(|| {
+                    let users: Vec<_> = users.into_iter().collect();
+                    (ckey.clone(), format!("discord {}", users.join(", ")))
+                })
+            })
+            .collect()
+    }
+
+    /// Sets whether a guild's members are named in comments next to their
+    /// entries
+    pub async fn set_comments(&self, guild_id: serenity::GuildId, enabled: bool) {
+        let changed = {
+            let mut guard = self.commented.write().await;
+            if enabled {
+                guard.insert(guild_id)
+            } else {
+                guard.remove(&guild_id)
+            }
+        };
+
+        if changed {
+            self.mark_dirty();
+        }
+    }
+
+    /// Members of guilds sharing the whitelist that own a ckey, they'd have
+    /// it whitelisted again when their records are loaded
+    pub async fn claimants(&self, ckey: &Ckey) -> Vec<serenity::UserId> {
+        let claims = self.claims.read().await;
+        let users: BTreeSet<_> = claims
+            .get(ckey.as_str())
+            .into_iter()
+            .flat_map(|guilds| guilds.values().copied())
+            .collect();
+
+        users.into_iter().collect()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.whitelist_path
+    }
+
     pub async fn list(&self) -> Vec<String> {
         self.whitelist.read().await.iter().cloned().collect()
     }
 
-    pub async fn insert(&self, ckey: &Ckey) -> Result<bool, Error> {
+    /// Every whitelisted ckey in order, with its details if there are any
+    pub async fn list_entries(&self) -> Vec<(String, Option<WhitelistEntry>)> {
+        let whitelist = self.whitelist.read().await;
+        let entries = self.entries.read().await;
+
+        let mut list: Vec<_> = whitelist
+            .iter()
+            .map(|ckey| (ckey.clone(), entries.get(ckey).cloned()))
+            .collect();
+        list.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        list
+    }
+
+    /// Ckeys the bot whitelisted for good on its own, leaving out lines
+    /// written by hand, entries staff added and temporary ones
+    pub async fn list_managed(&self) -> Vec<String> {
+        let whitelist = self.whitelist.read().await;
+        let entries = self.entries.read().await;
+        let file = self.file.read().await;
+        let manual: HashSet<_> = file.manual().collect();
+
+        whitelist
+            .iter()
+            .filter(|ckey| !manual.contains(ckey.as_str()))
+            .filter(|ckey| {
+                entries.get(*ckey).is_none_or(|entry| {
+                    entry.source != EntrySource::Manual && entry.expires_at.is_none()
+                })
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Release);
+        self.changed.notify_one();
+    }
+
+    fn mark_entries_dirty(&self) {
+        self.entries_dirty.store(true, Ordering::Release);
+        self.mark_dirty();
+    }
+
+    /// Whitelists may be shared between guilds, so entries are attributed to
+    /// the file rather than a guild
+    async fn record(&self, actor: Actor, action: AuditAction) -> Result<(), Error> {
+        self.audit
+            .record_whitelist(&self.whitelist_path, actor, action)
+            .await
+            .map_err(Error::Audit)
+    }
+
+    pub async fn insert(
+        &self,
+        ckey: &Ckey,
+        actor: Actor,
+        source: EntrySource,
+    ) -> Result<bool, Error> {
+        self.add(ckey, WhitelistEntry::new(actor, source)).await
+    }
+
+    /// Whitelists a ckey with the given details, unless it already is
+    pub async fn add(&self, ckey: &Ckey, entry: WhitelistEntry) -> Result<bool, Error> {
+        let actor = entry.added_by;
         let result = {
             let mut guard = self.whitelist.write().await;
             guard.insert(ckey.as_str().to_string())
         };
 
         if result {
-            self.store().await?;
+            self.entries
+                .write()
+                .await
+                .insert(ckey.as_str().to_string(), entry);
+            self.mark_entries_dirty();
+            self.record(
+                actor,
+                AuditAction::WhitelistAdd {
+                    ckey: ckey.as_str().to_string(),
+                },
+            )
+            .await?;
 
             Ok(true)
         } else {
@@ -116,18 +542,291 @@ impl WhitelistService {
         }
     }
 
-    pub async fn remove(&self, ckey: &Ckey) -> Result<bool, Error> {
+    pub async fn remove(&self, ckey: &Ckey, actor: Actor) -> Result<bool, Error> {
+        // Otherwise it'd come back on the next reload
+        self.file.write().await.remove_manual(ckey.as_str());
+
+        self.remove_managed(ckey, actor).await
+    }
+
+    /// Removes an entry the bot wrote, a line for the ckey written by hand
+    /// keeps it whitelisted
+    async fn remove_managed(&self, ckey: &Ckey, actor: Actor) -> Result<bool, Error> {
+        if self.file.read().await.has_manual(ckey.as_str()) {
+            if self.entries.write().await.remove(ckey.as_str()).is_some() {
+                self.mark_entries_dirty();
+            }
+
+            return Ok(false);
+        }
+
         let result = {
             let mut guard = self.whitelist.write().await;
             guard.remove(ckey.as_str())
         };
 
         if result {
-            self.store().await?;
+            self.entries.write().await.remove(ckey.as_str());
+            self.mark_entries_dirty();
+            self.record(
+                actor,
+                AuditAction::WhitelistRemove {
+                    ckey: ckey.as_str().to_string(),
+                },
+            )
+            .await?;
 
             Ok(true)
         } else {
             Ok(false)
         }
     }
+
+    /// Replaces the ckeys a guild's members claim when they're loaded, adding
+    /// any that are missing from the whitelist. Returns how many were added
+    pub async fn register(
+        &self,
+        guild_id: serenity::GuildId,
+        ckeys: HashMap<String, serenity::UserId>,
+    ) -> Result<usize, Error> {
+        {
+            let mut claims = self.claims.write().await;
+
+            claims.retain(|_, guilds| {
+                guilds.remove(&guild_id);
+                !guilds.is_empty()
+            });
+            for (ckey, user_id) in &ckeys {
+                claims
+                    .entry(ckey.clone())
+                    .or_default()
+                    .insert(guild_id, *user_id);
+            }
+        }
+        if self.commented.read().await.contains(&guild_id) {
+            self.mark_dirty();
+        }
+
+        let mut added = 0;
+        for ckey in ckeys.into_keys() {
+            if self
+                .insert(&Ckey::from(&ckey), Actor::System, EntrySource::Verification)
+                .await?
+            {
+                added += 1;
+            }
+        }
+
+        Ok(added)
+    }
+
+    /// Whitelists a ckey on behalf of one of a guild's members
+    pub async fn claim(
+        &self,
+        guild_id: serenity::GuildId,
+        user_id: serenity::UserId,
+        ckey: &Ckey,
+        actor: Actor,
+    ) -> Result<bool, Error> {
+        let previous = self
+            .claims
+            .write()
+            .await
+            .entry(ckey.as_str().to_string())
+            .or_default()
+            .insert(guild_id, user_id);
+        if previous != Some(user_id) && self.commented.read().await.contains(&guild_id) {
+            self.mark_dirty();
+        }
+
+        if self.insert(ckey, actor, EntrySource::Verification).await? {
+            return Ok(true);
+        }
+
+        // A member owning the ckey now keeps it on the whitelist
+        let mut entries = self.entries.write().await;
+        if entries
+            .get(ckey.as_str())
+            .is_none_or(|entry| entry.expires_at.is_some())
+        {
+            entries.insert(
+                ckey.as_str().to_string(),
+                WhitelistEntry::new(actor, EntrySource::Verification),
+            );
+            self.mark_entries_dirty();
+        }
+
+        Ok(false)
+    }
+
+    /// Removes entries whose time is up, unless a member owns them by now.
+    /// Returns the removed ckeys
+    pub async fn expire(&self) -> Result<Vec<String>, Error> {
+        let now = serenity::Timestamp::now();
+        let expired: Vec<_> = self
+            .entries
+            .read()
+            .await
+            .iter()
+            .filter(|(_, entry)| entry.is_expired(now))
+            .map(|(ckey, _)| ckey.clone())
+            .collect();
+
+        let mut removed = Vec::new();
+        for ckey in expired {
+            if self.claims.read().await.contains_key(&ckey) {
+                if let Some(entry) = self.entries.write().await.get_mut(&ckey) {
+                    entry.expires_at = None;
+                }
+                self.mark_entries_dirty();
+                continue;
+            }
+
+            if self
+                .remove_managed(&Ckey::from(&ckey), Actor::System)
+                .await?
+            {
+                log::info!("Whitelist entry for {ckey} expired");
+                removed.push(ckey);
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Drops a guild's claim on a ckey, unwhitelisting it once no guild
+    /// sharing the whitelist claims it anymore
+    pub async fn release(
+        &self,
+        guild_id: serenity::GuildId,
+        ckey: &Ckey,
+        actor: Actor,
+    ) -> Result<bool, Error> {
+        let unclaimed = {
+            let mut claims = self.claims.write().await;
+            match claims.get_mut(ckey.as_str()) {
+                Some(guilds) => {
+                    guilds.remove(&guild_id);
+                    guilds.is_empty()
+                }
+                None => true,
+            }
+        };
+
+        if !unclaimed {
+            if self.commented.read().await.contains(&guild_id) {
+                self.mark_dirty();
+            }
+
+            return Ok(false);
+        }
+        self.claims.write().await.remove(ckey.as_str());
+
+        self.remove_managed(ckey, actor).await
+    }
+}
+
+impl WriteBehind for WhitelistService {
+    fn changed(&self) -> &Notify {
+        &self.changed
+    }
+
+    async fn flush(&self) -> Result<(), super::Error> {
+        // Held from taking the dirty flag until it's written, so another flush
+        // waits for this one rather than returning early
+        let mut disk = self.disk.lock().await;
+
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+
+        if let Err(e) = self.store(&mut disk).await {
+            self.dirty.store(true, Ordering::Release);
+            return Err(e.into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks the file for changes made outside the bot every few seconds, for as
+/// long as the service is in use
+pub fn watch(service: Weak<WhitelistService>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            let Some(service) = service.upgrade() else {
+                break;
+            };
+
+            if let Err(e) = service.sync().await {
+                log::error!("Error while checking the whitelist for changes: {e}");
+            }
+        }
+    });
+}
+
+/// Removes expired entries every minute, for as long as the service is in use
+pub fn expire(service: Weak<WhitelistService>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            let Some(service) = service.upgrade() else {
+                break;
+            };
+
+            if let Err(e) = service.expire().await {
+                log::error!("Error while removing expired whitelist entries: {e}");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn takes_in_edits_inside_the_block() {
+        let dir = std::env::temp_dir().join(format!(
+            "nightstation-verify-{}-whitelist-block",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("whitelist.txt");
+        fs::write(&path, "").unwrap();
+
+        let whitelist = WhitelistService::new(&path, Arc::new(AuditService::new(&dir)));
+        whitelist.load().await.unwrap();
+        for ckey in ["alice", "carol"] {
+            let entry = WhitelistEntry::new(Actor::Console, EntrySource::Manual);
+            whitelist.add(&Ckey::from(ckey), entry).await.unwrap();
+        }
+        whitelist.reload().await.unwrap();
+
+        let edited = fs::read_to_string(&path)
+            .unwrap()
+            .replace("alice\n", "zed # by hand\n");
+        fs::write(&path, edited).unwrap();
+
+        assert!(whitelist.reload().await.unwrap());
+        let mut list = whitelist.list().await;
+        list.sort();
+        assert_eq!(list, ["carol", "zed"]);
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.contains("\ncarol\nzed\n"), "{contents}");
+        assert!(!contents.contains("by hand"), "{contents}");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
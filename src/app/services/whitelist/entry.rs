@@ -0,0 +1,56 @@
+use poise::serenity_prelude as serenity;
+use serde::{Deserialize, Serialize};
+
+use crate::app::services::audit::Actor;
+
+/// Why a ckey was whitelisted
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EntrySource {
+    /// Owned by a verified or always whitelisted member
+    Verification,
+    /// Added by staff, or by editing the file
+    Manual,
+    /// Brought in with a member import
+    Import,
+}
+
+impl EntrySource {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Self::Verification => "verification",
+            Self::Manual => "manual",
+            Self::Import => "import",
+        }
+    }
+}
+
+/// Details kept about a whitelisted ckey, entries that were already in the
+/// file before details were kept have none
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct WhitelistEntry {
+    pub added_by: Actor,
+    pub added_at: serenity::Timestamp,
+    pub source: EntrySource,
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// When the entry is removed again, never if not set
+    #[serde(default)]
+    pub expires_at: Option<serenity::Timestamp>,
+}
+
+impl WhitelistEntry {
+    pub fn new(added_by: Actor, source: EntrySource) -> Self {
+        Self {
+            added_by,
+            added_at: serenity::Timestamp::now(),
+            source,
+            reason: None,
+            expires_at: None,
+        }
+    }
+
+    pub fn is_expired(&self, now: serenity::Timestamp) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at.unix_timestamp() <= now.unix_timestamp())
+    }
+}
@@ -0,0 +1,212 @@
+use std::{collections::HashSet, fmt};
+
+/// Opens the block of entries the bot manages
+const BEGIN: &str = "# BEGIN nightstation-verify";
+/// Closes the block of entries the bot manages
+const END: &str = "# END nightstation-verify";
+/// Rest of the BEGIN line, saying what happens to edits inside the block
+const HEADER: &str = ", managed by the bot: ckeys added or removed up to the END line are \
+    taken in, but the block is sorted again and comments in it are dropped";
+/// Header of files from before the bot kept to its own block, every entry in
+/// them was written by the bot
+const LEGACY_HEADER: [&str; 2] = [
+    "# This file is autogenerated",
+    "# Any modifications will be lost",
+];
+
+/// Ckey on a whitelist line, without whitespace and trailing comment. `None`
+/// for blank and comment lines
+pub fn entry(line: &str) -> Option<&str> {
+    let entry = line.split('#').next().unwrap_or_default().trim();
+
+    (!entry.is_empty()).then_some(entry)
+}
+
+/// The bot's block is opened but never closed, so it can't tell which lines
+/// were written by hand
+#[derive(Debug)]
+pub struct UnterminatedBlock;
+
+impl fmt::Display for UnterminatedBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{BEGIN}\" line without an \"{END}\" line after it")
+    }
+}
+
+impl std::error::Error for UnterminatedBlock {}
+
+/// Whitelist file split around the bot's block. Lines outside of it are
+/// written back exactly as they were read
+#[derive(Clone, Debug, Default)]
+pub struct WhitelistFile {
+    before: Vec<String>,
+    after: Vec<String>,
+    /// Entries inside the bot's block when the file was read
+    managed: Vec<String>,
+}
+
+impl WhitelistFile {
+    pub fn parse(contents: &str) -> Result<Self, UnterminatedBlock> {
+        let mut file = Self::default();
+        let mut in_block = false;
+        let mut seen_block = false;
+
+        for line in contents.lines() {
+            if !seen_block && line.trim_start().starts_with(BEGIN) {
+                in_block = true;
+                seen_block = true;
+            } else if in_block && line.trim_start().starts_with(END) {
+                in_block = false;
+            } else if in_block {
+                file.managed.extend(entry(line).map(String::from));
+            } else if seen_block {
+                file.after.push(line.into());
+            } else {
+                file.before.push(line.into());
+            }
+        }
+
+        if in_block {
+            return Err(UnterminatedBlock);
+        }
+
+        if !seen_block && file.before.starts_with(&LEGACY_HEADER.map(String::from)) {
+            file.managed = file
+                .before
+                .drain(..)
+                .filter_map(|line| entry(&line).map(String::from))
+                .collect();
+        }
+
+        Ok(file)
+    }
+
+    /// Whether a line outside of the bot's block whitelists a ckey
+    pub fn has_manual(&self, ckey: &str) -> bool {
+        self.manual().any(|entry| entry == ckey)
+    }
+
+    /// Every entry in the file, inside the bot's block or not
+    pub fn entries(&self) -> HashSet<String> {
+        self.manual()
+            .chain(self.managed.iter().map(String::as_str))
+            .map(String::from)
+            .collect()
+    }
+
+    /// Entries added by hand outside of the bot's block
+    pub fn manual(&self) -> impl Iterator<Item = &str> {
+        self.before
+            .iter()
+            .chain(&self.after)
+            .filter_map(|line| entry(line))
+    }
+
+    /// Drops the lines outside of the bot's block that whitelist a ckey,
+    /// returns whether there were any
+    pub fn remove_manual(&mut self, ckey: &str) -> bool {
+        let len = self.before.len() + self.after.len();
+
+        self.before.retain(|line| entry(line) != Some(ckey));
+        self.after.retain(|line| entry(line) != Some(ckey));
+
+        self.before.len() + self.after.len() != len
+    }
+
+    /// File contents with the given entries in the bot's block, each with an
+    /// optional trailing comment
+    pub fn render<'a>(
+        &self,
+        managed: impl IntoIterator<Item = (&'a str, Option<String>)>,
+    ) -> String {
+        let mut contents = String::new();
+
+        for line in &self.before {
+            contents.push_str(line);
+            contents.push('\n');
+        }
+
+        contents.push_str(BEGIN);
+        contents.push_str(HEADER);
+        contents.push('\n');
+        for (ckey, comment) in managed {
+            contents.push_str(ckey);
+            if let Some(comment) = comment {
+                contents.push_str(" # ");
+                contents.push_str(&comment);
+            }
+            contents.push('\n');
+        }
+        contents.push_str(END);
+        contents.push('\n');
+
+        for line in &self.after {
+            contents.push_str(line);
+            contents.push('\n');
+        }
+
+        contents
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(entries: HashSet<String>) -> Vec<String> {
+        let mut entries: Vec<_> = entries.into_iter().collect();
+        entries.sort();
+        entries
+    }
+
+    #[test]
+    fn keeps_lines_around_the_block() {
+        let contents = "# staff\nalice # owner\n\
+            # BEGIN nightstation-verify, managed\nbob # discord 1\ncarol\n\
+            # END nightstation-verify\ndave\n";
+        let file = WhitelistFile::parse(contents).unwrap();
+
+        assert_eq!(file.manual().collect::<Vec<_>>(), ["alice", "dave"]);
+        assert_eq!(sorted(file.entries()), ["alice", "bob", "carol", "dave"]);
+
+        let rendered = file.render([("bob", None), ("erin", Some("discord 2".into()))]);
+        assert_eq!(
+            rendered,
+            format!(
+                "# staff\nalice # owner\n{BEGIN}{HEADER}\nbob\nerin # discord 2\n{END}\ndave\n"
+            )
+        );
+        assert_eq!(
+            sorted(WhitelistFile::parse(&rendered).unwrap().entries()),
+            ["alice", "bob", "dave", "erin"]
+        );
+    }
+
+    #[test]
+    fn takes_over_legacy_files() {
+        let contents = format!("{}\n{}\nalice\nbob\n", LEGACY_HEADER[0], LEGACY_HEADER[1]);
+        let file = WhitelistFile::parse(&contents).unwrap();
+
+        assert_eq!(file.manual().count(), 0);
+        assert_eq!(sorted(file.entries()), ["alice", "bob"]);
+    }
+
+    #[test]
+    fn rejects_unterminated_block() {
+        let contents = format!("alice\n{BEGIN}\nbob\n");
+
+        assert!(WhitelistFile::parse(&contents).is_err());
+    }
+
+    #[test]
+    fn removes_only_manual_lines() {
+        let contents = format!("alice\n{BEGIN}\nalice\nbob\n{END}\n");
+        let mut file = WhitelistFile::parse(&contents).unwrap();
+
+        assert!(file.has_manual("alice"));
+        assert!(!file.has_manual("bob"));
+        assert!(file.remove_manual("alice"));
+        assert!(!file.remove_manual("bob"));
+        assert_eq!(file.manual().count(), 0);
+    }
+}
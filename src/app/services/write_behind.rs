@@ -0,0 +1,30 @@
+use std::{future::Future, sync::Arc, time::Duration};
+
+use tokio::sync::Notify;
+
+/// How long changes are collected before they're written together
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// A service that keeps changes in memory and writes them out later
+pub trait WriteBehind: Send + Sync + 'static {
+    /// Notified whenever there's something new to write
+    fn changed(&self) -> &Notify;
+
+    /// Writes out everything that changed since the last flush
+    fn flush(&self) -> impl Future<Output = Result<(), super::Error>> + Send;
+}
+
+/// Flushes a service shortly after it changes, for as long as the program runs
+pub fn spawn<T: WriteBehind>(service: Arc<T>) {
+    tokio::spawn(async move {
+        loop {
+            service.changed().notified().await;
+            tokio::time::sleep(DEBOUNCE).await;
+
+            if let Err(e) = service.flush().await {
+                log::error!("Error while writing changes, retrying: {e}");
+                service.changed().notify_one();
+            }
+        }
+    });
+}
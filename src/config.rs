@@ -1,16 +1,20 @@
+pub mod template;
 mod wizard;
 
 use std::{
-    fmt,
-    fs::File,
-    io::{self, BufReader, BufWriter, Write},
+    collections::BTreeMap,
+    fmt, fs, io,
     path::{Path, PathBuf},
 };
 
-use ron::{error::SpannedError, ser::PrettyConfig};
+use ron::ser::PrettyConfig;
 use serde::{Deserialize, Serialize};
 use serenity::model::prelude::{ApplicationId, ChannelId, GuildId, RoleId, UserId};
 
+use template::{Template, Variable};
+
+use crate::data_file::{self, Schema};
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Question {
     pub id: String,
@@ -19,33 +23,471 @@ pub struct Question {
     pub correct_answer: usize,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct EmbedTemplate {
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub color: Option<u32>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub thumbnail: Option<String>,
+}
+
+impl EmbedTemplate {
+    pub fn validate(&self, kind: MessageKind) -> Result<(), template::Error> {
+        for source in [&self.title, &self.description, &self.thumbnail]
+            .into_iter()
+            .flatten()
+        {
+            kind.parse(source)?;
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Messages {
     pub greeting: String,
     pub verified: String,
     pub rejected: String,
+    #[serde(default)]
+    pub greeting_embed: Option<EmbedTemplate>,
+    #[serde(default)]
+    pub verified_embed: Option<EmbedTemplate>,
+    #[serde(default)]
+    pub rejected_embed: Option<EmbedTemplate>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct AppConfig {
-    pub token: String,
-    pub application_id: ApplicationId,
-    pub owner_id: UserId,
-    pub active_guild_id: GuildId,
+impl Default for Messages {
+    fn default() -> Self {
+        Self {
+            greeting: "{user}: Welcome to {guild}! Press the button below to begin verification"
+                .into(),
+            verified: "You are now verified as `{ckey}`".into(),
+            rejected: "You failed verification".into(),
+            greeting_embed: None,
+            verified_embed: None,
+            rejected_embed: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageKind {
+    Greeting,
+    Verified,
+    Rejected,
+}
+
+impl MessageKind {
+    pub const ALL: [MessageKind; 3] = [
+        MessageKind::Greeting,
+        MessageKind::Verified,
+        MessageKind::Rejected,
+    ];
+
+    pub fn variables(self) -> &'static [Variable] {
+        match self {
+            MessageKind::Greeting => &[
+                Variable::User,
+                Variable::UserName,
+                Variable::UserAvatar,
+                Variable::Guild,
+                Variable::QuestionCount,
+            ],
+            MessageKind::Verified => &[
+                Variable::User,
+                Variable::UserName,
+                Variable::UserAvatar,
+                Variable::Ckey,
+                Variable::Guild,
+                Variable::QuestionCount,
+            ],
+            MessageKind::Rejected => &[
+                Variable::User,
+                Variable::UserName,
+                Variable::UserAvatar,
+                Variable::Guild,
+                Variable::QuestionCount,
+            ],
+        }
+    }
+
+    pub fn parse(self, source: &str) -> Result<Template, template::Error> {
+        Template::parse_with(source, self.variables())
+    }
+
+    /// Parses a stored template, falling back to the raw text if it somehow became invalid
+    pub fn template(self, source: &str) -> Template {
+        self.parse(source).unwrap_or_else(|e| {
+            log::warn!("Invalid {self} template: {e}");
+            Template::literal(source)
+        })
+    }
+}
+
+impl fmt::Display for MessageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            MessageKind::Greeting => write!(f, "greeting message"),
+            MessageKind::Verified => write!(f, "verified message"),
+            MessageKind::Rejected => write!(f, "rejected message"),
+        }
+    }
+}
+
+impl Messages {
+    pub fn get(&self, kind: MessageKind) -> &str {
+        match kind {
+            MessageKind::Greeting => &self.greeting,
+            MessageKind::Verified => &self.verified,
+            MessageKind::Rejected => &self.rejected,
+        }
+    }
+
+    pub fn embed(&self, kind: MessageKind) -> Option<&EmbedTemplate> {
+        match kind {
+            MessageKind::Greeting => self.greeting_embed.as_ref(),
+            MessageKind::Verified => self.verified_embed.as_ref(),
+            MessageKind::Rejected => self.rejected_embed.as_ref(),
+        }
+    }
+
+    pub fn embed_mut(&mut self, kind: MessageKind) -> &mut Option<EmbedTemplate> {
+        match kind {
+            MessageKind::Greeting => &mut self.greeting_embed,
+            MessageKind::Verified => &mut self.verified_embed,
+            MessageKind::Rejected => &mut self.rejected_embed,
+        }
+    }
+
+    pub fn template(&self, kind: MessageKind) -> Template {
+        kind.template(self.get(kind))
+    }
+
+    /// Makes messages from before they were templates render as they used to.
+    /// Valid templates are kept, braces in the rest are taken literally
+    fn escape_invalid(&mut self, guild_id: GuildId) {
+        let messages = [
+            (
+                MessageKind::Greeting,
+                &mut self.greeting,
+                &mut self.greeting_embed,
+            ),
+            (
+                MessageKind::Verified,
+                &mut self.verified,
+                &mut self.verified_embed,
+            ),
+            (
+                MessageKind::Rejected,
+                &mut self.rejected,
+                &mut self.rejected_embed,
+            ),
+        ];
+
+        for (kind, message, embed) in messages {
+            let embed = embed.iter_mut().flat_map(|embed| {
+                [
+                    &mut embed.title,
+                    &mut embed.description,
+                    &mut embed.thumbnail,
+                ]
+                .into_iter()
+                .flatten()
+            });
+
+            for source in std::iter::once(message).chain(embed) {
+                if kind.parse(source).is_err() {
+                    log::warn!("Taking braces in the {kind} of {guild_id} literally");
+                    *source = Template::escape(source);
+                }
+            }
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), (MessageKind, template::Error)> {
+        for kind in MessageKind::ALL {
+            kind.parse(self.get(kind)).map_err(|e| (kind, e))?;
+
+            if let Some(embed) = self.embed(kind) {
+                embed.validate(kind).map_err(|e| (kind, e))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidTemplate {
+    pub guild_id: GuildId,
+    pub kind: MessageKind,
+    pub error: template::Error,
+}
+
+impl fmt::Display for InvalidTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} for server {}: {}",
+            self.kind, self.guild_id, self.error
+        )
+    }
+}
+
+#[derive(
+    Serialize,
+    Deserialize,
+    poise::ChoiceParameter,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+)]
+pub enum LogEvent {
+    #[name = "Verification started"]
+    Started,
+    #[name = "Answers accepted"]
+    Passed,
+    #[name = "Verification failed"]
+    Failed,
+    #[name = "Verified"]
+    Verified,
+    #[name = "Ckey conflict"]
+    CkeyConflict,
+    #[name = "Rejoined verified"]
+    Rejoined,
+    #[name = "Rejoined unverified"]
+    RejoinedUnverified,
+    #[name = "Rejoined rejected"]
+    RejoinedRejected,
+    #[name = "Banned"]
+    Banned,
+    #[name = "Approved by staff"]
+    Approved,
+    #[name = "Rejected by staff"]
+    Rejected,
+    #[name = "Verification undone"]
+    Aborted,
+}
+
+impl LogEvent {
+    pub const ALL: [LogEvent; 12] = [
+        LogEvent::Started,
+        LogEvent::Passed,
+        LogEvent::Failed,
+        LogEvent::Verified,
+        LogEvent::CkeyConflict,
+        LogEvent::Rejoined,
+        LogEvent::RejoinedUnverified,
+        LogEvent::RejoinedRejected,
+        LogEvent::Banned,
+        LogEvent::Approved,
+        LogEvent::Rejected,
+        LogEvent::Aborted,
+    ];
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Where an event is logged, events without a route go to the log channel
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LogRoute {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub channel_id: Option<ChannelId>,
+    #[serde(default)]
+    pub mention_role_id: Option<RoleId>,
+}
+
+impl Default for LogRoute {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            channel_id: None,
+            mention_role_id: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GuildConfig {
     pub greeting_channel_id: ChannelId,
     pub messages: Messages,
     pub log_channel_id: ChannelId,
+    #[serde(default)]
+    pub log_routes: BTreeMap<LogEvent, LogRoute>,
     pub verified_role_id: RoleId,
     pub ckey_prompt: String,
     pub questions: Vec<Question>,
     pub whitelist_path: PathBuf,
+    /// Name the member that owns each of the bot's whitelist entries in a
+    /// trailing comment, off by default since not every game server strips them
+    #[serde(default)]
+    pub whitelist_comments: bool,
+}
+
+impl GuildConfig {
+    pub fn new(
+        greeting_channel_id: ChannelId,
+        log_channel_id: ChannelId,
+        verified_role_id: RoleId,
+        whitelist_path: PathBuf,
+    ) -> Self {
+        Self {
+            greeting_channel_id,
+            messages: Messages::default(),
+            log_channel_id,
+            log_routes: BTreeMap::new(),
+            verified_role_id,
+            ckey_prompt: "What is your BYOND username?".into(),
+            questions: Vec::new(),
+            whitelist_path,
+            whitelist_comments: false,
+        }
+    }
+
+    /// Channel and role to ping for an event, if it should be logged at all
+    pub fn log_target(&self, event: LogEvent) -> Option<(ChannelId, Option<RoleId>)> {
+        match self.log_routes.get(&event) {
+            Some(route) if !route.enabled => None,
+            Some(route) => Some((
+                route.channel_id.unwrap_or(self.log_channel_id),
+                route.mention_role_id,
+            )),
+            None => Some((self.log_channel_id, None)),
+        }
+    }
+}
+
+/// Where ckeys and verification statuses are kept
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StorageBackend {
+    /// A RON file per kind of data, rewritten on every change
+    #[default]
+    Ron,
+    /// An SQLite database per server
+    Sqlite,
 }
 
+const CONFIG_SCHEMA: Schema = Schema {
+    migrations: &[migrate_unversioned],
+};
+
+/// Config layout from before several servers could be served, with the only
+/// server's settings at the top level
+#[derive(Deserialize)]
+struct LegacyAppConfig {
+    token: String,
+    application_id: ApplicationId,
+    owner_id: UserId,
+    active_guild_id: GuildId,
+    greeting_channel_id: ChannelId,
+    messages: Messages,
+    log_channel_id: ChannelId,
+    verified_role_id: RoleId,
+    ckey_prompt: String,
+    questions: Vec<Question>,
+    whitelist_path: PathBuf,
+}
+
+impl From<LegacyAppConfig> for AppConfig {
+    fn from(value: LegacyAppConfig) -> Self {
+        let guild = GuildConfig {
+            greeting_channel_id: value.greeting_channel_id,
+            messages: value.messages,
+            log_channel_id: value.log_channel_id,
+            log_routes: BTreeMap::new(),
+            verified_role_id: value.verified_role_id,
+            ckey_prompt: value.ckey_prompt,
+            questions: value.questions,
+            whitelist_path: value.whitelist_path,
+            whitelist_comments: false,
+        };
+
+        Self {
+            token: value.token,
+            application_id: value.application_id,
+            owner_id: value.owner_id,
+            storage: StorageBackend::default(),
+            guilds: BTreeMap::from([(value.active_guild_id, guild)]),
+        }
+    }
+}
+
+/// Unversioned configs are either already in the version 1 layout or from
+/// before several servers could be served
+fn migrate_unversioned(contents: String) -> Result<String, String> {
+    let mut config = match ron::de::from_str::<AppConfig>(&contents) {
+        Ok(config) => config,
+        Err(error) => {
+            let Ok(legacy) = ron::de::from_str::<LegacyAppConfig>(&contents) else {
+                return Err(error.to_string());
+            };
+
+            log::warn!(
+                "Converting single server config for {}, its data is moved into the {} \
+                subdirectory of the data directory on startup",
+                legacy.active_guild_id,
+                legacy.active_guild_id
+            );
+
+            AppConfig::from(legacy)
+        }
+    };
+
+    for (guild_id, guild) in &mut config.guilds {
+        guild.messages.escape_invalid(*guild_id);
+    }
+
+    ron::ser::to_string_pretty(&config, PrettyConfig::default()).map_err(|e| e.to_string())
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AppConfig {
+    pub token: String,
+    pub application_id: ApplicationId,
+    pub owner_id: UserId,
+    #[serde(default)]
+    pub storage: StorageBackend,
+    pub guilds: BTreeMap<GuildId, GuildConfig>,
+}
+
+impl AppConfig {
+    pub fn validate(&self) -> Result<(), InvalidTemplate> {
+        for (guild_id, guild) in &self.guilds {
+            guild
+                .messages
+                .validate()
+                .map_err(|(kind, error)| InvalidTemplate {
+                    guild_id: *guild_id,
+                    kind,
+                    error,
+                })?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     Io(io::Error),
-    Parse(SpannedError),
+    Parse(data_file::Error),
     Serialize(ron::Error),
-    DiscordError(serenity::Error),
+    DiscordError(Box<serenity::Error>),
+    Template(InvalidTemplate),
     WizardDismissed,
 }
 
@@ -55,8 +497,8 @@ impl From<io::Error> for Error {
     }
 }
 
-impl From<SpannedError> for Error {
-    fn from(value: SpannedError) -> Self {
+impl From<data_file::Error> for Error {
+    fn from(value: data_file::Error) -> Self {
         Self::Parse(value)
     }
 }
@@ -69,14 +511,20 @@ impl From<ron::Error> for Error {
 
 impl From<serenity::Error> for Error {
     fn from(value: serenity::Error) -> Self {
-        Self::DiscordError(value)
+        Self::DiscordError(Box::new(value))
+    }
+}
+
+impl From<InvalidTemplate> for Error {
+    fn from(value: InvalidTemplate) -> Self {
+        Self::Template(value)
     }
 }
 
 impl From<wizard::Error> for Error {
     fn from(value: wizard::Error) -> Self {
         match value {
-            wizard::Error::Discord(e) => Self::DiscordError(e),
+            wizard::Error::Discord(e) => Self::DiscordError(Box::new(e)),
             wizard::Error::Dismissed => Self::WizardDismissed,
         }
     }
@@ -89,6 +537,7 @@ impl fmt::Display for Error {
             Error::Parse(ref e) => fmt::Display::fmt(e, f),
             Error::Serialize(ref e) => fmt::Display::fmt(e, f),
             Error::DiscordError(ref e) => fmt::Display::fmt(e, f),
+            Error::Template(ref e) => fmt::Display::fmt(e, f),
             Error::WizardDismissed => write!(f, "Config wizard dismissed"),
         }
     }
@@ -96,13 +545,9 @@ impl fmt::Display for Error {
 
 impl AppConfig {
     pub async fn load(path: &Path) -> Result<AppConfig, Error> {
-        match File::open(path) {
-            Ok(f) => {
-                let reader = BufReader::new(f);
-
-                Ok(ron::de::from_reader(reader)?)
-            }
-            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+        match Self::read(path)? {
+            Some(config) => Ok(config),
+            None => {
                 log::info!("No config file found");
 
                 let config = wizard::run().await?;
@@ -110,17 +555,78 @@ impl AppConfig {
 
                 Ok(config)
             }
-            Err(e) => Err(e.into()),
         }
     }
 
+    /// Reads the config, upgrading it if it was written by an older version.
+    /// `None` if there is no config file
+    pub fn read(path: &Path) -> Result<Option<AppConfig>, Error> {
+        // Unlike data files a broken config isn't replaced by a backup, a
+        // mistake made while editing it should be fixed rather than undone
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let (config, version): (AppConfig, _) = CONFIG_SCHEMA.parse(&contents)?;
+        config.validate()?;
+        data_file::upgrade(path, &CONFIG_SCHEMA, version, &config)?;
+
+        Ok(Some(config))
+    }
+
     pub fn store(&self, path: &Path) -> Result<(), ron::Error> {
-        let file = File::create(path)?;
+        data_file::write_versioned(path, &CONFIG_SCHEMA, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEGACY: &str = r#"(
+        token: "token",
+        application_id: (1),
+        owner_id: (2),
+        active_guild_id: (3),
+        greeting_channel_id: (4),
+        messages: (
+            greeting: "Welcome {friend}",
+            verified: "Verified as {ckey}",
+            rejected: "Rejected :}",
+        ),
+        log_channel_id: (5),
+        verified_role_id: (6),
+        ckey_prompt: "Ckey?",
+        questions: [],
+        whitelist_path: "whitelist.txt",
+    )"#;
+
+    #[test]
+    fn upgrades_single_server_config() {
+        let (config, version): (AppConfig, _) = CONFIG_SCHEMA.parse(LEGACY.as_bytes()).unwrap();
+
+        assert_eq!(version, 0);
+        assert!(config.validate().is_ok());
+
+        let guild = &config.guilds[&GuildId(3)];
+        assert_eq!(guild.messages.greeting, "Welcome {{friend}}");
+        assert_eq!(guild.messages.verified, "Verified as {ckey}");
+        assert_eq!(guild.messages.rejected, "Rejected :}}");
+    }
 
-        let mut writer = BufWriter::new(file);
+    #[test]
+    fn keeps_current_config() {
+        let (config, _): (AppConfig, _) = CONFIG_SCHEMA.parse(LEGACY.as_bytes()).unwrap();
+        let contents = CONFIG_SCHEMA.serialize(&config).unwrap();
 
-        ron::ser::to_writer_pretty(&mut writer, self, PrettyConfig::default())?;
+        let (reread, version): (AppConfig, _) = CONFIG_SCHEMA.parse(contents.as_bytes()).unwrap();
 
-        Ok(writer.flush()?)
+        assert_eq!(version, CONFIG_SCHEMA.version());
+        assert_eq!(
+            reread.guilds[&GuildId(3)].messages.greeting,
+            "Welcome {{friend}}"
+        );
     }
 }
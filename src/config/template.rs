@@ -0,0 +1,238 @@
+use std::{collections::HashMap, fmt};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Variable {
+    User,
+    UserName,
+    UserAvatar,
+    Ckey,
+    Guild,
+    QuestionCount,
+}
+
+impl Variable {
+    pub const ALL: [Variable; 6] = [
+        Variable::User,
+        Variable::UserName,
+        Variable::UserAvatar,
+        Variable::Ckey,
+        Variable::Guild,
+        Variable::QuestionCount,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Variable::User => "user",
+            Variable::UserName => "user.name",
+            Variable::UserAvatar => "user.avatar",
+            Variable::Ckey => "ckey",
+            Variable::Guild => "guild",
+            Variable::QuestionCount => "question_count",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Variable> {
+        Self::ALL.into_iter().find(|v| v.name() == name)
+    }
+}
+
+impl fmt::Display for Variable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{{{}}}", self.name())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    UnknownVariable(String),
+    UnavailableVariable(Variable),
+    UnclosedBrace(usize),
+    UnmatchedBrace(usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Error::UnknownVariable(ref name) => write!(
+                f,
+                "unknown variable `{{{name}}}`, expected one of {}",
+                Variable::ALL.map(|v| format!("`{v}`")).join(", ")
+            ),
+            Error::UnavailableVariable(v) => write!(f, "`{v}` is not available in this message"),
+            Error::UnclosedBrace(pos) => write!(f, "unclosed `{{` at position {pos}"),
+            Error::UnmatchedBrace(pos) => write!(
+                f,
+                "unmatched `}}` at position {pos}, use `}}}}` for a literal brace"
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Text(String),
+    Variable(Variable),
+}
+
+/// Message text with `{variable}` placeholders, `{{` and `}}` escape literal braces
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Template, Error> {
+        let mut segments = Vec::new();
+        let mut text = String::new();
+        let mut chars = source.char_indices().peekable();
+
+        while let Some((pos, c)) = chars.next() {
+            match c {
+                '{' if chars.next_if(|&(_, c)| c == '{').is_some() => text.push('{'),
+                '}' if chars.next_if(|&(_, c)| c == '}').is_some() => text.push('}'),
+                '{' => {
+                    let mut name = String::new();
+
+                    loop {
+                        match chars.next() {
+                            Some((_, '}')) => break,
+                            Some((_, c)) => name.push(c),
+                            None => return Err(Error::UnclosedBrace(pos)),
+                        }
+                    }
+
+                    let variable =
+                        Variable::from_name(name.trim()).ok_or(Error::UnknownVariable(name))?;
+
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    segments.push(Segment::Variable(variable));
+                }
+                '}' => return Err(Error::UnmatchedBrace(pos)),
+                c => text.push(c),
+            }
+        }
+
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+
+        Ok(Template { segments })
+    }
+
+    /// Source of a template that renders the text as-is
+    pub fn escape(text: &str) -> String {
+        text.replace('{', "{{").replace('}', "}}")
+    }
+
+    /// Template that renders the source text as-is
+    pub fn literal(source: &str) -> Template {
+        Template {
+            segments: vec![Segment::Text(source.into())],
+        }
+    }
+
+    /// Parses a template, only accepting the given variables
+    pub fn parse_with(source: &str, available: &[Variable]) -> Result<Template, Error> {
+        let template = Self::parse(source)?;
+
+        let unavailable = template.variables().find(|v| !available.contains(v));
+
+        match unavailable {
+            Some(v) => Err(Error::UnavailableVariable(v)),
+            None => Ok(template),
+        }
+    }
+
+    pub fn variables(&self) -> impl Iterator<Item = Variable> + '_ {
+        self.segments.iter().filter_map(|s| match *s {
+            Segment::Variable(v) => Some(v),
+            Segment::Text(_) => None,
+        })
+    }
+
+    pub fn contains(&self, variable: Variable) -> bool {
+        self.variables().any(|v| v == variable)
+    }
+
+    pub fn render(&self, values: &Values) -> String {
+        let mut result = String::new();
+
+        for segment in &self.segments {
+            match *segment {
+                Segment::Text(ref text) => result.push_str(text),
+                Segment::Variable(v) => match values.0.get(&v) {
+                    Some(value) => result.push_str(value),
+                    None => result.push_str(&v.to_string()),
+                },
+            }
+        }
+
+        result
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Values(HashMap<Variable, String>);
+
+impl Values {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, variable: Variable, value: impl ToString) -> &mut Self {
+        self.0.insert(variable, value.to_string());
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_variables_and_escaped_braces() {
+        let template = Template::parse("{user} {{ok}} { guild }}}").unwrap();
+        let mut values = Values::new();
+        values
+            .set(Variable::User, "<@1>")
+            .set(Variable::Guild, "Station");
+
+        assert_eq!(template.render(&values), "<@1> {ok} Station}");
+        assert_eq!(
+            template.variables().collect::<Vec<_>>(),
+            [Variable::User, Variable::Guild]
+        );
+    }
+
+    #[test]
+    fn keeps_missing_values_as_placeholders() {
+        let template = Template::parse("Hi {user.name}").unwrap();
+
+        assert_eq!(template.render(&Values::new()), "Hi {user.name}");
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        assert_eq!(
+            Template::parse("{nope}"),
+            Err(Error::UnknownVariable("nope".into()))
+        );
+        assert_eq!(Template::parse("ab {user"), Err(Error::UnclosedBrace(3)));
+        assert_eq!(Template::parse("a}b"), Err(Error::UnmatchedBrace(1)));
+        assert_eq!(
+            Template::parse_with("{ckey}", &[Variable::User]),
+            Err(Error::UnavailableVariable(Variable::Ckey))
+        );
+    }
+
+    #[test]
+    fn escaped_text_renders_as_is() {
+        let text = "{user} }{ {{";
+        let template = Template::parse(&Template::escape(text)).unwrap();
+
+        assert_eq!(template.variables().count(), 0);
+        assert_eq!(template.render(&Values::new()), text);
+    }
+}
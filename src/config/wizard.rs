@@ -12,7 +12,12 @@ use dialoguer::{
     Confirm, Input,
 };
 
-use crate::{config::Messages, AppConfig};
+use std::collections::BTreeMap;
+
+use crate::{
+    config::{GuildConfig, MessageKind, Messages, StorageBackend},
+    AppConfig,
+};
 
 pub enum Error {
     Discord(serenity::Error),
@@ -42,9 +47,10 @@ impl<T: Theme> Wizard<T> {
             .unwrap()
     }
 
-    fn get_text(&self, prompt: &str) -> String {
+    fn get_template(&self, prompt: &str, kind: MessageKind) -> String {
         Input::with_theme(&self.theme)
             .with_prompt(prompt)
+            .validate_with(|input: &String| kind.parse(input).map(|_| ()))
             .interact_text()
             .unwrap()
     }
@@ -89,6 +95,8 @@ pub async fn run() -> Result<AppConfig, Error> {
 
     let active_guild = wizard.select_active_guild(&client, &info).await?;
 
+    eprintln!("Additional servers can be set up later with the /config init command");
+
     eprintln!("{}", style("[4/9] Greeting channel").cyan().bold());
 
     let greeting_channel = wizard
@@ -101,14 +109,16 @@ pub async fn run() -> Result<AppConfig, Error> {
 
     eprintln!("{}", style("[5/9] Messages").cyan().bold());
 
+    eprintln!("Messages can include variables like {{user}}, {{user.name}} and {{guild}}");
+
     eprintln!("The bot will send this message with every newcomer ping");
-    let greeting_message = wizard.get_text("Enter greeting message");
+    let greeting_message = wizard.get_template("Enter greeting message", MessageKind::Greeting);
 
     eprintln!("The bot will send this message once the newcomer is verified");
-    let verified_message = wizard.get_text("Enter verified message");
+    let verified_message = wizard.get_template("Enter verified message", MessageKind::Verified);
 
     eprintln!("The bot will send this message if the newcomer fails verification");
-    let rejected_message = wizard.get_text("Enter rejected message");
+    let rejected_message = wizard.get_template("Enter rejected message", MessageKind::Rejected);
 
     eprintln!("{}", style("[6/9] Log channel").cyan().bold());
 
@@ -138,21 +148,30 @@ pub async fn run() -> Result<AppConfig, Error> {
 
     let whitelist_path = wizard.get_whitelist_path();
 
-    Ok(AppConfig {
-        token: client.token,
-        application_id: info.id,
-        owner_id: info.owner.id,
-        active_guild_id: active_guild.id,
+    let guild = GuildConfig {
         greeting_channel_id: greeting_channel.id,
         messages: Messages {
             greeting: greeting_message,
             verified: verified_message,
             rejected: rejected_message,
+            greeting_embed: None,
+            verified_embed: None,
+            rejected_embed: None,
         },
         log_channel_id: log_channel.id,
+        log_routes: BTreeMap::new(),
         verified_role_id: verified_role.id,
         ckey_prompt,
         questions,
         whitelist_path,
+        whitelist_comments: false,
+    };
+
+    Ok(AppConfig {
+        token: client.token,
+        application_id: info.id,
+        owner_id: info.owner.id,
+        storage: StorageBackend::default(),
+        guilds: BTreeMap::from([(active_guild.id, guild)]),
     })
 }
@@ -89,7 +89,7 @@ impl<'a, T: Theme + 'a> QuestionsWizard<'a, T> {
         question.id = Input::with_theme(self.theme)
             .with_prompt("Enter question ID")
             .validate_with(|input: &String| -> Result<(), &str> {
-                if input.as_bytes().len() > 100 {
+                if input.len() > 100 {
                     Err("String must be less than 100 bytes")
                 } else if self.questions.iter().any(|q| q.id == *input) {
                     Err("Question IDs must be unique")
@@ -167,7 +167,7 @@ impl<'a, T: Theme + 'a> QuestionsWizard<'a, T> {
         let id: String = Input::with_theme(self.theme)
             .with_prompt("Enter question ID")
             .validate_with(|input: &String| -> Result<(), &str> {
-                if input.as_bytes().len() > 100 {
+                if input.len() > 100 {
                     Err("String must be less than 100 bytes")
                 } else if self.questions.iter().any(|q| q.id == *input) {
                     Err("Question IDs must be unique")
@@ -0,0 +1,505 @@
+use std::{
+    cmp::Reverse,
+    fmt,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ron::{error::SpannedError, ser::PrettyConfig};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Previous versions kept next to every file
+const BACKUP_COUNT: usize = 5;
+/// Least time between backups of a file, so frequent writes don't push every
+/// useful version out within seconds
+const BACKUP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// First line of every versioned file, a comment so the files stay plain RON
+const VERSION_HEADER: &str = "// version: ";
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Parse(SpannedError),
+    Migration { version: u32, message: String },
+    TooNew { version: u32, supported: u32 },
+}
+
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<SpannedError> for Error {
+    fn from(value: SpannedError) -> Self {
+        Self::Parse(value)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => fmt::Display::fmt(e, f),
+            Error::Parse(ref e) => fmt::Display::fmt(e, f),
+            Error::Migration {
+                version,
+                ref message,
+            } => write!(f, "upgrading from version {version}: {message}"),
+            Error::TooNew { version, supported } => write!(
+                f,
+                "written by a newer version of the bot (format version {version}, this build \
+                reads up to {supported}), upgrade the bot or restore an older backup"
+            ),
+        }
+    }
+}
+
+/// Upgrades a file's contents from one version to the next
+pub type Migration = fn(String) -> Result<String, String>;
+
+/// Files written before versioning have the same layout as version 1
+pub fn unversioned(contents: String) -> Result<String, String> {
+    Ok(contents)
+}
+
+/// How a kind of file has changed over time. The current version is the
+/// number of migrations, `migrations[n]` upgrades version `n` to `n + 1`
+pub struct Schema {
+    pub migrations: &'static [Migration],
+}
+
+impl Schema {
+    pub fn version(&self) -> u32 {
+        self.migrations.len() as u32
+    }
+
+    /// Parses contents of any version this build knows, returning the version
+    /// they were written in
+    pub fn parse<T: DeserializeOwned>(&self, contents: &[u8]) -> Result<(T, u32), Error> {
+        let contents =
+            str::from_utf8(contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let version = header_version(contents);
+
+        if version > self.version() {
+            return Err(Error::TooNew {
+                version,
+                supported: self.version(),
+            });
+        }
+
+        let mut contents = contents.to_string();
+        for (from, migration) in self.migrations.iter().enumerate().skip(version as usize) {
+            contents = migration(contents).map_err(|message| Error::Migration {
+                version: from as u32,
+                message,
+            })?;
+        }
+
+        Ok((ron::de::from_str(&contents)?, version))
+    }
+
+    pub fn serialize<T: Serialize>(&self, value: &T) -> Result<String, ron::Error> {
+        let contents = ron::ser::to_string_pretty(value, PrettyConfig::default())?;
+
+        Ok(format!("{VERSION_HEADER}{}\n{contents}", self.version()))
+    }
+}
+
+fn header_version(contents: &str) -> u32 {
+    contents
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix(VERSION_HEADER))
+        .and_then(|version| version.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+fn parent(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// Backups of a file with their timestamps, newest first
+fn backups(path: &Path) -> Vec<(u128, PathBuf)> {
+    let prefix = format!("{}.", file_name(path));
+
+    let Ok(entries) = fs::read_dir(parent(path)) else {
+        return Vec::new();
+    };
+
+    let mut backups: Vec<_> = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let timestamp: u128 = name
+                .strip_prefix(&prefix)?
+                .strip_suffix(".bak")?
+                .parse()
+                .ok()?;
+            Some((timestamp, entry.path()))
+        })
+        .collect();
+    backups.sort_by_key(|(timestamp, _)| Reverse(*timestamp));
+
+    backups
+}
+
+/// Keeps the current version of a file as a backup and drops the oldest ones,
+/// unless the newest backup is recent enough
+fn back_up(path: &Path) -> io::Result<()> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    if backups(path)
+        .first()
+        .is_some_and(|&(newest, _)| timestamp.saturating_sub(newest) < BACKUP_INTERVAL.as_millis())
+    {
+        return Ok(());
+    }
+    let backup = parent(path).join(format!("{}.{timestamp}.bak", file_name(path)));
+
+    // A hard link is free and the file it points to is never written again
+    // since new versions are renamed over the path
+    match fs::hard_link(path, &backup) {
+        Ok(_) => (),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(()),
+        Err(_) => {
+            fs::copy(path, &backup)?;
+        }
+    }
+
+    for (_, old) in backups(path).into_iter().skip(BACKUP_COUNT) {
+        fs::remove_file(old)?;
+    }
+
+    Ok(())
+}
+
+/// Replaces a file so that a crash at any point leaves either the old or the
+/// new contents in place, never a mix. The new file gets the permissions and,
+/// where allowed, the owner of the old one
+pub fn write(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let existing = match fs::metadata(path) {
+        Ok(metadata) => Some(metadata),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    if existing.is_some() && fs::read(path)? == contents {
+        return Ok(());
+    }
+
+    let temp = parent(path).join(format!(".{}.tmp", file_name(path)));
+
+    {
+        let mut file = File::create(&temp)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+    }
+
+    if let Some(metadata) = existing {
+        fs::set_permissions(&temp, metadata.permissions())?;
+
+        // Only works for root or when nothing changes, others keep the file as
+        // their own
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let _ = std::os::unix::fs::chown(&temp, Some(metadata.uid()), Some(metadata.gid()));
+        }
+    }
+
+    back_up(path)?;
+    fs::rename(&temp, path)?;
+
+    // Makes the rename itself durable, not possible on every platform
+    if let Ok(dir) = File::open(parent(path)) {
+        let _ = dir.sync_all();
+    }
+
+    Ok(())
+}
+
+/// Reads and parses a file, falling back to the newest backup that parses if
+/// the file itself can't be. `None` if the file doesn't exist
+pub fn read<T, E>(path: &Path, parse: impl Fn(&[u8]) -> Result<T, E>) -> Result<Option<T>, E>
+where
+    E: From<io::Error> + fmt::Display,
+{
+    let error = match fs::read(path) {
+        Ok(contents) => match parse(&contents) {
+            Ok(value) => return Ok(Some(value)),
+            Err(e) => e,
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => e.into(),
+    };
+
+    for (_, backup) in backups(path) {
+        let Ok(contents) = fs::read(&backup) else {
+            continue;
+        };
+        if let Ok(value) = parse(&contents) {
+            log::error!(
+                "!!! {} is unreadable ({error}), loaded the backup {} instead. \
+                Changes made after that backup are lost unless recovered by hand !!!",
+                path.display(),
+                backup.display()
+            );
+
+            return Ok(Some(value));
+        }
+    }
+
+    Err(error)
+}
+
+/// Rewrites a file loaded from an older version in the current one, keeping
+/// the original as `<file>.v<version>.bak` first
+pub fn upgrade<T: Serialize>(
+    path: &Path,
+    schema: &Schema,
+    version: u32,
+    value: &T,
+) -> Result<(), ron::Error> {
+    if version >= schema.version() {
+        return Ok(());
+    }
+
+    let backup = path.with_file_name(format!("{}.v{version}.bak", file_name(path)));
+    if !backup.exists() {
+        fs::copy(path, &backup)?;
+    }
+
+    write(path, schema.serialize(value)?.as_bytes())?;
+
+    log::info!(
+        "Upgraded {} from version {version} to {}, the original is kept as {}",
+        path.display(),
+        schema.version(),
+        backup.display()
+    );
+
+    Ok(())
+}
+
+/// Reads a versioned RON file, upgrading it in place if it's older than the
+/// current version. A file newer than this build is an error rather than a
+/// reason to fall back to a backup, since that would lose its changes
+pub fn read_versioned<T>(path: &Path, schema: &Schema) -> Result<Option<T>, Error>
+where
+    T: Serialize + DeserializeOwned,
+{
+    if let Ok(contents) = fs::read(path) {
+        let version = header_version(&String::from_utf8_lossy(&contents));
+        if version > schema.version() {
+            return Err(Error::TooNew {
+                version,
+                supported: schema.version(),
+            });
+        }
+    }
+
+    let Some((value, version)) = read(path, |contents| schema.parse(contents))? else {
+        return Ok(None);
+    };
+
+    upgrade(path, schema, version, &value).map_err(|e| Error::Migration {
+        version,
+        message: e.to_string(),
+    })?;
+
+    Ok(Some(value))
+}
+
+pub fn write_versioned<T: Serialize>(
+    path: &Path,
+    schema: &Schema,
+    value: &T,
+) -> Result<(), ron::Error> {
+    Ok(write(path, schema.serialize(value)?.as_bytes())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Renames the `count` field of version 1 files to `total`
+    fn rename_count(contents: String) -> Result<String, String> {
+        Ok(contents.replace("count:", "total:"))
+    }
+
+    const SCHEMA: Schema = Schema {
+        migrations: &[unversioned, rename_count],
+    };
+
+    #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+    struct Counter {
+        total: u32,
+    }
+
+    /// Empty directory of its own for a test
+    fn dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("nightstation-verify-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn migrates_older_versions() {
+        let (value, version): (Counter, _) = SCHEMA.parse(b"(count: 3)").unwrap();
+        assert_eq!((value, version), (Counter { total: 3 }, 0));
+
+        let (value, version): (Counter, _) = SCHEMA.parse(b"// version: 1\n(count: 4)").unwrap();
+        assert_eq!((value, version), (Counter { total: 4 }, 1));
+
+        let contents = SCHEMA.serialize(&Counter { total: 5 }).unwrap();
+        let (value, version): (Counter, _) = SCHEMA.parse(contents.as_bytes()).unwrap();
+        assert_eq!((value, version), (Counter { total: 5 }, 2));
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let result = SCHEMA.parse::<Counter>(b"// version: 3\n(total: 1)");
+
+        assert!(matches!(
+            result,
+            Err(Error::TooNew {
+                version: 3,
+                supported: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn upgrades_in_place_keeping_the_original() {
+        let dir = dir("upgrade");
+        let path = dir.join("counter.ron");
+        fs::write(&path, "(count: 6)").unwrap();
+
+        let value: Option<Counter> = read_versioned(&path, &SCHEMA).unwrap();
+
+        assert_eq!(value, Some(Counter { total: 6 }));
+        assert_eq!(
+            fs::read_to_string(dir.join("counter.ron.v0.bak")).unwrap(),
+            "(count: 6)"
+        );
+        assert_eq!(header_version(&fs::read_to_string(&path).unwrap()), 2);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn falls_back_to_newest_readable_backup() {
+        let dir = dir("backup");
+        let path = dir.join("counter.ron");
+        for (timestamp, contents) in [(1, "(total: 1)"), (2, "(total: 2)"), (3, "(tot")] {
+            fs::write(dir.join(format!("counter.ron.{timestamp}.bak")), contents).unwrap();
+        }
+        fs::write(&path, "not ron").unwrap();
+
+        let value: Option<Counter> = read_versioned(&path, &SCHEMA).unwrap();
+
+        assert_eq!(value, Some(Counter { total: 2 }));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_limited_backups() {
+        let dir = dir("rotate");
+        let path = dir.join("counter.ron");
+        for timestamp in 1..=BACKUP_COUNT {
+            fs::write(
+                dir.join(format!("counter.ron.{timestamp}.bak")),
+                "(total: 0)",
+            )
+            .unwrap();
+        }
+
+        write_versioned(&path, &SCHEMA, &Counter { total: 1 }).unwrap();
+        write_versioned(&path, &SCHEMA, &Counter { total: 2 }).unwrap();
+
+        let backups = backups(&path);
+        assert_eq!(backups.len(), BACKUP_COUNT);
+        assert!(!dir.join("counter.ron.1.bak").exists());
+        assert_eq!(
+            fs::read_to_string(&backups[0].1).unwrap(),
+            SCHEMA.serialize(&Counter { total: 1 }).unwrap()
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn backs_up_at_most_once_per_interval() {
+        let dir = dir("interval");
+        let path = dir.join("counter.ron");
+
+        for total in 0..5 {
+            write_versioned(&path, &SCHEMA, &Counter { total }).unwrap();
+        }
+
+        assert_eq!(backups(&path).len(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unchanged_contents_leave_the_file_alone() {
+        let dir = dir("unchanged");
+        let path = dir.join("counter.ron");
+        fs::write(&path, "(total: 1)").unwrap();
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+
+        write(&path, b"(total: 1)").unwrap();
+
+        assert!(backups(&path).is_empty());
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), modified);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = dir("permissions");
+        let path = dir.join("whitelist.txt");
+        fs::write(&path, "alice\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+
+        write(&path, b"bob\n").unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn missing_file_is_none() {
+        let dir = dir("missing");
+
+        let value: Option<Counter> = read_versioned(&dir.join("counter.ron"), &SCHEMA).unwrap();
+
+        assert_eq!(value, None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,5 +1,6 @@
 mod app;
 mod config;
+mod data_file;
 
 pub const SOURCE: &str = "https://github.com/SS13-Aeon/nightstation-verify";
 pub const COPYRIGHT: &str = r#"Nightstation verification bot
@@ -18,6 +19,7 @@ GNU Affero General Public License for more details.
 You should have received a copy of the GNU Affero General Public License
 along with this program.  If not, see <https://www.gnu.org/licenses/>."#;
 
+pub use app::cli::Command as AppCommand;
 pub use app::App;
 pub use app::AppPaths;
 pub type AppError = app::Error;
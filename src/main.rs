@@ -5,7 +5,7 @@ use std::{
 };
 
 use clap::Parser;
-use nightstation_verify::{App, AppConfig, AppConfigError, AppError, AppPaths};
+use nightstation_verify::{App, AppCommand, AppConfig, AppConfigError, AppError, AppPaths};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -28,6 +28,11 @@ struct Cli {
     /// Increase verbosity level
     #[arg(short = 'v', long, action = clap::ArgAction::Count)]
     verbose: u8,
+    /// Server to run a command against, needed when several are configured
+    #[arg(short, long)]
+    guild: Option<u64>,
+    #[command(subcommand)]
+    command: Option<AppCommand>,
 }
 
 #[tokio::main]
@@ -80,6 +85,13 @@ async fn main() -> ExitCode {
         }
     };
 
+    // Commands are meant for scripts, they shouldn't start the interactive wizard
+    if cli.command.is_some() && !cli.config.exists() {
+        log::error!("No config file found at {}", cli.config.display());
+
+        return ExitCode::FAILURE;
+    }
+
     let config = match AppConfig::load(Path::new(&cli.config)).await {
         Ok(config) => {
             log::info!(
@@ -99,6 +111,7 @@ async fn main() -> ExitCode {
                     log::error!("Error while writing config file: {}", e)
                 }
                 AppConfigError::DiscordError(e) => log::error!("Unexpected Discord error: {}", e),
+                AppConfigError::Template(e) => log::error!("Invalid message template: {}", e),
                 AppConfigError::WizardDismissed => {
                     log::error!("Cannot continue without configuration")
                 }
@@ -108,7 +121,7 @@ async fn main() -> ExitCode {
         }
     };
 
-    if cli.no_run {
+    if cli.no_run && cli.command.is_none() {
         return ExitCode::SUCCESS;
     }
 
@@ -120,12 +133,26 @@ async fn main() -> ExitCode {
         },
     );
 
-    match app.run().await {
+    let result = match cli.command {
+        Some(command) => app.run_command(cli.guild, command).await,
+        None => app.run().await,
+    };
+
+    match result {
         Ok(_) => (),
         Err(e) => {
             match e {
                 AppError::DataDir(e) => log::error!("Error while creating data directory: {}", e),
+                AppError::DataDirLocked => log::error!(
+                    "The data directory is in use by another instance, \
+                    stop the bot before running commands against it"
+                ),
                 AppError::Whietlist(e) => log::error!("Error while reading whitelist: {}", e),
+                AppError::GuildNotConfigured(_) => log::error!("Server is not configured"),
+                AppError::GuildRequired => {
+                    log::error!("Several servers are configured, pick one with --guild")
+                }
+                AppError::File(e) => log::error!("Error while accessing file: {}", e),
                 AppError::Discord(e) => log::error!("Unexpected Discord error: {}", e),
                 AppError::Service(e) => log::error!("Service error: {}", e),
                 AppError::JoinError(e) => log::error!("Tokio error: {}", e),